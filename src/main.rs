@@ -15,29 +15,51 @@ fn main() -> Result<()> {
 
     // Parse command and act accordingly
     let command = &args[2];
-    let conn = sqlite::open(&args[1])?;
+    let mut conn = sqlite::open(&args[1])?;
 
     match command.as_str() {
         ".dbinfo" => {
-            println!("Logs from your program will appear here!");
+            let header = conn.get_header();
+            println!("database page size:  {}", header.page_size);
+            println!("write format:        {}", header.write_version);
+            println!("read format:         {}", header.read_version);
+            println!("reserved bytes:      {}", header.reserved_bytes);
+            println!("file change counter: {}", header.file_change_counter);
+            println!("database page count: {}", header.database_size);
+            println!("freelist page count: {}", header.freelist_count);
+            println!("schema cookie:       {}", header.schema_cookie);
+            println!("schema format:       {}", header.schema_format);
+            println!("default cache size:  {}", header.default_cache_size);
+            println!("autovacuum top root: {}", header.largest_root_page);
+            println!("incremental vacuum:  {}", header.incremental_vacuum as u32);
+            println!(
+                "text encoding:       {} ({})",
+                header.text_encoding.code(),
+                header.text_encoding
+            );
+            println!("user version:        {}", header.user_version);
+            println!("application id:      {}", header.application_id);
+            println!("software version:    {}", header.sqlite_version_number);
+
             let schema = conn.get_schema();
-            println!("database page size: {}", conn.get_header().page_size);
-            let c = schema
+            let tables = schema
                 .iter()
                 .filter(|x| matches!(x.as_ref(), SqliteSchema::Table(_)))
                 .count();
-            println!("number of tables: {}", c);
+            let indexes = schema
+                .iter()
+                .filter(|x| matches!(x.as_ref(), SqliteSchema::Index(_)))
+                .count();
+            println!("number of tables:    {}", tables);
+            println!("number of indexes:   {}", indexes);
         }
 
         ".tables" => {
             let schema = conn.get_schema();
             let names = schema
                 .iter()
-                .filter(|x| matches!(x.as_ref(), SqliteSchema::Table(_)))
-                .map(|x| match x.as_ref() {
-                    SqliteSchema::Table(t) => t.name.clone(),
-                    SqliteSchema::Index(i) => i.name.clone(),
-                })
+                .filter(|x| matches!(x.as_ref(), SqliteSchema::Table(_) | SqliteSchema::View(_)))
+                .map(|x| x.get_name())
                 .collect_vec();
             print!("{}", names.join(" "));
         }
@@ -53,6 +75,14 @@ fn main() -> Result<()> {
                     SqliteSchema::Index(index) => {
                         println!("Table: {:?}", index);
                     }
+                    SqliteSchema::View(view) => {
+                        println!("View: {}", view.name);
+                        println!("{}", view.sql);
+                    }
+                    SqliteSchema::Trigger(trigger) => {
+                        println!("Trigger: {} on {}", trigger.name, trigger.table_name);
+                        println!("{}", trigger.sql);
+                    }
                 };
             }
         }
@@ -60,22 +90,48 @@ fn main() -> Result<()> {
             for schema in conn.get_schema() {
                 match schema.as_ref() {
                     SqliteSchema::Table(table) => {
-                        conn.get_tree(&table.name)?.pretty_print()?;
+                        conn.get_tree(&table.name)?.pretty_print(conn.get_db())?;
                         println!("Table: {}", table.name);
                         for col in &table.columns {
                             println!("{:15} - {} ", col.name, col.type_affinity);
                         }
                     }
                     SqliteSchema::Index(index) => {
-                        conn.get_index_tree(&index.parent_table, &index.column_name)?
-                            .pretty_print()?;
+                        conn.get_index_tree(&index.parent_table, index.column_name())?
+                            .pretty_print(conn.get_db())?;
                     }
+                    // views and triggers have no storage of their own
+                    SqliteSchema::View(_) | SqliteSchema::Trigger(_) => {}
                 };
             }
         }
+        ".freelist" => {
+            let freelist = conn.get_db().get_freelist()?;
+            let page_count = conn.get_header().database_size;
+            let wasted = match page_count {
+                0 => 0.0,
+                n => freelist.len() as f64 * 100.0 / n as f64,
+            };
+            println!("free pages:  {} of {}", freelist.len(), page_count);
+            println!("wasted:      {:.1}%", wasted);
+            println!("trunk pages: {}", freelist.trunk_pages.iter().join(" "));
+            println!("leaf pages:  {}", freelist.leaf_pages.iter().join(" "));
+        }
+        ".check" => {
+            let problems = conn.integrity_check()?;
+            if problems.is_empty() {
+                println!("ok");
+            }
+            for problem in problems {
+                println!("{}", problem);
+            }
+        }
         s if s.starts_with(".page") => {
-            let page_number = s.split_once(' ').unwrap().1.parse::<u32>().unwrap();
-            conn.dump_page(page_number);
+            let Some(Ok(page_number)) = s.split_once(' ').map(|(_, n)| n.trim().parse::<u32>())
+            else {
+                bail!("usage: .page <page number>");
+            };
+            conn.dump_page(page_number)?;
         }
         _query => {
             conn.execute_query(_query.trim())?;
@@ -1,16 +1,29 @@
-use anyhow::Result;
 use connection::Connection;
+use database::OpenOptions;
+use error::Result;
+use storage::MemoryStorage;
 
+pub mod balance;
 pub mod btree;
 pub mod column;
 pub mod connection;
+pub mod cursor;
 pub mod database;
+pub mod error;
+pub mod freelist;
 pub mod index_btree;
+pub mod integrity;
+pub mod journal;
+pub mod lock;
 pub mod page;
+pub mod page_cache;
 pub mod record;
 pub mod row;
 pub mod schema;
 pub mod sql;
+pub mod storage;
+pub mod transaction;
+pub mod wal;
 
 #[cfg(test)]
 mod tests;
@@ -18,3 +31,16 @@ mod tests;
 pub fn open(file_path: impl Into<String>) -> Result<Connection> {
     Connection::new(file_path)
 }
+
+pub fn open_with_options(file_path: impl Into<String>, options: OpenOptions) -> Result<Connection> {
+    Connection::with_options(file_path, options)
+}
+
+/// Opens a database held in memory, such as one received over the network, without
+/// writing it to disk
+pub fn open_bytes(bytes: impl AsRef<[u8]> + Send + Sync + 'static) -> Result<Connection> {
+    Connection::from_storage(
+        Box::new(MemoryStorage::new(bytes)),
+        OpenOptions::default(),
+    )
+}
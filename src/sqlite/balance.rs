@@ -0,0 +1,367 @@
+// https://www.sqlite.org/fileformat2.html#b_tree_pages
+//
+// Keeps b-trees balanced as cells are added. A page that runs out of room is split: its
+// cells are spread over as many pages as they need and a divider cell for each new page
+// goes into the parent, which may have to be split in turn. When the root is full its
+// cells move down to new pages instead, so the root keeps its page number and the tree
+// grows a level. Going the other way, a page that gets too empty as cells are removed
+// merges with a sibling, and a root left with a single child takes over its cells.
+
+use std::ops::Range;
+
+use super::{
+    database::Database,
+    error::{bail, Result},
+    page::{btree_page, page_header::PageType},
+    record::codec,
+    transaction::Transaction,
+};
+
+/// Where a search down a b-tree ended: the interior pages passed on the way with the
+/// index of the child taken from each, and the cell a key is at or belongs at
+pub struct Position {
+    pub path: Vec<(u32, usize)>,
+    pub page_number: u32,
+    pub index: usize,
+    pub found: bool,
+}
+
+/// Adds cells to a b-tree page at a position of its cell pointer array, splitting the
+/// page when they don't fit. `path` holds the pages from the root down to the parent of
+/// the page, each with the index of the child the path takes from it.
+pub fn insert_cells(
+    db: &Database,
+    transaction: &mut Transaction,
+    path: &[(u32, usize)],
+    page_number: u32,
+    index: usize,
+    cells: &[Vec<u8>],
+) -> Result<()> {
+    let mut page = transaction.btree_page_mut(db, page_number)?;
+    for (i, cell) in cells.iter().enumerate() {
+        if !page.insert_cell(db, index + i, cell)? {
+            return split(db, transaction, path, page_number, index + i, &cells[i..]);
+        }
+    }
+    Ok(())
+}
+
+/// Removes a cell from a b-tree page. A page left less than a third full is merged with
+/// a sibling, or shares out the cells of both again when they don't fit on one page, and
+/// the pages this frees go on the freelist.
+pub fn delete_cell(
+    db: &Database,
+    transaction: &mut Transaction,
+    path: &[(u32, usize)],
+    page_number: u32,
+    index: usize,
+) -> Result<()> {
+    transaction
+        .btree_page_mut(db, page_number)?
+        .remove_cell(db, index)?;
+    rebalance(db, transaction, path, page_number)
+}
+
+/// The cells of a page and its right child when it's an interior page
+struct Contents {
+    page_type: PageType,
+    cells: Vec<Vec<u8>>,
+    right_child: Option<u32>,
+}
+
+impl Contents {
+    fn read(db: &Database, transaction: &Transaction, page_number: u32) -> Result<Contents> {
+        let page = transaction.btree_page(db, page_number)?;
+        Ok(Contents {
+            page_type: page.page_type().clone(),
+            cells: (0..page.cell_count())
+                .map(|i| page.cell(db, i).map(<[u8]>::to_vec))
+                .collect::<Result<Vec<_>>>()?,
+            right_child: (!page.is_leaf()).then(|| page.right_child()),
+        })
+    }
+}
+
+fn split(
+    db: &Database,
+    transaction: &mut Transaction,
+    path: &[(u32, usize)],
+    page_number: u32,
+    index: usize,
+    new_cells: &[Vec<u8>],
+) -> Result<()> {
+    let mut contents = Contents::read(db, transaction, page_number)?;
+    // rows are mostly added with ever larger rowids, a row added after the last one on
+    // a leaf gets a page of its own rather than half of the rows
+    let appended = matches!(contents.page_type, PageType::TableLeaf)
+        && new_cells.len() == 1
+        && index == contents.cells.len()
+        && path.iter().all(|(parent, child)| {
+            transaction
+                .btree_page(db, *parent)
+                .is_ok_and(|p| *child == p.cell_count())
+        });
+    contents
+        .cells
+        .splice(index..index, new_cells.iter().cloned());
+
+    // the cells never go back onto page 1, only its dividers if it's the root
+    let count = contents.cells.len();
+    let runs = match appended {
+        true => vec![0..count - 1, count - 1..count],
+        false => distribute(&contents, btree_page::capacity(db, &contents.page_type))?,
+    };
+
+    // the root keeps its page number, any other page keeps the last of the runs so the
+    // parent's pointer to it stays right
+    let is_root = path.is_empty();
+    let mut page_numbers = Vec::with_capacity(runs.len());
+    for i in 0..runs.len() {
+        page_numbers.push(match i == runs.len() - 1 && !is_root {
+            true => page_number,
+            false => transaction.allocate_page(db)?,
+        });
+    }
+    let dividers = write_runs(db, transaction, &contents, &runs, &page_numbers)?;
+
+    match path.split_last() {
+        Some(((parent, child), path)) => {
+            insert_cells(db, transaction, path, *parent, *child, &dividers)
+        }
+        None => {
+            let right = page_numbers.last().copied();
+            let page_type = contents.page_type.interior();
+            write_page(db, transaction, page_number, &page_type, &dividers, right)
+        }
+    }
+}
+
+fn rebalance(
+    db: &Database,
+    transaction: &mut Transaction,
+    path: &[(u32, usize)],
+    page_number: u32,
+) -> Result<()> {
+    let page = transaction.btree_page(db, page_number)?;
+    let Some(((parent_number, child), parent_path)) = path.split_last() else {
+        // a root left with a child but no cells takes the place of its child, which
+        // only doesn't fit when the root is page 1
+        if page.is_leaf() || page.cell_count() > 0 {
+            return Ok(());
+        }
+        let child = page.right_child();
+        let contents = Contents::read(db, transaction, child)?;
+        let capacity =
+            btree_page::capacity(db, &contents.page_type) - btree_page::header_offset(page_number);
+        if sizes(&contents).sum::<usize>() > capacity {
+            return Ok(());
+        }
+        let Contents {
+            page_type,
+            cells,
+            right_child,
+        } = contents;
+        write_page(
+            db,
+            transaction,
+            page_number,
+            &page_type,
+            &cells,
+            right_child,
+        )?;
+        return transaction.free_page(db, child);
+    };
+    let capacity = btree_page::capacity(db, page.page_type());
+    if page.cell_count() > 0 && page.free_space()? < capacity * 2 / 3 {
+        return Ok(());
+    }
+
+    // the page goes together with the sibling to its left, or to its right when it's
+    // the first child, along with the divider between them
+    let parent = transaction.btree_page(db, *parent_number)?;
+    let count = parent.cell_count();
+    if count == 0 {
+        bail!(Corrupt, "interior page {} has no cells", parent_number);
+    }
+    let divider = child.saturating_sub(1);
+    let child_at = |i: usize| match i == count {
+        true => Ok(parent.right_child()),
+        false => parent.left_child(i),
+    };
+    let (left, right) = (child_at(divider)?, child_at(divider + 1)?);
+    let divider_cell = parent.cell(db, divider)?.to_vec();
+    let mut contents = Contents::read(db, transaction, left)?;
+    let right_contents = Contents::read(db, transaction, right)?;
+    if right_contents.page_type.flag() != contents.page_type.flag() {
+        bail!(
+            Corrupt,
+            "sibling pages {} and {} are of different types",
+            left,
+            right
+        );
+    }
+    // the divider comes down between the cells of the two pages, the reverse of how
+    // splitting made it
+    match (&contents.page_type, contents.right_child) {
+        (PageType::TableLeaf, _) => {}
+        (PageType::IndexLeaf, _) => contents.cells.push(divider_cell[4..].to_vec()),
+        (_, right_child) => {
+            let left_child = right_child.unwrap_or_default().to_be_bytes();
+            contents
+                .cells
+                .push([&left_child[..], &divider_cell[4..]].concat());
+        }
+    }
+    contents.cells.extend(right_contents.cells);
+    contents.right_child = right_contents.right_child;
+    transaction
+        .btree_page_mut(db, *parent_number)?
+        .remove_cell(db, divider)?;
+
+    if sizes(&contents).sum::<usize>() <= capacity {
+        let Contents {
+            page_type,
+            cells,
+            right_child,
+        } = &contents;
+        write_page(db, transaction, right, page_type, cells, *right_child)?;
+        transaction.free_page(db, left)?;
+        return rebalance(db, transaction, parent_path, *parent_number);
+    }
+    // too many cells for one page, they are shared out again
+    let runs = distribute(&contents, capacity)?;
+    let mut page_numbers = vec![left];
+    for _ in 2..runs.len() {
+        page_numbers.push(transaction.allocate_page(db)?);
+    }
+    page_numbers.push(right);
+    let dividers = write_runs(db, transaction, &contents, &runs, &page_numbers)?;
+    insert_cells(
+        db,
+        transaction,
+        parent_path,
+        *parent_number,
+        divider,
+        &dividers,
+    )
+}
+
+/// Writes runs of cells to the pages, returning the divider cell that goes in the parent
+/// for each page but the last
+fn write_runs(
+    db: &Database,
+    transaction: &mut Transaction,
+    contents: &Contents,
+    runs: &[Range<usize>],
+    page_numbers: &[u32],
+) -> Result<Vec<Vec<u8>>> {
+    let cells = &contents.cells;
+    let mut dividers = Vec::with_capacity(runs.len() - 1);
+    for (i, run) in runs.iter().enumerate() {
+        let last = i == runs.len() - 1;
+        // an interior page's right child is the left child of the cell after it, which
+        // becomes its divider
+        let right = match (contents.right_child, last) {
+            (Some(right), true) => Some(right),
+            (Some(_), false) => Some(u32_at(&cells[run.end], 0)),
+            (None, _) => None,
+        };
+        let page_type = &contents.page_type;
+        write_page(
+            db,
+            transaction,
+            page_numbers[i],
+            page_type,
+            &cells[run.clone()],
+            right,
+        )?;
+        if last {
+            continue;
+        }
+        let left = page_numbers[i].to_be_bytes();
+        dividers.push(match page_type {
+            // table leaves keep all their cells, the divider is the largest rowid on the
+            // page to the left of it
+            PageType::TableLeaf => {
+                let row_id = leaf_row_id(&cells[run.end - 1])?;
+                [&left[..], &codec::encode_varint(row_id)].concat()
+            }
+            PageType::IndexLeaf => [&left[..], &cells[run.end]].concat(),
+            PageType::TableInterior | PageType::IndexInterior => {
+                [&left[..], &cells[run.end][4..]].concat()
+            }
+        });
+    }
+    Ok(dividers)
+}
+
+/// The space each cell takes with its pointer, never less than 4 bytes for the cell
+fn sizes(contents: &Contents) -> impl Iterator<Item = usize> + '_ {
+    contents.cells.iter().map(|c| c.len().max(4) + 2)
+}
+
+/// Splits cells into runs that each fit on a page, filling the pages about evenly.
+/// Except on table leaves the cell after each run but the last one goes up to the parent
+/// as a divider rather than onto a page.
+fn distribute(contents: &Contents, capacity: usize) -> Result<Vec<Range<usize>>> {
+    let dividers = !matches!(contents.page_type, PageType::TableLeaf);
+    let sizes = sizes(contents).collect::<Vec<_>>();
+    let total = sizes.iter().sum::<usize>();
+    let target = total.div_ceil(total.div_ceil(capacity).max(2));
+
+    let mut runs = Vec::new();
+    let mut start = 0;
+    while start < sizes.len() {
+        let mut end = start + 1;
+        let mut used = sizes[start];
+        while end < sizes.len() && used < target && used + sizes[end] <= capacity {
+            used += sizes[end];
+            end += 1;
+        }
+        // a divider needs a page to the right of it
+        if dividers && end + 1 == sizes.len() {
+            if end - start < 2 {
+                bail!(Corrupt, "cells are too large to split between pages");
+            }
+            end -= 1;
+        }
+        runs.push(start..end);
+        start = end + usize::from(dividers);
+    }
+    Ok(runs)
+}
+
+fn write_page(
+    db: &Database,
+    transaction: &mut Transaction,
+    page_number: u32,
+    page_type: &PageType,
+    cells: &[Vec<u8>],
+    right_child: Option<u32>,
+) -> Result<()> {
+    let mut page = transaction.init_btree_page(db, page_number, page_type.clone())?;
+    for (i, cell) in cells.iter().enumerate() {
+        if !page.insert_cell(db, i, cell)? {
+            bail!(Corrupt, "cells don't fit on page {}", page_number);
+        }
+    }
+    if let Some(right_child) = right_child {
+        page.set_right_child(right_child);
+    }
+    Ok(())
+}
+
+/// The rowid of a table leaf cell, which follows the payload size
+fn leaf_row_id(cell: &[u8]) -> Result<i64> {
+    let payload_size = codec::decode_varint(cell)?;
+    Ok(codec::decode_varint(&cell[payload_size.size as usize..])?.value)
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes([
+        data[offset],
+        data[offset + 1],
+        data[offset + 2],
+        data[offset + 3],
+    ])
+}
@@ -1,20 +1,28 @@
-
-use std::{borrow::Cow, rc::Rc, vec};
+use std::{borrow::Cow, cmp::Ordering, sync::Arc, vec};
 
 use super::{
-    database::Database,
-    page::{table_interior::TableInteriorPage, TablePage},
-    record::{CellValue, Record},
+    balance::{self, Position},
+    column::TypeAffinity,
+    cursor::{self, IndexCursor, TableCursor},
+    database::{Database, PayloadKind},
+    error::{bail, error, Error, Result},
+    index_btree::IndexNode,
+    page::{page_header::PageType, table_interior::TableInteriorPage, TablePage},
+    record::{codec, CellValue, Record},
+    row::{ColumnReader, ColumnType},
     schema::SqliteSchema,
+    transaction::Transaction,
 };
-use anyhow::{anyhow, bail, Ok, Result};
-use ptree::{print_tree_with,  PrintConfig, Style, TreeItem};
+use itertools::Itertools;
+use ptree::{print_tree_with, PrintConfig, Style, TreeItem};
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct TableBTree {
-    pub root_node: TableNode,
-    pub schema: Rc<SqliteSchema>,
+    pub root_page: u32,
+    pub schema: Arc<SqliteSchema>,
 }
+
+/// A table b-tree read into memory as a whole, used to print it
 #[derive(Debug, Clone)]
 pub struct TableNode {
     pub page: TablePage,
@@ -22,82 +30,287 @@ pub struct TableNode {
 }
 
 impl TableNode {
-    pub fn cells<'a>(&'a self) -> Box<dyn Iterator<Item = &(u32, u16)> + 'a> {
-        match &self.page {
-            TablePage::Leaf(l) => Box::new(l.cell_pointers.iter()),
-            TablePage::Interior(_) => Box::new(self.children.iter().flat_map(|n| n.cells())),
-        }
+    pub fn new(page: TablePage, db: &Database) -> Result<TableNode> {
+        TableNode::read(page, db, 0)
     }
 
-    pub fn new(page: TablePage, db: &Database) -> Result<TableNode> {
+    /// Reads the subtree below a page that has `depth` pages above it
+    fn read(page: TablePage, db: &Database, depth: usize) -> Result<TableNode> {
         Ok(match &page {
             TablePage::Leaf(_) => TableNode {
                 page,
                 children: Vec::new(),
             },
             TablePage::Interior(i) => {
-                let children = TableBTree::get_child_pages(db, i)?;
+                let children = TableBTree::get_child_pages(db, i, depth + 1)?;
                 TableNode { page, children }
             }
         })
     }
-    pub fn get_row(&self, db: &Database, row_id: i64) -> Result<Record> {
-        match &self.page {
-            TablePage::Leaf(leaf) => {
-                let mut row_ids = Vec::<i64>::new();
-                for (page_number, pointer) in leaf.cell_pointers.iter() {
-                    let record = db.read_record(*page_number, *pointer)?;
-                    row_ids.push(record.row_id);
-                    if record.row_id == row_id {
-                        return Ok(record);
-                    }
-                }
-                bail!("could not find leaf");
-            }
-            TablePage::Interior(_) => {
-                return self
-                    .children
-                    .iter()
-                    .find(|p| row_id <= p.page.get_row_id())
-                    .expect("this should exsist")
-                    .get_row(db, row_id)
-            }
-        }
-    }
 }
 
 impl TableBTree {
-    pub fn new(db: &Database, schema: Rc<SqliteSchema>) -> Result<Self> {
+    pub fn new(db: &Database, schema: Arc<SqliteSchema>) -> Result<Self> {
         let SqliteSchema::Table(t_schema) = schema.as_ref() else {
-            bail!("expected table schema but got index");
+            bail!(TypeMismatch, "expected table schema but got index");
         };
-        let root_node = TableNode::new(db.read_table_page(t_schema.root_page, None)?, db)?;
+        // WITHOUT ROWID tables live in an index b-tree keyed by their primary key,
+        // reading the root page checks the tree is of the expected kind
+        match t_schema.without_rowid {
+            true => db.read_index_page(t_schema.root_page, None).map(|_| ())?,
+            false => db.read_table_page(t_schema.root_page, None).map(|_| ())?,
+        }
         Ok(TableBTree {
-            root_node,
+            root_page: t_schema.root_page,
             schema: schema.clone(),
         })
     }
 
-    fn get_child_pages(db: &Database, page: &TableInteriorPage) -> Result<Vec<TableNode>> {
+    pub fn without_rowid(&self) -> bool {
+        matches!(self.schema.as_ref(), SqliteSchema::Table(t) if t.without_rowid)
+    }
+
+    fn get_child_pages(
+        db: &Database,
+        page: &TableInteriorPage,
+        depth: usize,
+    ) -> Result<Vec<TableNode>> {
         let mut result = Vec::new();
         for cell in &page.cells {
+            cursor::check_depth(depth, cell.left_child_page_number)?;
             let page = db.read_table_page(cell.left_child_page_number, Some(cell.row_id))?;
-            let node = TableNode::new(page, db)?;
+            let node = TableNode::read(page, db, depth)?;
             result.push(node);
         }
         Ok(result)
     }
 
-    pub fn row_reader<'a>(&'a self, db: &'a Database) -> RowReader {
+    pub fn row_reader<'a>(&self, db: &'a Database) -> RowReader<'a> {
         RowReader::new(self, db)
     }
 
-    pub fn get_row<'a>(&'a self, db: &'a Database, row_id: i64) -> Result<TableRow> {
-        let record = self.root_node.get_row(db, row_id)?;
-        Ok(TableRow::new(db, record, self.schema.clone()))
+    pub fn get_row<'a>(&self, db: &'a Database, row_id: i64) -> Result<TableRow<'a>> {
+        if self.without_rowid() {
+            bail!(
+                Unsupported,
+                "{} is a WITHOUT ROWID table",
+                self.schema.get_name()
+            );
+        }
+        let mut page = db.read_table_page(self.root_page, None)?;
+        let mut depth = 0;
+        loop {
+            match page {
+                TablePage::Leaf(leaf) => {
+                    // only the rowid of each probed cell is read, the record just for the hit
+                    let cells = &leaf.cell_pointers;
+                    let row_id_at = |i: usize| db.read_cell_row_id(cells[i].0, cells[i].1);
+                    let i = cursor::partition_point(cells.len(), |i| Ok(row_id_at(i)? < row_id))?;
+                    if i == cells.len() || row_id_at(i)? != row_id {
+                        bail!(
+                            Corrupt,
+                            "row {} not found in {}",
+                            row_id,
+                            self.schema.get_name()
+                        );
+                    }
+                    let record = db.read_record(cells[i].0, cells[i].1)?;
+                    return Ok(TableRow::new(db, record, self.schema.clone()));
+                }
+                TablePage::Interior(interior) => {
+                    // the right-most pointer is kept as the last cell, rows past the
+                    // last divider key live under it
+                    let (right, dividers) = interior
+                        .cells
+                        .split_last()
+                        .ok_or_else(|| error!(Corrupt, "interior page has no children"))?;
+                    let i = dividers.partition_point(|c| c.row_id < row_id);
+                    let child = dividers.get(i).unwrap_or(right).left_child_page_number;
+                    depth += 1;
+                    cursor::check_depth(depth, child)?;
+                    page = db.read_table_page(child, None)?;
+                }
+            }
+        }
+    }
+
+    /// Looks up a row of a WITHOUT ROWID table by the values of its primary key columns,
+    /// in key order
+    pub fn get_row_by_key<'a>(
+        &self,
+        db: &'a Database,
+        key: &[CellValue],
+    ) -> Result<Option<TableRow<'a>>> {
+        let SqliteSchema::Table(schema) = self.schema.as_ref() else {
+            bail!(TypeMismatch, "expected table schema but got index");
+        };
+        if !schema.without_rowid {
+            bail!(Unsupported, "{} is not a WITHOUT ROWID table", schema.name);
+        }
+        if key.len() != schema.primary_key.len() {
+            bail!(
+                TypeMismatch,
+                "primary key of {} has {} columns",
+                schema.name,
+                schema.primary_key.len()
+            );
+        }
+        // key columns compare in the collation of the table column
+        let orders = schema
+            .primary_key
+            .iter()
+            .map(|(i, order)| {
+                (
+                    *order,
+                    schema.columns[*i].collation.as_deref().map(String::from),
+                )
+            })
+            .collect_vec();
+        let Some(location) = IndexCursor::seek(db, self.root_page, key, &orders)?.next() else {
+            return Ok(None);
+        };
+        let (page_number, pointer) = location?;
+        let record = db.read_index_record(page_number, pointer)?;
+        if cursor::compare(db, &record, key, &orders)? != Ordering::Equal {
+            return Ok(None);
+        }
+        Ok(Some(TableRow::new(db, record, self.schema.clone())))
+    }
+
+    /// The largest rowid in the table as the transaction has left it, None when the table
+    /// is empty
+    pub fn max_row_id(&self, db: &Database, transaction: &Transaction) -> Result<Option<i64>> {
+        // the largest rowid is the last cell of the right-most leaf
+        let mut page = transaction.btree_page(db, self.root_page)?;
+        let mut depth = 0;
+        while !page.is_leaf() {
+            depth += 1;
+            cursor::check_depth(depth, page.right_child())?;
+            page = transaction.btree_page(db, page.right_child())?;
+        }
+        match page.cell_count() {
+            0 => Ok(None),
+            count => page.cell_row_id(count - 1).map(Some),
+        }
+    }
+
+    /// Adds a row with the record as its payload, failing if the rowid is already taken
+    pub fn insert(
+        &self,
+        db: &Database,
+        transaction: &mut Transaction,
+        row_id: i64,
+        payload: &[u8],
+    ) -> Result<()> {
+        let SqliteSchema::Table(schema) = self.schema.as_ref() else {
+            bail!(TypeMismatch, "expected table schema but got index");
+        };
+        if schema.without_rowid {
+            bail!(Unsupported, "{} is a WITHOUT ROWID table", schema.name);
+        }
+
+        let Position {
+            path,
+            page_number,
+            index,
+            found,
+        } = self.seek(db, transaction, row_id)?;
+        if found {
+            let column = schema.rowid_alias.map(|c| schema.columns[c].name.as_ref());
+            bail!(
+                Constraint,
+                "UNIQUE constraint failed: {}.{}",
+                schema.name,
+                column.unwrap_or("rowid")
+            );
+        }
+
+        // https://www.sqlite.org/fileformat2.html#b_tree_pages
+        //
+        // A table leaf cell is the payload size and the rowid as varints followed by
+        // as much of the payload as is stored on the page, and the first overflow page
+        // when the rest of it spills onto overflow pages
+        let mut cell = codec::encode_varint(payload.len() as i64);
+        cell.extend(codec::encode_varint(row_id));
+        let local = db.local_payload_size(payload.len(), PayloadKind::Table);
+        cell.extend_from_slice(&payload[..local]);
+        if local < payload.len() {
+            let overflow = transaction.write_overflow(db, &payload[local..])?;
+            cell.extend(overflow.to_be_bytes());
+        }
+
+        balance::insert_cells(db, transaction, &path, page_number, index, &[cell])
+    }
+
+    /// Removes a row, returning its record
+    pub fn delete(
+        &self,
+        db: &Database,
+        transaction: &mut Transaction,
+        row_id: i64,
+    ) -> Result<Vec<u8>> {
+        let Position {
+            path,
+            page_number,
+            index,
+            found,
+        } = self.seek(db, transaction, row_id)?;
+        if !found {
+            bail!(Corrupt, "{} has no row {}", self.schema.get_name(), row_id);
+        }
+        let page = transaction.btree_page(db, page_number)?;
+        let (local, overflow) = page.cell_payload(db, index)?;
+        let payload = match overflow {
+            Some((first_page, size)) => {
+                let payload = [local, &transaction.read_overflow(db, first_page, size)?].concat();
+                transaction.free_overflow(db, first_page, size)?;
+                payload
+            }
+            None => local.to_vec(),
+        };
+        balance::delete_cell(db, transaction, &path, page_number, index)?;
+        Ok(payload)
+    }
+
+    /// Finds the leaf a rowid is on or belongs on, with the interior pages passed on the
+    /// way down and whether the row is there
+    fn seek(&self, db: &Database, transaction: &Transaction, row_id: i64) -> Result<Position> {
+        let mut path = Vec::new();
+        let mut page_number = self.root_page;
+        loop {
+            cursor::check_depth(path.len(), page_number)?;
+            let page = transaction.btree_page(db, page_number)?;
+            let count = page.cell_count();
+            let i = cursor::partition_point(count, |i| Ok(page.cell_row_id(i)? < row_id))?;
+            match page.page_type() {
+                // rows up to the rowid of a divider cell live under its left child
+                PageType::TableInterior => {
+                    path.push((page_number, i));
+                    page_number = match i {
+                        i if i == count => page.right_child(),
+                        i => page.left_child(i)?,
+                    }
+                }
+                PageType::TableLeaf => {
+                    let found = i < count && page.cell_row_id(i)? == row_id;
+                    return Ok(Position {
+                        path,
+                        page_number,
+                        index: i,
+                        found,
+                    });
+                }
+                _ => bail!(
+                    Corrupt,
+                    "page {} of {} is not a table page",
+                    page_number,
+                    self.schema.get_name()
+                ),
+            }
+        }
     }
 
-    pub fn pretty_print(&self) -> Result<()> {
+    pub fn pretty_print(&self, db: &Database) -> Result<()> {
         let config = PrintConfig {
             leaf: Style {
                 bold: true,
@@ -106,20 +319,37 @@ impl TableBTree {
             branch: Style { ..Style::default() },
             ..PrintConfig::default()
         };
-        print_tree_with(&self.root_node, &config)?;
+        match self.without_rowid() {
+            true => {
+                let root = IndexNode::new(db.read_index_page(self.root_page, None)?, db)?;
+                print_tree_with(&root, &config)?
+            }
+            false => {
+                let root = TableNode::new(db.read_table_page(self.root_page, None)?, db)?;
+                print_tree_with(&root, &config)?
+            }
+        }
         Ok(())
     }
 }
 
+/// Reads the rows of a table in order, loading pages as it goes
 pub struct RowReader<'a> {
     db: &'a Database,
-    iter: Box<dyn Iterator<Item = &'a (u32, u16)> + 'a>,
-    schema: Rc<SqliteSchema>,
+    cells: Box<dyn Iterator<Item = Result<(u32, u16)>> + 'a>,
+    without_rowid: bool,
+    schema: Arc<SqliteSchema>,
 }
 impl<'a> RowReader<'a> {
-    pub fn new(tree: &'a TableBTree, db: &'a Database) -> Self {
+    pub fn new(tree: &TableBTree, db: &'a Database) -> Self {
+        let without_rowid = tree.without_rowid();
+        let cells: Box<dyn Iterator<Item = _>> = match without_rowid {
+            true => Box::new(IndexCursor::new(db, tree.root_page)),
+            false => Box::new(TableCursor::new(db, tree.root_page)),
+        };
         RowReader {
-            iter: tree.root_node.cells(),
+            cells,
+            without_rowid,
             db,
             schema: tree.schema.clone(),
         }
@@ -130,42 +360,71 @@ impl<'a> Iterator for RowReader<'a> {
     type Item = Result<TableRow<'a>>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        let record = self
-            .iter
-            .next()
-            .map(|(page_number, pointer)| self.db.read_record(*page_number, *pointer))?
-            .unwrap();
-        Some(Ok(TableRow::new(self.db, record, self.schema.clone())))
+        let location = self.cells.next()?;
+        let record = location.and_then(|(page_number, pointer)| match self.without_rowid {
+            true => self.db.read_index_record(page_number, pointer),
+            false => self.db.read_record(page_number, pointer),
+        });
+        Some(record.map(|record| TableRow::new(self.db, record, self.schema.clone())))
     }
 }
 
 pub struct TableRow<'a> {
     pub record: Record,
-    schema: Rc<SqliteSchema>,
+    schema: Arc<SqliteSchema>,
     db: &'a Database,
 }
 
 impl<'a> TableRow<'a> {
-    pub fn new(db: &'a Database, record: Record, schema: Rc<SqliteSchema>) -> Self {
+    pub fn new(db: &'a Database, record: Record, schema: Arc<SqliteSchema>) -> Self {
         TableRow { record, schema, db }
     }
     pub fn read_column(&self, column_name: &str) -> Result<CellValue> {
         let SqliteSchema::Table(schema) = self.schema.as_ref() else {
-            unreachable!("this has to be a table schema");
+            bail!(TypeMismatch, "expected table schema but got index");
         };
 
-        if column_name == "id" {
+        if schema.is_rowid_column(column_name) {
             return Ok(CellValue::Int(self.record.row_id));
         }
 
-        let (index, _) = schema
-            .columns
-            .iter()
-            .enumerate()
-            .find(|f| *f.1.name == *column_name)
-            .ok_or(anyhow!("Invalid column name: {}", column_name))?;
+        let index = schema
+            .column_index(column_name)
+            .ok_or_else(|| Error::NoSuchColumn(column_name.to_string()))?;
+
+        self.db
+            .read_record_cell(&self.record, schema.record_index(index))
+    }
+}
 
-        self.db.read_record_cell(&self.record, index)
+impl ColumnReader for TableRow<'_> {
+    fn read_column(&self, column_name: &str) -> Result<CellValue> {
+        TableRow::read_column(self, column_name)
+    }
+
+    fn column_names(&self) -> Vec<String> {
+        let SqliteSchema::Table(schema) = self.schema.as_ref() else {
+            return Vec::new();
+        };
+        schema.columns.iter().map(|c| c.name.to_string()).collect()
+    }
+
+    fn column_type(&self, column_name: &str) -> Option<ColumnType> {
+        let SqliteSchema::Table(schema) = self.schema.as_ref() else {
+            return None;
+        };
+        match schema.column_index(column_name) {
+            Some(index) => Some(ColumnType {
+                affinity: schema.columns[index].type_affinity.clone(),
+                collation: schema.columns[index].collation.clone(),
+            }),
+            // rowid, oid and _rowid_ when no column takes the name
+            None if schema.is_rowid_column(column_name) => Some(ColumnType {
+                affinity: TypeAffinity::Int,
+                collation: None,
+            }),
+            None => None,
+        }
     }
 }
 
@@ -183,7 +442,7 @@ impl TreeItem for TableNode {
         }
     }
 
-    fn children(&self) -> std::borrow::Cow<[Self::Child]> {
+    fn children(&self) -> std::borrow::Cow<'_, [Self::Child]> {
         match &self.page {
             TablePage::Leaf(_) => Cow::from(vec![]),
             TablePage::Interior(_) => self.children.to_owned().into(),
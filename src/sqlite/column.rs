@@ -1,11 +1,18 @@
-use std::{fmt, rc::Rc};
+use std::{fmt, sync::Arc};
 
-use sqlparser::ast::DataType;
+use super::record::CellValue;
 
 #[derive(Debug, Clone)]
 pub struct Column {
     pub type_affinity: TypeAffinity,
-    pub name: Rc<str>,
+    pub name: Arc<str>,
+    /// The COLLATE clause of the column definition, BINARY when unset
+    pub collation: Option<Arc<str>>,
+    pub not_null: bool,
+    /// Whether the column has a DEFAULT value, which rows that leave it out take
+    pub has_default: bool,
+    /// Generated columns are computed from the others, VIRTUAL ones aren't stored at all
+    pub generated: bool,
 }
 
 #[derive(Debug, Clone)]
@@ -29,79 +36,61 @@ impl fmt::Display for TypeAffinity {
     }
 }
 
-impl From<&DataType> for TypeAffinity {
-    fn from(value: &DataType) -> Self {
-        //https://www.sqlite.org/datatype3.html#type_affinity
-        match value {
-            //1. If the declared type contains the string "INT" then it is assigned INTEGER affinity.
-            DataType::TinyInt(_)
-            | DataType::UnsignedTinyInt(_)
-            | DataType::Int2(_)
-            | DataType::UnsignedInt2(_)
-            | DataType::SmallInt(_)
-            | DataType::UnsignedSmallInt(_)
-            | DataType::MediumInt(_)
-            | DataType::UnsignedMediumInt(_)
-            | DataType::Int(_)
-            | DataType::Int4(_)
-            | DataType::Integer(_)
-            | DataType::UnsignedInt(_)
-            | DataType::UnsignedInt4(_)
-            | DataType::UnsignedInteger(_)
-            | DataType::BigInt(_)
-            | DataType::UnsignedBigInt(_)
-            | DataType::Int8(_)
-            | DataType::UnsignedInt8(_)
-            | DataType::Interval => TypeAffinity::Int,
+impl TypeAffinity {
+    // https://www.sqlite.org/datatype3.html#determination_of_column_affinity
+    pub fn from_declared_type(declared_type: Option<&str>) -> Self {
+        let Some(declared_type) = declared_type else {
+            return TypeAffinity::Blob;
+        };
+        let declared_type = declared_type.to_uppercase();
+        let contains = |parts: &[&str]| parts.iter().any(|p| declared_type.contains(p));
 
-            //2.If the declared type of the column contains any of the strings "CHAR", "CLOB", or "TEXT" then that column has TEXT affinity.
-            //Notice that the type VARCHAR contains the string "CHAR" and is thus assigned TEXT affinity.
-            DataType::Character(_)
-            | DataType::Char(_)
-            | DataType::CharacterVarying(_)
-            | DataType::CharVarying(_)
-            | DataType::Varchar(_)
-            | DataType::Nvarchar(_)
-            | DataType::Text
-            | DataType::Clob(_) => TypeAffinity::Text,
-
-            //3. //If the declared type for a column contains the string "BLOB" or if no type is specified then the column has affinity BLOB.
-            DataType::Blob(_) => TypeAffinity::Blob,
-
-            //4. If the declared type for a column contains any of the strings
-            //"REAL", "FLOA", or "DOUB" then the column has REAL affinity.
-            DataType::Double
-            | DataType::DoublePrecision
-            | DataType::Float(_)
-            | DataType::Float4
-            | DataType::Float8
-            | DataType::Real => TypeAffinity::Real,
+        if contains(&["INT"]) {
+            TypeAffinity::Int
+        } else if contains(&["CHAR", "CLOB", "TEXT"]) {
+            TypeAffinity::Text
+        } else if contains(&["BLOB"]) {
+            TypeAffinity::Blob
+        } else if contains(&["REAL", "FLOA", "DOUB"]) {
+            TypeAffinity::Real
+        } else {
+            TypeAffinity::Numeric
+        }
+    }
 
-            //5. Otherwise, the affinity is NUMERIC.
-            DataType::Numeric(_)
-            | DataType::Decimal(_)
-            | DataType::BigNumeric(_)
-            | DataType::BigDecimal(_)
-            | DataType::Dec(_)
-            | DataType::Bool
-            | DataType::Boolean
-            | DataType::Date
-            | DataType::Time(_, _)
-            | DataType::Datetime(_)
-            | DataType::Timestamp(_, _)
-            | DataType::JSON
-            | DataType::Regclass
-            | DataType::String
-            | DataType::Bytea
-            | DataType::Custom(_, _)
-            | DataType::Array(_)
-            | DataType::Enum(_)
-            | DataType::Set(_)
-            | DataType::Uuid
-            | DataType::CharacterLargeObject(_)
-            | DataType::CharLargeObject(_)
-            | DataType::Binary(_)
-            | DataType::Varbinary(_) => TypeAffinity::Numeric,
+    // https://www.sqlite.org/datatype3.html#type_affinity
+    //
+    // Converts a value the way sqlite does before storing it in, or comparing it
+    // against, a column with this affinity
+    pub fn apply(&self, value: CellValue) -> CellValue {
+        match (self, value) {
+            (TypeAffinity::Int | TypeAffinity::Numeric, CellValue::String(s)) => {
+                let trimmed = s.trim();
+                if let Ok(i) = trimmed.parse::<i64>() {
+                    CellValue::Int(i)
+                } else {
+                    match trimmed.parse::<f64>() {
+                        Ok(f) if f.fract() == 0.0 && f.abs() < i64::MAX as f64 => {
+                            CellValue::Int(f as i64)
+                        }
+                        Ok(f) => CellValue::Float(f),
+                        Err(_) => CellValue::String(s),
+                    }
+                }
+            }
+            (TypeAffinity::Int | TypeAffinity::Numeric, CellValue::Float(f))
+                if f.fract() == 0.0 && f.abs() < i64::MAX as f64 =>
+            {
+                CellValue::Int(f as i64)
+            }
+            (TypeAffinity::Real, CellValue::String(s)) => match s.trim().parse::<f64>() {
+                Ok(f) => CellValue::Float(f),
+                Err(_) => CellValue::String(s),
+            },
+            (TypeAffinity::Real, CellValue::Int(i)) => CellValue::Float(i as f64),
+            (TypeAffinity::Text, CellValue::Int(i)) => CellValue::String(i.to_string()),
+            (TypeAffinity::Text, CellValue::Float(f)) => CellValue::String(f.to_string()),
+            (_, value) => value,
         }
     }
 }
@@ -1,4 +1,3 @@
-use anyhow::{bail, Ok, Result};
 use itertools::Itertools;
 
 use sqlparser::{
@@ -6,20 +5,63 @@ use sqlparser::{
     dialect::SQLiteDialect,
     parser::Parser,
 };
-use std::rc::Rc;
+use std::{
+    cmp::Ordering,
+    collections::{BTreeSet, HashMap, HashSet},
+    fmt, iter,
+    ops::Bound,
+    sync::Arc,
+};
 
 use crate::sqlite::{
-    btree::TableRow, record::CellValue, schema::SqliteSchema, sql::sql_engine::Operator,
+    btree::TableRow,
+    record::{known_collation, CellValue},
+    schema::SqliteSchema,
+    sql::sql_engine::Operator,
 };
 
 use super::{
     btree::TableBTree,
-    database::Database,
+    column::TypeAffinity,
+    database::{Database, OpenOptions},
+    error::{bail, Error, Result},
     index_btree::IndexBTree,
-    sql::sql_engine::{self, AggregateFunction, Expression, Object, Query},
+    integrity::{self, IntegrityCheck},
+    record::codec,
+    row::{ColumnReader, ColumnType, Row},
+    schema::{schema_parser::SortOrder, table_schema::TableSchema},
+    sql::sql_engine::{
+        self, AggregateFunction, DeleteQuery, Expression, InsertQuery, Object, Query, SelectQuery,
+    },
+    storage::Storage,
+    transaction::Transaction,
 };
 
+/// The lower and upper bound of a range of index keys
+type KeyRange = (Bound<CellValue>, Bound<CellValue>);
+
+/// The rows of a select, read from the database as they're iterated
+type Rows<'a> = Box<dyn Iterator<Item = Result<Row>> + 'a>;
+
 static DIALECT: SQLiteDialect = SQLiteDialect {};
+
+/// The lowercased name of a PRAGMA statement and its argument, given as either
+/// `PRAGMA name = value` or `PRAGMA name(value)`. None for any other statement.
+fn parse_pragma(sql: &str) -> Option<(String, Option<String>)> {
+    let sql = sql.trim().trim_end_matches(';').trim_end();
+    let (keyword, pragma) = sql.split_once(char::is_whitespace)?;
+    if !keyword.eq_ignore_ascii_case("PRAGMA") {
+        return None;
+    }
+    let (name, argument) = match pragma.split_once(['(', '=']) {
+        Some((name, argument)) => (
+            name,
+            Some(argument.trim_end_matches(')').trim().to_string()),
+        ),
+        None => (pragma, None),
+    };
+    Some((name.trim().to_ascii_lowercase(), argument))
+}
 pub struct Connection {
     db: Database,
 }
@@ -31,7 +73,19 @@ impl Connection {
         })
     }
 
-    pub fn get_schema(&self) -> Vec<Rc<SqliteSchema>> {
+    pub fn with_options(file_path: impl Into<String>, options: OpenOptions) -> Result<Connection> {
+        Ok(Connection {
+            db: Database::with_options(file_path, options)?,
+        })
+    }
+
+    pub fn from_storage(storage: Box<dyn Storage>, options: OpenOptions) -> Result<Connection> {
+        Ok(Connection {
+            db: Database::from_storage(storage, options)?,
+        })
+    }
+
+    pub fn get_schema(&self) -> Vec<Arc<SqliteSchema>> {
         self.db.get_schemas()
     }
 
@@ -39,125 +93,605 @@ impl Connection {
         &self.db.header
     }
 
-    pub fn execute_query(&self, sql: impl AsRef<str>) -> Result<()> {
-        let mut ast = Parser::parse_sql(&DIALECT, sql.as_ref())?;
-        let exp: Query = match (ast.pop(), ast.pop()) {
-            (Some(s), None) => (&s).try_into()?,
-            _ => bail!("only a single expression is currently supported"),
+    /// Runs any statement, printing the rows it returns as they're read
+    pub fn execute_query(&mut self, sql: impl AsRef<str>) -> Result<()> {
+        let rows: Rows = match parse_pragma(sql.as_ref()) {
+            Some((pragma, argument)) => Box::new(
+                self.run_pragma(&pragma, argument.as_deref())?
+                    .into_iter()
+                    .map(Ok),
+            ),
+            None => match Connection::parse_query(sql.as_ref())? {
+                Query::Select(select) => self.run_select(&select, &mut HashSet::new())?,
+                Query::Insert(insert) => {
+                    self.write(|conn, transaction| conn.run_insert(&insert, transaction))?;
+                    return Ok(());
+                }
+                Query::Delete(delete) => {
+                    self.write(|conn, transaction| conn.run_delete(&delete, transaction))?;
+                    return Ok(());
+                }
+            },
         };
+        for row in rows {
+            println!("{}", row?.cells.iter().map(|f| f.to_string()).join("|"));
+        }
+        Ok(())
+    }
 
-        let Query::Select(select) = exp;
+    pub fn query_rows(&self, sql: impl AsRef<str>) -> Result<Vec<Row>> {
+        // the sql parser doesn't know sqlite's PRAGMA statements
+        if let Some((pragma, argument)) = parse_pragma(sql.as_ref()) {
+            return self.run_pragma(&pragma, argument.as_deref());
+        }
+        match Connection::parse_query(sql.as_ref())? {
+            Query::Select(select) => self.run_select(&select, &mut HashSet::new())?.try_collect(),
+            Query::Insert(_) => bail!(
+                Unsupported,
+                "INSERT changes the database, run it with execute"
+            ),
+            Query::Delete(_) => bail!(
+                Unsupported,
+                "DELETE changes the database, run it with execute"
+            ),
+        }
+    }
 
-        if select.sources.len() != 1 {
-            bail!("only a single source is currently supported")
+    pub fn execute(&mut self, sql: impl AsRef<str>) -> Result<usize> {
+        match Connection::parse_query(sql.as_ref())? {
+            Query::Insert(insert) => {
+                self.write(|conn, transaction| conn.run_insert(&insert, transaction))
+            }
+            Query::Delete(delete) => {
+                self.write(|conn, transaction| conn.run_delete(&delete, transaction))
+            }
+            Query::Select(_) => bail!(Unsupported, "SELECT returns rows, run it with query_rows"),
         }
-        let source_name = match &select.sources[0] {
-            sql_engine::Source::Table(t) => t.to_owned(),
+    }
+
+    /// Runs a change in a transaction, which is committed when it succeeds and rolled
+    /// back when it fails. The transaction begins before anything is read so the change
+    /// sees what other connections committed.
+    fn write<T>(
+        &mut self,
+        change: impl FnOnce(&Connection, &mut Transaction) -> Result<T>,
+    ) -> Result<T> {
+        let mut transaction = self.db.begin()?;
+        match change(self, &mut transaction) {
+            Ok(changed) => {
+                self.db.commit(transaction)?;
+                Ok(changed)
+            }
+            Err(e) => {
+                self.db.rollback(transaction)?;
+                Err(e)
+            }
+        }
+    }
+
+    fn parse_query(sql: &str) -> Result<Query> {
+        let mut ast = Parser::parse_sql(&DIALECT, sql)?;
+        match (ast.pop(), ast.pop()) {
+            (Some(s), None) => (&s).try_into(),
+            _ => bail!(
+                Unsupported,
+                "only a single expression is currently supported"
+            ),
+        }
+    }
+
+    // https://www.sqlite.org/lang_insert.html
+    //
+    // Every row of VALUES becomes a record holding a value for each column of the table,
+    // NULL for the columns left out. The rowid is the one given for the INTEGER PRIMARY
+    // KEY column or one of the rowid names, or one more than the largest rowid in use.
+    fn run_insert(&self, insert: &InsertQuery, transaction: &mut Transaction) -> Result<usize> {
+        let schema = self.db.get_table_schema(&insert.table)?;
+        let SqliteSchema::Table(table) = schema.as_ref() else {
+            bail!(TypeMismatch, "{} is not a table", insert.table);
         };
+        if table.without_rowid {
+            bail!(
+                Unsupported,
+                "inserting into WITHOUT ROWID tables is not supported"
+            );
+        }
+        let indexes = self.writable_indexes(table, "inserting into")?;
+        if table.columns.iter().any(|c| c.generated) {
+            bail!(
+                Unsupported,
+                "inserting into tables with generated columns is not supported"
+            );
+        }
 
-        // handle aggregate functions
-        if select
-            .selections
-            .iter()
-            .any(|f| matches!(f, sql_engine::Selection::AggFn(_)))
-        {
-            let tree = self.get_tree(&source_name)?;
-            let agg_fn: &AggregateFunction = select
-                .selections
+        // None stands for the rowid when it is given by one of its own names
+        let columns: Vec<Option<usize>> = match insert.columns.is_empty() {
+            true => (0..table.columns.len()).map(Some).collect(),
+            false => insert
+                .columns
                 .iter()
-                .map(|sel_item| match sel_item {
-                    sql_engine::Selection::Identifier(_) => panic!("ruh roh"),
-                    sql_engine::Selection::AggFn(a) => a,
+                .map(|name| match table.column_index(name) {
+                    Some(i) => Ok(Some(i)),
+                    None if table.is_rowid_column(name) => Ok(None),
+                    None => Err(Error::NoSuchColumn(name.to_owned())),
                 })
-                .collect_vec()[0];
-
-            match agg_fn {
-                AggregateFunction::Count => {
-                    let mut count = 0;
-                    for row in tree.row_reader(&self.db) {
-                        let row = row?;
-                        if Connection::evalute_clause(&row, &select.clause)? {
-                            count += 1;
-                        }
+                .try_collect()?,
+        };
+        for (i, column) in table.columns.iter().enumerate() {
+            if column.has_default && !columns.contains(&Some(i)) {
+                bail!(
+                    Unsupported,
+                    "DEFAULT values of left out columns are not supported"
+                );
+            }
+        }
+
+        let tree = TableBTree::new(&self.db, schema.clone())?;
+        // literals don't read any columns
+        let no_columns = Row {
+            columns: Arc::new(HashMap::new()),
+            cells: Vec::new(),
+        };
+        for values in &insert.rows {
+            if values.len() != columns.len() {
+                bail!(
+                    Parse,
+                    "{} values for {} columns",
+                    values.len(),
+                    columns.len()
+                );
+            }
+            let mut record = vec![CellValue::Null; table.columns.len()];
+            let mut row_id = CellValue::Null;
+            for (column, value) in columns.iter().zip(values) {
+                let value = Connection::evalute_value(&no_columns, value)?;
+                match column {
+                    Some(i) if table.rowid_alias != Some(*i) => {
+                        record[*i] = table.columns[*i].type_affinity.apply(value)
                     }
-                    println!("{}", count);
+                    _ => row_id = TypeAffinity::Int.apply(value),
                 }
             }
-            return Ok(());
+            // the rowid alias is stored as NULL, its value is the rowid
+            for (i, column) in table.columns.iter().enumerate() {
+                if column.not_null && record[i] == CellValue::Null && table.rowid_alias != Some(i) {
+                    bail!(
+                        Constraint,
+                        "NOT NULL constraint failed: {}.{}",
+                        table.name,
+                        column.name
+                    );
+                }
+            }
+            let row_id = match row_id {
+                CellValue::Int(row_id) => row_id,
+                CellValue::Null => match tree.max_row_id(&self.db, transaction)? {
+                    None => 1,
+                    Some(i64::MAX) => bail!(Unsupported, "the largest rowid is already in use"),
+                    Some(max) => max + 1,
+                },
+                _ => bail!(TypeMismatch, "datatype mismatch"),
+            };
+            let payload = codec::encode_record(
+                &record,
+                self.db.header.text_encoding,
+                self.db.header.schema_format,
+            );
+            tree.insert(&self.db, transaction, row_id, &payload)?;
+            for (index, columns) in &indexes {
+                let entry = Connection::index_entry(columns, &record, row_id);
+                index.insert(&self.db, transaction, &entry)?;
+            }
         }
+        Ok(insert.rows.len())
+    }
 
-        let columns: Vec<String> = select
-            .selections
-            .iter()
-            .map(|sel_item| match sel_item {
-                sql_engine::Selection::Identifier(i) => Ok(i.to_owned()),
-                sql_engine::Selection::AggFn(_) => {
-                    bail!("can't mix agg and table values")
+    // https://www.sqlite.org/lang_delete.html
+    //
+    // The rows to delete are found the way a select finds them and are all read before
+    // the first one goes, then each row is removed from the table and from every index.
+    fn run_delete(&self, delete: &DeleteQuery, transaction: &mut Transaction) -> Result<usize> {
+        let schema = self.db.get_table_schema(&delete.table)?;
+        let SqliteSchema::Table(table) = schema.as_ref() else {
+            bail!(TypeMismatch, "{} is not a table", delete.table);
+        };
+        if table.without_rowid {
+            bail!(
+                Unsupported,
+                "deleting from WITHOUT ROWID tables is not supported"
+            );
+        }
+        let indexes = self.writable_indexes(table, "deleting from")?;
+
+        let tree = TableBTree::new(&self.db, schema.clone())?;
+        // a set, so a row found more than once is still only deleted once
+        let mut row_ids = BTreeSet::new();
+        for row in self.candidate_rows(table, tree.clone(), &delete.clause)? {
+            let row = row?;
+            if Connection::evalute_clause(&row, &delete.clause)? {
+                row_ids.insert(row.record.row_id);
+            }
+        }
+
+        for row_id in &row_ids {
+            let payload = tree.delete(&self.db, transaction, *row_id)?;
+            let mut record = codec::decode_record(&payload, self.db.header.text_encoding)?;
+            // columns added after the row was written are missing from its record
+            record.resize(table.columns.len(), CellValue::Null);
+            for (index, columns) in &indexes {
+                let entry = Connection::index_entry(columns, &record, *row_id);
+                index.delete(&self.db, transaction, &entry)?;
+            }
+        }
+        Ok(row_ids.len())
+    }
+
+    /// The indexes of a table that are kept up to date as rows change, each with the
+    /// columns it names where None stands for the rowid
+    fn writable_indexes(
+        &self,
+        table: &TableSchema,
+        action: &str,
+    ) -> Result<Vec<(IndexBTree, Vec<Option<usize>>)>> {
+        let mut indexes = Vec::new();
+        for other in self.db.get_schemas() {
+            match other.as_ref() {
+                SqliteSchema::Index(i) if i.parent_table == table.name => {
+                    if i.where_clause.is_some() || i.columns.iter().any(|c| c.expression) {
+                        bail!(
+                            Unsupported,
+                            "{} has partial or expression indexes, {} it is not supported",
+                            table.name,
+                            action
+                        );
+                    }
+                    let columns: Vec<Option<usize>> = i
+                        .columns
+                        .iter()
+                        .map(|c| match table.column_index(&c.name) {
+                            Some(i) if table.columns[i].generated => Err(Error::Unsupported(
+                                format!("{} indexes generated columns", table.name),
+                            )),
+                            Some(i) if table.rowid_alias != Some(i) => Ok(Some(i)),
+                            Some(_) => Ok(None),
+                            None if table.is_rowid_column(&c.name) => Ok(None),
+                            None => Err(Error::NoSuchColumn(c.name.to_owned())),
+                        })
+                        .try_collect()?;
+                    indexes.push((IndexBTree::new(&self.db, other.clone())?, columns));
                 }
+                SqliteSchema::Trigger(t) if t.table_name == table.name => bail!(
+                    Unsupported,
+                    "{} has triggers, {} tables with triggers is not supported",
+                    table.name,
+                    action
+                ),
+                _ => {}
+            }
+        }
+        Ok(indexes)
+    }
+
+    /// The entry an index has for a row, its key columns followed by the rowid
+    fn index_entry(columns: &[Option<usize>], record: &[CellValue], row_id: i64) -> Vec<CellValue> {
+        columns
+            .iter()
+            .map(|c| match c {
+                Some(i) => record[*i].clone(),
+                None => CellValue::Int(row_id),
             })
-            .try_collect()?;
+            .chain([CellValue::Int(row_id)])
+            .collect_vec()
+    }
 
-        let indexes = self.db.get_table_indexes(&source_name);
+    /// Checks the structure of the whole database file, returning the first 100 problems
+    /// found. An intact database gives an empty list.
+    pub fn integrity_check(&self) -> Result<Vec<String>> {
+        IntegrityCheck::run(&self.db, integrity::DEFAULT_MAX_ERRORS)
+    }
 
-        let where_columns = match &select.clause {
-            Some(exp) => exp.get_columns(),
-            None => vec![],
+    fn run_pragma(&self, pragma: &str, argument: Option<&str>) -> Result<Vec<Row>> {
+        let values = match (pragma, argument) {
+            // the argument of integrity_check limits the number of errors
+            ("integrity_check" | "quick_check", _) => {
+                let limit = argument.and_then(|a| a.parse::<usize>().ok());
+                let limit = limit
+                    .filter(|l| *l > 0)
+                    .unwrap_or(integrity::DEFAULT_MAX_ERRORS);
+                let mut problems = IntegrityCheck::run(&self.db, limit)?;
+                if problems.is_empty() {
+                    problems.push("ok".to_string());
+                }
+                problems.into_iter().map(CellValue::String).collect()
+            }
+            ("cache_size", None) => vec![CellValue::Int(self.db.cache_size() as i64)],
+            // like sqlite a negative size is a limit in KiB rather than in pages
+            ("cache_size", Some(size)) => {
+                let Result::Ok(size) = size.parse::<i64>() else {
+                    bail!(TypeMismatch, "cache_size must be an integer, got {}", size);
+                };
+                let pages = match size {
+                    0.. => size.unsigned_abs(),
+                    _ => size.unsigned_abs().saturating_mul(1024) / self.db.header.page_size as u64,
+                };
+                // sizes too large for memory are clamped, the cache only grows as it's used
+                let pages = usize::try_from(pages).unwrap_or(usize::MAX);
+                self.db.set_cache_size(pages);
+                Vec::new()
+            }
+            (p, _) => bail!(Unsupported, "PRAGMA {} is not currently supported", p),
+        };
+        let columns = Arc::new(HashMap::from([(pragma.to_string(), 0)]));
+        Ok(values
+            .into_iter()
+            .map(|value| Row {
+                columns: columns.clone(),
+                cells: vec![value],
+            })
+            .collect())
+    }
+
+    /// Runs a select against a table or a view, a view's own select runs as a subquery
+    /// whose rows the outer select reads from. `views` holds the views being expanded,
+    /// a view reached again through its own select is circularly defined.
+    fn run_select(&self, select: &SelectQuery, views: &mut HashSet<String>) -> Result<Rows<'_>> {
+        if select.sources.len() != 1 {
+            bail!(Unsupported, "only a single source is currently supported")
+        }
+        let source_name = match &select.sources[0] {
+            sql_engine::Source::Table(t) => t.to_owned(),
         };
 
-        if !where_columns.is_empty() && where_columns.iter().all(|f| indexes.contains(f)) {
-            if where_columns.len() != 1 {
-                bail!("only single column index where clauses are supported");
+        let view = self.db.get_view_schema(&source_name);
+        if let Some(SqliteSchema::View(view)) = view.as_deref() {
+            if !views.insert(view.name.to_string()) {
+                bail!(Corrupt, "view {} is circularly defined", view.name);
             }
-            let clause = &select.clause.unwrap();
-            let (column_name, value) = match clause {
-                Expression::InfixExpression(left, Operator::Equal, right) => {
-                    match (left.as_ref(), right.as_ref()) {
-                        (Expression::Identifier(ident), Expression::Literal(lit)) => (ident, lit),
-                        _ => bail!("invalide indxed where clause"),
+            let mut rows = self.run_select(&view.query()?.try_into()?, views)?;
+            if !view.columns.is_empty() {
+                let columns: Arc<HashMap<String, usize>> = Arc::new(
+                    view.columns
+                        .iter()
+                        .enumerate()
+                        .map(|(i, c)| (c.to_string(), i))
+                        .collect(),
+                );
+                let name = view.name.clone();
+                rows = Box::new(rows.map(move |row| {
+                    let mut row = row?;
+                    if row.cells.len() != columns.len() {
+                        bail!(
+                            Corrupt,
+                            "view {} has {} columns but its select returns {}",
+                            name,
+                            columns.len(),
+                            row.cells.len()
+                        );
                     }
+                    row.columns = columns.clone();
+                    Ok(row)
+                }));
+            }
+            return Connection::project(select, rows);
+        }
+
+        let schema = self.db.get_table_schema(&source_name)?;
+        let SqliteSchema::Table(table) = schema.as_ref() else {
+            bail!(TypeMismatch, "{} is not a table", source_name);
+        };
+        let tree = self.get_tree(&source_name)?;
+
+        // WITHOUT ROWID tables are stored keyed by their primary key, so equality on a
+        // single column key is a direct lookup
+        if let (
+            true,
+            [(key_column, _)],
+            Some(Expression::InfixExpression(left, Operator::Equal, right)),
+        ) = (table.without_rowid, &table.primary_key[..], &select.clause)
+        {
+            if let (Expression::Identifier(ident), Expression::Literal(lit)) =
+                (left.as_ref(), right.as_ref())
+            {
+                if table.column_index(ident) == Some(*key_column) {
+                    let value = table.columns[*key_column].type_affinity.apply(lit.clone());
+                    let row = tree.get_row_by_key(&self.db, &[value])?;
+                    return Connection::project(select, row.into_iter().map(Ok));
                 }
-                _ => bail!("invalide indxed where clause"),
-            };
+            }
+        }
+
+        let rows = self.candidate_rows(table, tree, &select.clause)?;
+        Connection::project(select, rows)
+    }
+
+    /// The rows of a table a WHERE clause may hold for, read through an index when one
+    /// can narrow them down. The clause still has to be checked against each row.
+    fn candidate_rows<'a>(
+        &'a self,
+        table: &TableSchema,
+        tree: TableBTree,
+        clause: &Option<Expression>,
+    ) -> Result<Box<dyn Iterator<Item = Result<TableRow<'a>>> + 'a>> {
+        let indexes = self.db.get_table_indexes(&table.name);
 
-            let index_tree = self.get_index_tree(&source_name, column_name)?;
-            let tree = self.get_tree(&source_name)?;
-            let row_ids = index_tree.get_row_ids(&self.db, value)?;
-            for row_id in row_ids {
-                let row = tree.get_row(&self.db, row_id);
-                let row = row?;
+        let ranges = clause
+            .as_ref()
+            .and_then(|clause| Connection::index_ranges(table, clause));
+        if let Some((column_name, ranges)) = ranges.filter(|(c, _)| indexes.contains(c)) {
+            // compare against the keys the way sqlite would store them in the column
+            let affinity = table
+                .column_index(&column_name)
+                .map(|i| &table.columns[i].type_affinity);
+            let apply = |bound: Bound<CellValue>| match affinity {
+                Some(affinity) => bound.map(|value| affinity.apply(value)),
+                None => bound,
+            };
 
-                let values: Vec<CellValue> =
-                    columns.iter().map(|f| row.read_column(f)).try_collect()?;
-                println!("{}", values.iter().map(|f| f.to_string()).join("|"));
+            let index_tree = self.get_index_tree(&table.name, &column_name)?;
+            let mut row_ids: Box<dyn Iterator<Item = Result<i64>>> = Box::new(iter::empty());
+            for (lower, upper) in ranges {
+                let range =
+                    index_tree.range(&self.db, apply(lower), apply(upper), SortOrder::Asc)?;
+                row_ids = Box::new(row_ids.chain(range));
             }
-            return Ok(());
+            // the ranges of an IN list can still hold the same keys in the index's
+            // collation, a row is read once however many of them it's in
+            let mut seen = HashSet::new();
+            let row_ids =
+                row_ids.filter(move |row_id| row_id.as_ref().map_or(true, |id| seen.insert(*id)));
+            let rows = row_ids.map(move |row_id| tree.get_row(&self.db, row_id?));
+            return Ok(Box::new(rows));
         }
 
-        let tree = self.get_tree(&source_name)?;
-        for row in tree.row_reader(&self.db) {
-            let row = row?;
-            if !Connection::evalute_clause(&row, &select.clause)? {
-                continue;
+        Ok(Box::new(tree.row_reader(&self.db)))
+    }
+
+    /// The column a WHERE clause restricts to ranges of values, if it's one an index can
+    /// answer: a comparison of the column with a literal, a BETWEEN or an IN list. The
+    /// rows are still filtered by the clause afterwards.
+    fn index_ranges(table: &TableSchema, clause: &Expression) -> Option<(String, Vec<KeyRange>)> {
+        // NULL sorts first in an index but never compares true, so it's left out of
+        // ranges that are open below
+        let above_null = Bound::Excluded(CellValue::Null);
+        let (column, ranges) = match clause {
+            Expression::InfixExpression(left, op, right) => {
+                let (column, op, value) = match (left.as_ref(), right.as_ref()) {
+                    (Expression::Identifier(i), Expression::Literal(v)) => (i, *op, v),
+                    (Expression::Literal(v), Expression::Identifier(i)) => (i, op.flip(), v),
+                    _ => return None,
+                };
+                let value = value.clone();
+                let range = match op {
+                    Operator::Equal => (Bound::Included(value.clone()), Bound::Included(value)),
+                    Operator::Less => (above_null, Bound::Excluded(value)),
+                    Operator::LessEqual => (above_null, Bound::Included(value)),
+                    Operator::Greater => (Bound::Excluded(value), Bound::Unbounded),
+                    Operator::GreaterEqual => (Bound::Included(value), Bound::Unbounded),
+                    _ => return None,
+                };
+                (column, vec![range])
             }
+            Expression::Between(value, low, high) => {
+                match (value.as_ref(), low.as_ref(), high.as_ref()) {
+                    (
+                        Expression::Identifier(i),
+                        Expression::Literal(low),
+                        Expression::Literal(high),
+                    ) => (
+                        i,
+                        vec![(Bound::Included(low.clone()), Bound::Included(high.clone()))],
+                    ),
+                    _ => return None,
+                }
+            }
+            Expression::InList(value, list) => {
+                let Expression::Identifier(column) = value.as_ref() else {
+                    return None;
+                };
+                // each value once and in index order, like the index would return them.
+                // Values are only the same key once they have the column's affinity, 13
+                // and '13' are one key of an INTEGER column.
+                let affinity = &table.columns[table.column_index(column)?].type_affinity;
+                let mut values = list
+                    .iter()
+                    .map(|e| match e {
+                        Expression::Literal(v) => Some(affinity.apply(v.clone())),
+                        _ => None,
+                    })
+                    .collect::<Option<Vec<_>>>()?;
+                values.sort_by(|l, r| l.sqlite_cmp(r));
+                values.dedup_by(|l, r| l.sqlite_cmp(r) == Ordering::Equal);
+                let ranges = values
+                    .into_iter()
+                    .map(|v| (Bound::Included(v.clone()), Bound::Included(v)))
+                    .collect();
+                (column, ranges)
+            }
+            _ => return None,
+        };
+        Some((column.to_owned(), ranges))
+    }
 
-            let values: Vec<CellValue> =
-                columns.iter().map(|f| row.read_column(f)).try_collect()?;
-            println!("{}", values.iter().map(|f| f.to_string()).join("|"));
+    /// Filters rows with the select's WHERE clause and reads its selections from the rows
+    /// that match as they're read. An aggregate reads all the rows before returning.
+    fn project<'a, R: ColumnReader>(
+        select: &SelectQuery,
+        rows: impl Iterator<Item = Result<R>> + 'a,
+    ) -> Result<Rows<'a>> {
+        let aggregate = select.selections.iter().find_map(|f| match f {
+            sql_engine::Selection::AggFn(a) => Some(a),
+            _ => None,
+        });
+        if aggregate.is_some() && select.selections.len() != 1 {
+            bail!(Unsupported, "can't mix agg and table values");
         }
-        Ok(())
+
+        if let Some(agg_fn) = aggregate {
+            let mut count = 0;
+            for row in rows {
+                if Connection::evalute_clause(&row?, &select.clause)? {
+                    count += 1;
+                }
+            }
+            let row = match agg_fn {
+                AggregateFunction::Count => Row {
+                    columns: Arc::new(HashMap::from([("count(*)".to_string(), 0)])),
+                    cells: vec![CellValue::Int(count)],
+                },
+            };
+            return Ok(Box::new(iter::once(Ok(row))));
+        }
+
+        let select = select.clone();
+        let mut columns = None;
+        let rows = rows.filter_map(move |row| {
+            let row = match row {
+                Ok(row) => row,
+                Err(e) => return Some(Err(e)),
+            };
+            match Connection::evalute_clause(&row, &select.clause) {
+                Ok(true) => {}
+                Ok(false) => return None,
+                Err(e) => return Some(Err(e)),
+            }
+
+            let (columns, names) = columns.get_or_insert_with(|| {
+                let names = select
+                    .selections
+                    .iter()
+                    .flat_map(|f| match f {
+                        sql_engine::Selection::Identifier(i) => vec![i.to_owned()],
+                        sql_engine::Selection::Wildcard => row.column_names(),
+                        sql_engine::Selection::AggFn(_) => vec![],
+                    })
+                    .collect_vec();
+                let columns: HashMap<String, usize> = names
+                    .iter()
+                    .enumerate()
+                    .map(|(i, name)| (name.to_owned(), i))
+                    .collect();
+                (Arc::new(columns), names)
+            });
+            let cells = names.iter().map(|f| row.read_column(f)).try_collect();
+            Some(cells.map(|cells| Row {
+                columns: columns.clone(),
+                cells,
+            }))
+        });
+        Ok(Box::new(rows))
     }
 
-    fn evalute_clause(row: &TableRow, expression: &Option<Expression>) -> Result<bool> {
+    fn evalute_clause(row: &impl ColumnReader, expression: &Option<Expression>) -> Result<bool> {
         match expression {
             Some(val) => match Connection::evalute_exp(row, val)? {
                 Object::Bool(b) => Ok(b),
-                _ => bail!("bool expceted as result from where clause"),
+                _ => bail!(TypeMismatch, "bool expceted as result from where clause"),
             },
-            None => Ok(true)
+            None => Ok(true),
         }
     }
 
-    fn evalute_exp(row: &TableRow, exp: &Expression) -> Result<Object> {
+    fn evalute_exp(row: &impl ColumnReader, exp: &Expression) -> Result<Object> {
         //i'm not dealing with precedence at all here,
         //this is just a hack to get where clauses mostly working for now
         match exp {
@@ -170,9 +704,13 @@ impl Connection {
                     (Object::Bool(l), Operator::And, Object::Bool(r)) => l && r,
                     (Object::Bool(l), Operator::Or, Object::Bool(r)) => l || r,
 
-                    (Object::String(l), Operator::Equal, Object::String(r)) => l == r,
-                    (Object::String(l), Operator::NotEqual, Object::String(r)) => l != r,
+                    (Object::Value(l), op, Object::Value(r)) => Connection::compare_values(
+                        (&l, Connection::operand_type(row, left).as_ref()),
+                        *op,
+                        (&r, Connection::operand_type(row, right).as_ref()),
+                    )?,
                     (l, ex, r) => bail!(
+                        TypeMismatch,
                         "invalid operation, cannot use {:?} with {:?} and {:?}",
                         ex,
                         l,
@@ -182,20 +720,108 @@ impl Connection {
 
                 Ok(Object::Bool(result))
             }
-            Expression::Literal(l) => Ok(Object::String(l.to_string())),
-            Expression::Identifier(i) => {
-                let value = row.read_column(i)?;
-                let val = match value {
-                    CellValue::Int(i) => i.to_string(),
-                    CellValue::Float(f) => f.to_string(),
-                    CellValue::Blob(_) => bail!("Can't use blob field in where clause"),
-                    CellValue::String(s) => s,
-                    CellValue::Null => "NULL".to_string(),
-                };
+            Expression::Between(value, low, high) => {
+                let value_type = Connection::operand_type(row, value);
+                let value = (&Connection::evalute_value(row, value)?, value_type.as_ref());
+                let low_type = Connection::operand_type(row, low);
+                let low = (&Connection::evalute_value(row, low)?, low_type.as_ref());
+                let high_type = Connection::operand_type(row, high);
+                let high = (&Connection::evalute_value(row, high)?, high_type.as_ref());
+                Ok(Object::Bool(
+                    Connection::compare_values(value, Operator::GreaterEqual, low)?
+                        && Connection::compare_values(value, Operator::LessEqual, high)?,
+                ))
+            }
+            Expression::InList(value, list) => {
+                let value_type = Connection::operand_type(row, value);
+                let value = (&Connection::evalute_value(row, value)?, value_type.as_ref());
+                // the values of the list count as having no affinity or collation
+                for item in list {
+                    let item = Connection::evalute_value(row, item)?;
+                    if Connection::compare_values(value, Operator::Equal, (&item, None))? {
+                        return Ok(Object::Bool(true));
+                    }
+                }
+                Ok(Object::Bool(false))
+            }
+            Expression::Literal(l) => Ok(Object::Value(l.clone())),
+            Expression::Identifier(i) => Ok(Object::Value(row.read_column(i)?)),
+        }
+    }
+
+    fn evalute_value(row: &impl ColumnReader, exp: &Expression) -> Result<CellValue> {
+        match Connection::evalute_exp(row, exp)? {
+            Object::Value(value) => Ok(value),
+            Object::Bool(b) => bail!(TypeMismatch, "expected a value but got {}", b),
+        }
+    }
 
-                Ok(Object::String(val))
+    /// The affinity and collation an operand brings to a comparison, only columns have any
+    fn operand_type(row: &impl ColumnReader, exp: &Expression) -> Option<ColumnType> {
+        match exp {
+            Expression::Identifier(i) => row.column_type(i),
+            _ => None,
+        }
+    }
+
+    // https://www.sqlite.org/datatype3.html#type_conversions_prior_to_comparison
+    //
+    /// Compares two values, each with the type of the column it was read from. A numeric
+    /// column turns the other operand into a number when it looks like one, otherwise a
+    /// TEXT column turns a literal into text. Text compares in the collation of the left
+    /// column, or the right one when only it is a column. Nothing matches NULL.
+    fn compare_values(
+        (left, left_type): (&CellValue, Option<&ColumnType>),
+        op: Operator,
+        (right, right_type): (&CellValue, Option<&ColumnType>),
+    ) -> Result<bool> {
+        if matches!(left, CellValue::Null) || matches!(right, CellValue::Null) {
+            return Ok(false);
+        }
+        let affinity = |t: Option<&ColumnType>| t.map(|t| t.affinity.clone());
+        let numeric = |a: &Option<TypeAffinity>| {
+            matches!(
+                a,
+                Some(TypeAffinity::Int | TypeAffinity::Real | TypeAffinity::Numeric)
+            )
+        };
+        let (left_affinity, right_affinity) = (affinity(left_type), affinity(right_type));
+        let (left, right) = match (&left_affinity, &right_affinity) {
+            (l, r) if numeric(l) && !numeric(r) => {
+                (left.clone(), TypeAffinity::Numeric.apply(right.clone()))
+            }
+            (l, r) if numeric(r) && !numeric(l) => {
+                (TypeAffinity::Numeric.apply(left.clone()), right.clone())
+            }
+            (Some(TypeAffinity::Text), None) => {
+                (left.clone(), TypeAffinity::Text.apply(right.clone()))
+            }
+            (None, Some(TypeAffinity::Text)) => {
+                (TypeAffinity::Text.apply(left.clone()), right.clone())
             }
+            _ => (left.clone(), right.clone()),
+        };
+
+        let collation = left_type
+            .or(right_type)
+            .and_then(|t| t.collation.as_deref());
+        if !known_collation(collation) {
+            bail!(
+                Unsupported,
+                "no such collation sequence: {}",
+                collation.unwrap_or_default()
+            );
         }
+        let ordering = left.collate_cmp(&right, collation);
+        Ok(match op {
+            Operator::Equal => ordering == Ordering::Equal,
+            Operator::NotEqual => ordering != Ordering::Equal,
+            Operator::Less => ordering == Ordering::Less,
+            Operator::LessEqual => ordering != Ordering::Greater,
+            Operator::Greater => ordering == Ordering::Greater,
+            Operator::GreaterEqual => ordering != Ordering::Less,
+            Operator::And | Operator::Or => bail!(TypeMismatch, "{:?} needs boolean operands", op),
+        })
     }
 
     pub fn query(&self, sql: impl AsRef<str>) -> Result<()> {
@@ -203,29 +829,35 @@ impl Connection {
 
         let exp = match (ast.pop(), ast.pop()) {
             (Some(s), None) => s,
-            _ => bail!("only a single expression is currently supported"),
+            _ => bail!(
+                Unsupported,
+                "only a single expression is currently supported"
+            ),
         };
         let mut select = match exp {
             sqlparser::ast::Statement::Query(q) => match *q.body {
                 sqlparser::ast::SetExpr::Select(select) => select,
-                e => bail!("{} queries are not currently supported", e),
+                e => bail!(Unsupported, "{} queries are not currently supported", e),
             },
-            q => bail!("{} queries are not currently supported", q),
+            q => bail!(Unsupported, "{} queries are not currently supported", q),
         };
 
         let source = match (select.from.pop(), select.from.pop()) {
             (Some(s), None) => s,
-            _ => bail!("only a single source is currenly supported"),
+            _ => bail!(Unsupported, "only a single source is currenly supported"),
         };
 
         let source_name = match source.relation {
             sqlparser::ast::TableFactor::Table { mut name, .. } => {
                 match (name.0.pop(), name.0.pop()) {
                     (Some(n), None) => n.value,
-                    _ => bail!("only a single expression is currently supported"),
+                    _ => bail!(
+                        Unsupported,
+                        "only a single expression is currently supported"
+                    ),
                 }
             }
-            _ => bail!("currently only table sources are supported"),
+            _ => bail!(Unsupported, "currently only table sources are supported"),
         };
 
         let tree = self.get_tree(&source_name)?;
@@ -238,7 +870,10 @@ impl Connection {
                     value, ..
                 })) => Ok(value.to_owned()),
 
-                _ => bail!("only field names are currently supported in selects"),
+                _ => bail!(
+                    Unsupported,
+                    "only field names are currently supported in selects"
+                ),
             })
             .try_collect()?;
         let where_clause = Connection::generate_clause(select.selection)?;
@@ -266,11 +901,14 @@ impl Connection {
                         Ok(match op {
                             BinaryOperator::Eq => left_value == right_value,
                             BinaryOperator::NotEq => left_value != right_value,
-                            _ => bail!("invalid conditoin operator"),
+                            _ => bail!(Unsupported, "invalid conditoin operator"),
                         })
                     })
                 }
-                _ => bail!("this type of where clause is not currently supported"),
+                _ => bail!(
+                    Unsupported,
+                    "this type of where clause is not currently supported"
+                ),
             },
             _ => Box::new(|_| Ok(true)),
         })
@@ -301,42 +939,224 @@ impl Connection {
         let tree = TableBTree::new(&self.db, schema.clone())?;
         let reader = tree.row_reader(&self.db);
         for r in reader {
-            let row = r.unwrap();
+            let row = r?;
             let value = row.read_column(&column_name)?;
             println!("{}", value);
         }
         Ok(())
     }
 
-    pub fn dump_page(&self, page_number: u32) {
-        let wow = self.db.read_table_page(page_number, None).unwrap();
+    pub fn dump_page(&self, page_number: u32) -> Result<()> {
+        let wow = self.db.read_table_page(page_number, None)?;
         match &wow {
             crate::sqlite::page::TablePage::Leaf(leaf) => {
                 for (page_number, offset) in &leaf.cell_pointers {
-                    let record = self.db.read_record(*page_number, *offset).unwrap();
+                    let record = self.db.read_record(*page_number, *offset)?;
                     println!("{:?}", record);
                 }
             }
             crate::sqlite::page::TablePage::Interior(_) => {}
         }
         println!("{:?}", wow);
+        Ok(())
     }
 }
 
 type SqlRowClause = Box<dyn Fn(&TableRow) -> Result<bool>>;
 
+const HEADER_MAGIC: &[u8; 16] = b"SQLite format 3\0";
+
+// https://www.sqlite.org/fileformat2.html#the_database_header
 #[derive(Debug)]
 pub struct DatabaseHeader {
-    pub page_size: u16,
+    pub page_size: u32,
+    pub write_version: u8,
+    pub read_version: u8,
+    pub reserved_bytes: u8,
+    pub file_change_counter: u32,
+    pub database_size: u32,
+    pub first_freelist_trunk_page: u32,
+    pub freelist_count: u32,
+    pub schema_cookie: u32,
+    pub schema_format: u32,
+    pub default_cache_size: u32,
+    pub largest_root_page: u32,
+    pub text_encoding: TextEncoding,
+    pub user_version: u32,
+    pub incremental_vacuum: bool,
+    pub application_id: u32,
+    pub version_valid_for: u32,
+    pub sqlite_version_number: u32,
 }
 
-#[derive(Debug)]
+impl DatabaseHeader {
+    pub fn parse(buffer: &[u8; 100]) -> Result<DatabaseHeader> {
+        let u32_at = |offset: usize| {
+            u32::from_be_bytes([
+                buffer[offset],
+                buffer[offset + 1],
+                buffer[offset + 2],
+                buffer[offset + 3],
+            ])
+        };
+
+        if &buffer[..16] != HEADER_MAGIC {
+            bail!(Corrupt, "file is not a database, invalid header string");
+        }
+
+        // a value of 1 represents a page size of 65536
+        let page_size = match u16::from_be_bytes([buffer[16], buffer[17]]) {
+            1 => 65536,
+            size => size as u32,
+        };
+        if !(512..=65536).contains(&page_size) || !page_size.is_power_of_two() {
+            bail!(Corrupt, "invalid page size {}", page_size);
+        }
+
+        let (write_version, read_version) = (buffer[18], buffer[19]);
+        if !(1..=2).contains(&write_version) {
+            bail!(
+                Unsupported,
+                "unsupported file format write version {}",
+                write_version
+            );
+        }
+        if !(1..=2).contains(&read_version) {
+            bail!(
+                Unsupported,
+                "unsupported file format read version {}",
+                read_version
+            );
+        }
+
+        let reserved_bytes = buffer[20];
+        if page_size - (reserved_bytes as u32) < 480 {
+            bail!(
+                Corrupt,
+                "{} reserved bytes leaves less than 480 usable bytes on a {} byte page",
+                reserved_bytes,
+                page_size
+            );
+        }
+
+        if buffer[21..24] != [64, 32, 32] {
+            bail!(
+                Corrupt,
+                "invalid payload fractions {}/{}/{}, must be 64/32/32",
+                buffer[21],
+                buffer[22],
+                buffer[23]
+            );
+        }
+
+        let schema_format = u32_at(44);
+        if schema_format > 4 {
+            bail!(
+                Unsupported,
+                "unsupported schema format number {}",
+                schema_format
+            );
+        }
+
+        Ok(DatabaseHeader {
+            page_size,
+            write_version,
+            read_version,
+            reserved_bytes,
+            file_change_counter: u32_at(24),
+            database_size: u32_at(28),
+            first_freelist_trunk_page: u32_at(32),
+            freelist_count: u32_at(36),
+            schema_cookie: u32_at(40),
+            schema_format,
+            default_cache_size: u32_at(48),
+            largest_root_page: u32_at(52),
+            text_encoding: u32_at(56).try_into()?,
+            user_version: u32_at(60),
+            incremental_vacuum: u32_at(64) != 0,
+            application_id: u32_at(68),
+            version_valid_for: u32_at(92),
+            sqlite_version_number: u32_at(96),
+        })
+    }
+
+    /// The page size minus the reserved space at the end of every page
+    pub fn usable_size(&self) -> u32 {
+        self.page_size - self.reserved_bytes as u32
+    }
+
+    /// The in-header database size is only trusted when it is non zero and was written
+    /// by a version of sqlite that also updated the version-valid-for number
+    pub fn database_size_is_valid(&self) -> bool {
+        self.database_size != 0 && self.file_change_counter == self.version_valid_for
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum TextEncoding {
     Utf8,
     Utf16le,
     Utf16be,
 }
 
+impl TryFrom<u32> for TextEncoding {
+    type Error = Error;
+
+    fn try_from(value: u32) -> Result<Self> {
+        Ok(match value {
+            1 => TextEncoding::Utf8,
+            2 => TextEncoding::Utf16le,
+            3 => TextEncoding::Utf16be,
+            e => bail!(Corrupt, "invalid text encoding {}", e),
+        })
+    }
+}
+
+impl TextEncoding {
+    pub fn decode(&self, data: &[u8]) -> Result<String> {
+        let units = |to_u16: fn([u8; 2]) -> u16| {
+            if !data.len().is_multiple_of(2) {
+                bail!(Corrupt, "utf-16 text must have an even number of bytes");
+            }
+            Ok(data
+                .chunks_exact(2)
+                .map(|c| to_u16([c[0], c[1]]))
+                .collect_vec())
+        };
+        Ok(match self {
+            TextEncoding::Utf8 => String::from_utf8(data.to_vec())?,
+            TextEncoding::Utf16le => String::from_utf16(&units(u16::from_le_bytes)?)?,
+            TextEncoding::Utf16be => String::from_utf16(&units(u16::from_be_bytes)?)?,
+        })
+    }
+
+    pub fn encode(&self, text: &str) -> Vec<u8> {
+        match self {
+            TextEncoding::Utf8 => text.as_bytes().to_vec(),
+            TextEncoding::Utf16le => text.encode_utf16().flat_map(u16::to_le_bytes).collect(),
+            TextEncoding::Utf16be => text.encode_utf16().flat_map(u16::to_be_bytes).collect(),
+        }
+    }
+
+    pub fn code(&self) -> u32 {
+        match self {
+            TextEncoding::Utf8 => 1,
+            TextEncoding::Utf16le => 2,
+            TextEncoding::Utf16be => 3,
+        }
+    }
+}
+
+impl fmt::Display for TextEncoding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TextEncoding::Utf8 => write!(f, "utf8"),
+            TextEncoding::Utf16le => write!(f, "utf16le"),
+            TextEncoding::Utf16be => write!(f, "utf16be"),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum SelectCell {
     NamedColumn(String),
@@ -0,0 +1,293 @@
+// Cursors walk a b-tree a page at a time. Only the pages on the path from the root to
+// the current cell are held in memory, a child page is read when the walk reaches it.
+
+use std::cmp::Ordering;
+
+use super::{
+    database::Database,
+    error::{bail, Result},
+    page::{IndexPage, TablePage},
+    record::{CellValue, Record},
+    schema::schema_parser::SortOrder,
+};
+
+/// How many pages deep a b-tree may be, sqlite's limit too. A walk that goes deeper has
+/// met a page pointing back at one above it.
+pub const MAX_DEPTH: usize = 20;
+
+/// Fails when a page is reached below `depth` others on the way down from the root, so
+/// a cycle among corrupt pages ends the walk instead of going on forever
+pub fn check_depth(depth: usize, page_number: u32) -> Result<()> {
+    if depth >= MAX_DEPTH {
+        bail!(
+            Corrupt,
+            "b-tree page {} is more than {} levels deep",
+            page_number,
+            MAX_DEPTH
+        );
+    }
+    Ok(())
+}
+
+/// Visits the cells of a table b-tree in rowid order
+pub struct TableCursor<'a> {
+    db: &'a Database,
+    /// The root page until the first step reads it
+    root: Option<u32>,
+    /// The pages from the root down to the current leaf, each with the position of the
+    /// next child or cell to visit
+    path: Vec<(TablePage, usize)>,
+}
+
+impl<'a> TableCursor<'a> {
+    pub fn new(db: &'a Database, root_page: u32) -> TableCursor<'a> {
+        TableCursor {
+            db,
+            root: Some(root_page),
+            path: Vec::new(),
+        }
+    }
+
+    fn descend(&mut self, page_number: u32) -> Result<()> {
+        check_depth(self.path.len(), page_number)?;
+        let page = self.db.read_table_page(page_number, None)?;
+        self.path.push((page, 0));
+        Ok(())
+    }
+}
+
+impl Iterator for TableCursor<'_> {
+    type Item = Result<(u32, u16)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(root) = self.root.take() {
+            if let Err(e) = self.descend(root) {
+                return Some(Err(e));
+            }
+        }
+        loop {
+            let (page, position) = self.path.last_mut()?;
+            let child = match page {
+                TablePage::Leaf(leaf) => {
+                    if let Some(cell) = leaf.cell_pointers.get(*position) {
+                        *position += 1;
+                        return Some(Ok(*cell));
+                    }
+                    None
+                }
+                // the right-most pointer is kept as the last of the interior cells
+                TablePage::Interior(interior) => interior
+                    .cells
+                    .get(*position)
+                    .map(|c| c.left_child_page_number),
+            };
+            *position += 1;
+            match child {
+                Some(child) => {
+                    if let Err(e) = self.descend(child) {
+                        self.path.clear();
+                        return Some(Err(e));
+                    }
+                }
+                None => {
+                    self.path.pop();
+                }
+            }
+        }
+    }
+}
+
+/// Visits the entries of an index b-tree in key order, or in reverse. Interior cells are
+/// entries too, each one sorts between the entries of its left child and those of the
+/// next child.
+pub struct IndexCursor<'a> {
+    db: &'a Database,
+    root: Option<u32>,
+    /// On a leaf the positions are the cells. On an interior page with n cells there are
+    /// 2n+1 positions, the even ones are children and the odd ones the cells. Walking
+    /// forward a position is the next one to visit, walking backward the one after it.
+    path: Vec<(IndexPage, usize)>,
+    reverse: bool,
+}
+
+impl<'a> IndexCursor<'a> {
+    /// A cursor starting at the first entry
+    pub fn new(db: &'a Database, root_page: u32) -> IndexCursor<'a> {
+        IndexCursor {
+            db,
+            root: Some(root_page),
+            path: Vec::new(),
+            reverse: false,
+        }
+    }
+
+    /// A cursor starting at the first entry whose leading values don't sort before `key`,
+    /// each key column compared in its declared sort order and collation
+    pub fn seek(
+        db: &'a Database,
+        root_page: u32,
+        key: &[CellValue],
+        orders: &[KeyOrder],
+    ) -> Result<IndexCursor<'a>> {
+        IndexCursor::seek_by(db, root_page, SortOrder::Asc, |record| {
+            Ok(compare(db, record, key, orders)? == Ordering::Less)
+        })
+    }
+
+    /// A cursor at the boundary of the entries `before` holds for, which have to be the
+    /// first ones in key order. Walking forward it starts right after them, walking
+    /// backward at the last of them.
+    pub fn seek_by(
+        db: &'a Database,
+        root_page: u32,
+        direction: SortOrder,
+        mut before: impl FnMut(&Record) -> Result<bool>,
+    ) -> Result<IndexCursor<'a>> {
+        let reverse = direction == SortOrder::Desc;
+        let mut path = Vec::new();
+        let mut page_number = root_page;
+        loop {
+            check_depth(path.len(), page_number)?;
+            let page = db.read_index_page(page_number, None)?;
+            match &page {
+                IndexPage::Leaf(leaf) => {
+                    let cells = &leaf.cell_pointers;
+                    let position = partition_point(cells.len(), |i| {
+                        before(&db.read_index_record(cells[i].0, cells[i].1)?)
+                    })?;
+                    path.push((page, position));
+                    break;
+                }
+                IndexPage::Interior(interior) => {
+                    // the boundary lies in the child left of the first cell `before`
+                    // doesn't hold for, that cell comes next walking forward and the one
+                    // before it walking backward
+                    let cells = &interior.cells;
+                    let index = partition_point(cells.len(), |i| {
+                        before(
+                            &db.read_index_record(cells[i].page_number, cells[i].record_pointer)?,
+                        )
+                    })?;
+                    page_number = cells
+                        .get(index)
+                        .map_or(interior.right_cell, |c| c.left_child_page_number);
+                    path.push((page, 2 * index + usize::from(!reverse)));
+                }
+            }
+        }
+        Ok(IndexCursor {
+            db,
+            root: None,
+            path,
+            reverse,
+        })
+    }
+
+    fn descend(&mut self, page_number: u32) -> Result<()> {
+        check_depth(self.path.len(), page_number)?;
+        let page = self.db.read_index_page(page_number, None)?;
+        let position = match (&page, self.reverse) {
+            (_, false) => 0,
+            (IndexPage::Leaf(leaf), true) => leaf.cell_pointers.len(),
+            (IndexPage::Interior(interior), true) => 2 * interior.cells.len() + 1,
+        };
+        self.path.push((page, position));
+        Ok(())
+    }
+}
+
+impl Iterator for IndexCursor<'_> {
+    type Item = Result<(u32, u16)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(root) = self.root.take() {
+            if let Err(e) = self.descend(root) {
+                return Some(Err(e));
+            }
+        }
+        loop {
+            let (page, position) = self.path.last_mut()?;
+            let slot = match self.reverse {
+                false => {
+                    *position += 1;
+                    Some(*position - 1)
+                }
+                true => position.checked_sub(1).inspect(|slot| *position = *slot),
+            };
+            let child = match (page, slot) {
+                (_, None) => None,
+                (IndexPage::Leaf(leaf), Some(slot)) => match leaf.cell_pointers.get(slot) {
+                    Some(cell) => return Some(Ok(*cell)),
+                    None => None,
+                },
+                (IndexPage::Interior(interior), Some(slot)) => {
+                    let index = slot / 2;
+                    match (slot % 2, interior.cells.get(index)) {
+                        (1, Some(cell)) => {
+                            return Some(Ok((cell.page_number, cell.record_pointer)));
+                        }
+                        (0, Some(cell)) => Some(cell.left_child_page_number),
+                        (0, None) if index == interior.cells.len() => Some(interior.right_cell),
+                        _ => None,
+                    }
+                }
+            };
+            match child {
+                Some(child) => {
+                    if let Err(e) = self.descend(child) {
+                        self.path.clear();
+                        return Some(Err(e));
+                    }
+                }
+                None => {
+                    self.path.pop();
+                }
+            }
+        }
+    }
+}
+
+/// The sort order and collation of a key column, None for BINARY
+pub type KeyOrder = (SortOrder, Option<String>);
+
+/// Compares the leading values of an index record with a key, each column in its
+/// declared sort order and collation
+pub fn compare(
+    db: &Database,
+    record: &Record,
+    key: &[CellValue],
+    orders: &[KeyOrder],
+) -> Result<Ordering> {
+    for (i, value) in key.iter().enumerate() {
+        let (order, collation) = match orders.get(i) {
+            Some((order, collation)) => (*order, collation.as_deref()),
+            None => (SortOrder::Asc, None),
+        };
+        let stored = db.read_record_cell(record, i)?;
+        let ordering = stored.collate_cmp(value, collation);
+        let ordering = match order {
+            SortOrder::Desc => ordering.reverse(),
+            SortOrder::Asc => ordering,
+        };
+        if ordering != Ordering::Equal {
+            return Ok(ordering);
+        }
+    }
+    Ok(Ordering::Equal)
+}
+
+/// The index of the first of `len` items for which `before` is false, where every item
+/// it holds for comes first. Binary search like `slice::partition_point`, for items
+/// that have to be read to be compared.
+pub fn partition_point(len: usize, mut before: impl FnMut(usize) -> Result<bool>) -> Result<usize> {
+    let (mut low, mut high) = (0, len);
+    while low < high {
+        let middle = low + (high - low) / 2;
+        if before(middle)? {
+            low = middle + 1;
+        } else {
+            high = middle;
+        }
+    }
+    Ok(low)
+}
@@ -1,24 +1,19 @@
-use anyhow::{bail, Context, Result};
 use itertools::Itertools;
 
-use sqlparser::{
-    ast,
-    dialect::SQLiteDialect,
-    parser::{Parser, ParserError},
-};
 use std::{
-    cell::RefCell,
     collections::HashSet,
-    fs::File,
-    io::{Read, Seek, SeekFrom},
-    rc::Rc,
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
 };
 
 use crate::sqlite::page::{page_header::PageHeader, table_leaf::TableLeafPage};
 
 use super::{
-    column::Column,
+    btree::TableBTree,
     connection::DatabaseHeader,
+    error::{bail, error, Context, Error, Result},
+    freelist::Freelist,
+    journal::Journal,
+    lock::LockLevel,
     page::{
         index_interior::IndexInteriorPage,
         index_leaf::IndexLeafPage,
@@ -26,160 +21,474 @@ use super::{
         table_interior::{TableInteriorCell, TableInteriorPage},
         IndexPage, Page, TablePage,
     },
-    record::{CellType, CellValue, Record, RecordHeader},
-    schema::{index_schema::IndexSchema, table_schema::TableSchema, SqliteSchema},
+    page_cache::{CacheStats, PageCache, DEFAULT_CACHE_SIZE},
+    record::{codec, CellType, CellValue, Record, RecordHeader},
+    schema::{
+        index_schema::IndexSchema, table_schema::TableSchema, trigger_schema::TriggerSchema,
+        view_schema::ViewSchema, SqliteSchema,
+    },
+    storage::{FileStorage, MmapStorage, Storage},
+    transaction::Transaction,
+    wal::Wal,
 };
 
 pub struct Database {
     pub header: DatabaseHeader,
-    file: RefCell<File>,
-    schema: Vec<Rc<SqliteSchema>>,
+    storage: Box<dyn Storage>,
+    /// Committed pages that haven't been checkpointed into the database file yet
+    wal: Option<Wal>,
+    /// The pages of a hot journal served in place of the database file when it wasn't
+    /// rolled back on open
+    journal: Option<Journal>,
+    cache: Mutex<PageCache>,
+    schema: Vec<Arc<SqliteSchema>>,
+    read_only: bool,
+    /// Whether a hot journal found when a transaction begins is rolled back
+    rollback_journal: bool,
+    /// Where transactions write their rollback journal, None for a database that doesn't
+    /// come from a file
+    journal_path: Option<String>,
 }
 
-static DIALECT: SQLiteDialect = SQLiteDialect {};
+#[derive(Debug, Clone)]
+pub struct OpenOptions {
+    /// Never write to the database file
+    pub read_only: bool,
+    /// The number of pages kept in memory
+    pub cache_size: usize,
+    /// Map the database file into memory instead of reading it with system calls. Off by
+    /// default: another process truncating the file while it's mapped crashes this one
+    /// with SIGBUS, see [`MmapStorage`].
+    pub mmap: bool,
+    /// Play a hot journal back into the database file and delete it, like sqlite does
+    /// when it opens the database. On by default. When it's off or the file can't be
+    /// written to, the journal's pages are read in place of the file's and the file is
+    /// left alone, which also keeps the database read only.
+    pub rollback_journal: bool,
+}
+
+impl Default for OpenOptions {
+    fn default() -> Self {
+        OpenOptions {
+            read_only: false,
+            cache_size: DEFAULT_CACHE_SIZE,
+            mmap: false,
+            rollback_journal: true,
+        }
+    }
+}
 
 impl Database {
     pub fn new(file_path: impl Into<String>) -> Result<Database> {
-        let mut file = File::open(file_path.into())?;
+        Database::with_options(file_path, OpenOptions::default())
+    }
+
+    pub fn with_options(file_path: impl Into<String>, options: OpenOptions) -> Result<Database> {
+        let file_path = file_path.into();
+        let storage: Box<dyn Storage> = match options.mmap {
+            true => Box::new(MmapStorage::open(&file_path)?),
+            false => Box::new(FileStorage::open(&file_path, !options.read_only)?),
+        };
+
+        // a journal left behind by a writer that crashed mid transaction means the
+        // database file is half written, the journal holds the original pages. SHARED
+        // keeps a writer from starting while the journal is looked at.
+        storage.lock(LockLevel::Shared)?;
+        let journal_path = format!("{}-journal", file_path);
+        let journal = match Journal::open(&journal_path)? {
+            Some(journal) if journal.is_hot(storage.as_ref())? => Some(journal),
+            _ => None,
+        };
+        let journal = match journal {
+            Some(journal) if options.rollback_journal && storage.writable() => {
+                storage.lock(LockLevel::Exclusive)?;
+                journal.rollback(storage.as_ref(), &journal_path)?;
+                storage.lock(LockLevel::Shared)?;
+                None
+            }
+            journal => journal,
+        };
+
+        let wal_path = format!("{}-wal", file_path);
+        let mut db = Database::open(storage, journal, Some(&wal_path), options)?;
+        db.storage.lock(LockLevel::None)?;
+        db.journal_path = Some(journal_path);
+        Ok(db)
+    }
+
+    /// Opens a database whose bytes come from the storage alone, with no journal or WAL
+    /// file next to it
+    pub fn from_storage(storage: Box<dyn Storage>, options: OpenOptions) -> Result<Database> {
+        Database::open(storage, None, None, options)
+    }
+
+    fn open(
+        storage: Box<dyn Storage>,
+        journal: Option<Journal>,
+        wal_path: Option<&str>,
+        options: OpenOptions,
+    ) -> Result<Database> {
         let mut buffer = [0; 100];
-        file.read_exact(&mut buffer)?;
-        let header = DatabaseHeader {
-            page_size: u16::from_be_bytes([buffer[16], buffer[17]]),
+        let header = match &journal {
+            Some(journal) => {
+                match journal.page(1) {
+                    Some(page) => buffer.copy_from_slice(&page[..100]),
+                    None => storage.read_at(0, &mut buffer)?,
+                }
+                let mut header = DatabaseHeader::parse(&buffer)?;
+                header.database_size = journal.initial_size;
+                header
+            }
+            None => Database::read_header(storage.as_ref())?,
         };
+
+        // a read version of 2 means the database is in WAL mode
+        let wal = match (header.read_version, wal_path) {
+            (2, Some(wal_path)) => Wal::open(wal_path, header.page_size)?,
+            _ => None,
+        };
+
         let mut db = Database {
-            file: file.into(),
+            storage,
             header,
+            wal,
+            journal,
+            cache: Mutex::new(PageCache::new(options.cache_size)),
             schema: Vec::new(),
+            read_only: options.read_only,
+            rollback_journal: options.rollback_journal,
+            journal_path: None,
         };
+
+        // the WAL can hold a newer page 1 and with it a newer header
+        if let Some(wal) = &db.wal {
+            let database_size = wal.database_size;
+            buffer.copy_from_slice(&db.read_page(1)?[..100]);
+            db.header = DatabaseHeader::parse(&buffer)?;
+            db.header.database_size = database_size;
+        }
+
         let schema = db.read_schemas()?;
-        db.schema = schema.into_iter().map(Rc::new).collect_vec();
+        db.schema = schema.into_iter().map(Arc::new).collect_vec();
 
         Ok(db)
     }
 
-    fn read_record_header(&self, pos: Position) -> Result<RecordHeader> {
-        self.seek_position(pos)?;
-        let Varint { mut value, size } = self.read_varint()?;
-        let header_size = value;
-        value -= size as i64;
-        let mut headers: Vec<CellType> = Vec::new();
-        while value > 0 {
-            let varint = self.read_varint()?;
-            value -= varint.size as i64;
-            headers.push(match varint.value {
-                0 => CellType::Null,
-                1 => CellType::Varint(1),
-                2 => CellType::Varint(2),
-                3 => CellType::Varint(3),
-                4 => CellType::Varint(4),
-                5 => CellType::Varint(6),
-                6 => CellType::Varint(8),
-                7 => CellType::Float64,
-                8 => CellType::Varint(1),
-                9 => CellType::Varint(1),
-                10 | 11 => CellType::Null,
-                code => {
-                    if code % 2 == 0 {
-                        CellType::Blob(((code - 12) / 2) as isize)
-                    } else {
-                        CellType::String(((code - 12) / 2) as isize)
-                    }
+    /// The header at the start of the database file, with the database size worked out
+    /// from the file when the header's can't be trusted
+    fn read_header(storage: &dyn Storage) -> Result<DatabaseHeader> {
+        let mut buffer = [0; 100];
+        storage
+            .read_at(0, &mut buffer)
+            .context("file is too small to contain a database header")?;
+        let mut header = DatabaseHeader::parse(&buffer)?;
+        let file_pages = storage.size()?.div_ceil(header.page_size as u64);
+        if !header.database_size_is_valid() {
+            header.database_size = (storage.size()? / header.page_size as u64) as u32;
+        } else if header.database_size as u64 > file_pages {
+            // like sqlite a header claiming pages the file doesn't have is corrupt
+            bail!(
+                Corrupt,
+                "header gives a database size of {} pages but the file holds {}",
+                header.database_size,
+                file_pages
+            );
+        }
+        Ok(header)
+    }
+
+    /// Starts a transaction, failing unless this database can be written to. Like sqlite
+    /// it holds a RESERVED lock on the file until it's committed or rolled back, so other
+    /// processes can still read but not start writing.
+    pub fn begin(&mut self) -> Result<Transaction> {
+        if self.journal.is_some() {
+            bail!(
+                Unsupported,
+                "the database has a hot journal, open it with rollback_journal to write to it"
+            );
+        }
+        if self.read_only || !self.storage.writable() {
+            bail!(Unsupported, "the database is read only");
+        }
+        self.storage.lock(LockLevel::Reserved)?;
+        let ready = self
+            .recover_journal()
+            .and_then(|_| self.refresh())
+            .and_then(|_| self.check_writable());
+        match ready {
+            Ok(()) => Ok(Transaction::new(self.header.database_size)),
+            Err(e) => {
+                self.storage.lock(LockLevel::None)?;
+                Err(e)
+            }
+        }
+    }
+
+    /// Looks for a journal again once RESERVED keeps other writers out, a writer may have
+    /// died mid transaction since the database was opened. A hot journal is rolled back
+    /// like on open, what's left of a committed transaction is deleted so the next
+    /// journal can be created in its place.
+    fn recover_journal(&mut self) -> Result<()> {
+        let Some(journal_path) = &self.journal_path else {
+            return Ok(());
+        };
+        match Journal::open(journal_path)? {
+            Some(journal) if journal.is_hot(self.storage.as_ref())? => {
+                if !self.rollback_journal {
+                    bail!(
+                        Unsupported,
+                        "the database has a hot journal, open it with rollback_journal to write to it"
+                    );
                 }
-            });
+                self.storage.lock(LockLevel::Exclusive)?;
+                journal.rollback(self.storage.as_ref(), journal_path)?;
+                self.storage.lock(LockLevel::Reserved)?;
+                // the pages read before may be the ones the dead writer half wrote
+                let header = Database::read_header(self.storage.as_ref())?;
+                self.reload(header)
+            }
+            _ => Journal::discard(journal_path),
+        }
+    }
+
+    /// Rereads the header and drops everything read before when another connection
+    /// changed the file since, which its change counter tells
+    fn refresh(&mut self) -> Result<()> {
+        let header = Database::read_header(self.storage.as_ref())?;
+        if header.file_change_counter == self.header.file_change_counter {
+            return Ok(());
         }
-        Ok(RecordHeader::new(headers, header_size))
+        self.reload(header)
+    }
+
+    fn reload(&mut self, header: DatabaseHeader) -> Result<()> {
+        self.header = header;
+        let capacity = self.cache_size();
+        *self.cache() = PageCache::new(capacity);
+        let schema = self.read_schemas()?;
+        self.schema = schema.into_iter().map(Arc::new).collect_vec();
+        Ok(())
+    }
+
+    fn check_writable(&self) -> Result<()> {
+        if self.wal.is_some() || self.header.write_version == 2 {
+            bail!(
+                Unsupported,
+                "writing to a database in WAL mode is not supported"
+            );
+        }
+        // auto-vacuum databases keep pointer map pages that would have to be updated too
+        if self.header.largest_root_page != 0 {
+            bail!(
+                Unsupported,
+                "writing to an auto-vacuum database is not supported"
+            );
+        }
+        Ok(())
+    }
+
+    /// Drops the changes of a transaction and releases its lock
+    pub fn rollback(&self, transaction: Transaction) -> Result<()> {
+        drop(transaction);
+        self.storage.lock(LockLevel::None)
+    }
+
+    /// Writes the pages a transaction changed to the database file. The original pages
+    /// go to a rollback journal first so a crash part way through can be undone. The
+    /// file is written under an EXCLUSIVE lock, which fails while others are reading it.
+    pub fn commit(&mut self, transaction: Transaction) -> Result<()> {
+        let committed = self.write_transaction(transaction);
+        self.storage.lock(LockLevel::None)?;
+        committed
+    }
+
+    fn write_transaction(&mut self, mut transaction: Transaction) -> Result<()> {
+        if transaction.is_empty() {
+            return Ok(());
+        }
+        self.storage.lock(LockLevel::Exclusive)?;
+        let initial_size = self.header.database_size;
+        let change_counter = self.header.file_change_counter.wrapping_add(1);
+        let database_size = transaction.database_size;
+        let header = transaction.page_mut(self, 1)?;
+        header[24..28].copy_from_slice(&change_counter.to_be_bytes());
+        header[28..32].copy_from_slice(&database_size.to_be_bytes());
+        // the in-header database size is only trusted alongside a matching counter here
+        header[92..96].copy_from_slice(&change_counter.to_be_bytes());
+
+        if let Some(journal_path) = &self.journal_path {
+            let originals = transaction
+                .pages()
+                .filter(|(page_number, _)| *page_number <= initial_size)
+                .map(|(page_number, _)| Ok((page_number, self.read_page(page_number)?)))
+                .collect::<Result<Vec<_>>>()?;
+            let originals = originals
+                .iter()
+                .map(|(page_number, page)| (*page_number, page.as_ref()))
+                .collect_vec();
+            Journal::write(
+                journal_path,
+                self.header.page_size,
+                initial_size,
+                &originals,
+            )?;
+        }
+        let page_size = self.header.page_size as u64;
+        for (page_number, page) in transaction.pages() {
+            self.storage
+                .write_at((page_number as u64 - 1) * page_size, page)
+                .with_context(|| format!("failed to write page {}", page_number))?;
+        }
+        self.storage.sync()?;
+        if let Some(journal_path) = &self.journal_path {
+            Journal::commit(journal_path)?;
+        }
+
+        let mut buffer = [0; 100];
+        buffer.copy_from_slice(&transaction.page(self, 1)?[..100]);
+        self.header = DatabaseHeader::parse(&buffer)?;
+        let mut cache = self.cache();
+        for (page_number, page) in transaction.into_pages() {
+            cache.insert(page_number, page.into());
+        }
+        Ok(())
+    }
+
+    fn read_record_header(&self, payload: &[u8]) -> Result<RecordHeader> {
+        codec::decode_header(payload)
     }
 
     pub fn read_cell_row_id(&self, page_number: u32, pointer: u16) -> Result<i64> {
-        self.seek_position(Position::new(page_number, pointer))?;
-        self.read_varint()?; //payload size;
-        let row_id = self.read_varint()?;
+        let mut reader = self.reader(page_number, pointer)?;
+        reader.read_varint()?; //payload size;
+        let row_id = reader.read_varint()?;
         Ok(row_id.value)
     }
     pub fn read_index_record(&self, page_number: u32, pointer: u16) -> Result<Record> {
-        self.seek_position(Position::new(page_number, pointer))?;
-        let payload_size = self.read_varint()?;
-        let cell_header_size = payload_size.size; 
-        let record_header = self.read_record_header(Position::Relative)?;
-        Ok(Record::new(
-            0,
-            record_header,
-            page_number,
-            pointer,
-            cell_header_size as i64,
-        ))
+        let mut reader = self.reader(page_number, pointer)?;
+        let payload_size = reader.read_varint()?;
+        let payload = self.read_payload(&mut reader, payload_size.value, PayloadKind::Index)?;
+        let record_header = self.read_record_header(&payload)?;
+        Ok(Record::new(0, record_header, page_number, pointer, payload))
     }
 
     pub fn read_record(&self, page_number: u32, pointer: u16) -> Result<Record> {
-        self.seek_position(Position::new(page_number, pointer))?;
-        let payload_size = self.read_varint()?;
-        let row_id = self.read_varint()?;
-        let cell_header_size = payload_size.size + row_id.size;
-        let record_header = self.read_record_header(Position::Relative)?;
+        let mut reader = self.reader(page_number, pointer)?;
+        let payload_size = reader.read_varint()?;
+        let row_id = reader.read_varint()?;
+        let payload = self.read_payload(&mut reader, payload_size.value, PayloadKind::Table)?;
+        let record_header = self.read_record_header(&payload)?;
         Ok(Record::new(
             row_id.value,
             record_header,
             page_number,
             pointer,
-            cell_header_size as i64,
+            payload,
         ))
     }
 
-    fn get_location(&self, page_number: u32, offset: u16) -> Result<i64> {
-        if page_number == 0 {
-            bail!("pages start at index 1");
+    // https://www.sqlite.org/fileformat2.html#cellformat
+    //
+    // U is the usable size of a page, P the payload size.
+    // X is the largest payload that is stored entirely on the b-tree page,
+    // M the smallest amount that must be stored on the page once it spills.
+    // If P > X then K = M+((P-M)%(U-4)), K bytes are kept local if K <= X otherwise M bytes are.
+    pub fn local_payload_size(&self, payload_size: usize, kind: PayloadKind) -> usize {
+        let usable = self.header.usable_size() as usize;
+        let max_local = match kind {
+            PayloadKind::Table => usable - 35,
+            PayloadKind::Index => ((usable - 12) * 64 / 255) - 23,
+        };
+        if payload_size <= max_local {
+            return payload_size;
         }
-        if offset > self.header.page_size {
-            bail!("page offset can't be larger than page size");
+        let min_local = ((usable - 12) * 32 / 255) - 23;
+        let local = min_local + ((payload_size - min_local) % (usable - 4));
+        if local <= max_local {
+            local
+        } else {
+            min_local
         }
+    }
 
-        let page_start = match page_number {
-            1 => 0, 
-            num => (num - 1) * self.header.page_size as u32,
-        };
-        Ok((page_start + offset as u32) as i64)
+    /// Reads a cell payload starting at the reader's position, following the overflow
+    /// page chain when the payload does not fit on the b-tree page
+    fn read_payload(
+        &self,
+        reader: &mut PageReader,
+        payload_size: i64,
+        kind: PayloadKind,
+    ) -> Result<Vec<u8>> {
+        if payload_size < 0 {
+            bail!(Corrupt, "invalid payload size {}", payload_size);
+        }
+        let payload_size = payload_size as usize;
+        let local_size = self.local_payload_size(payload_size, kind);
+        let mut payload = vec![0u8; payload_size];
+        reader.read_exact(&mut payload[..local_size])?;
+        if local_size == payload_size {
+            return Ok(payload);
+        }
+
+        // every overflow page starts with the next page number followed by U-4 bytes of content
+        let overflow_size = self.header.usable_size() as usize - 4;
+        let mut next_page = reader.read_u32()?;
+        let mut read = local_size;
+        let mut visited = HashSet::new();
+        while read < payload_size {
+            if next_page == 0 {
+                bail!(
+                    Corrupt,
+                    "overflow chain ended after {} of {} bytes",
+                    read,
+                    payload_size
+                );
+            }
+            if !visited.insert(next_page) {
+                bail!(Corrupt, "overflow chain loops back to page {}", next_page);
+            }
+            let mut overflow = self.reader(next_page, 0)?;
+            let page = next_page;
+            next_page = overflow.read_u32()?;
+            let len = overflow_size.min(payload_size - read);
+            overflow
+                .read_exact(&mut payload[read..read + len])
+                .with_context(|| format!("failed to read overflow page {}", page))?;
+            read += len;
+        }
+        Ok(payload)
     }
 
     pub fn read_table_page(&self, page_number: u32, row_id: Option<i64>) -> Result<TablePage> {
         match self.read_page_raw(page_number, None, row_id)? {
             Page::Table(t) => Ok(t),
-            Page::Index(_) => bail!("Expectting table page but got index"),
+            Page::Index(_) => bail!(Corrupt, "Expectting table page but got index"),
         }
     }
 
     pub fn read_index_page(&self, page_number: u32, value: Option<CellValue>) -> Result<IndexPage> {
         match self.read_page_raw(page_number, value, None)? {
-            Page::Table(_) => bail!("Expectting table page but got index"),
+            Page::Table(_) => bail!(Corrupt, "Expectting table page but got index"),
             Page::Index(i) => Ok(i),
         }
     }
-    pub fn read_table_interior_cell(
-        &self,
-        _page_number: u32,
-        _cell_pointer: i64,
-    ) -> Result<TableInteriorCell> {
-        todo!()
-    }
     fn read_page_raw(
         &self,
         page_number: u32,
         first_key: Option<CellValue>,
         row_id: Option<i64>,
     ) -> Result<Page> {
-        let mut buffer = [0; 1];
-
         let offset = match page_number {
             1 => 100,
             _ => 0,
         };
-        self.seek(page_number, offset)?;
-        self.read_exact(&mut buffer)?;
-        let page_type = match buffer[0] {
+        let mut reader = self.reader(page_number, offset)?;
+        let page_type = match reader.read_u8()? {
             0x02 => PageType::IndexInterior,
             0x05 => PageType::TableInterior,
             0x0a => PageType::IndexLeaf,
             0x0d => PageType::TableLeaf,
-            _ => bail!("invalid page type "),
+            _ => bail!(Corrupt, "invalid page type "),
         };
 
         // Offset	Size	Description
@@ -191,31 +500,24 @@ impl Database {
         // 8	4	The four-byte page number at offset 8 is the right-most pointer. This value appears in the header of interior b-tree pages only and is omitted from all other pages.
         //
         //
-        self.seek(page_number, offset + 1)?;
-        let free_block = self.read_u16()?;
-        self.seek(page_number, offset + 3)?;
-        let cell_count = self.read_u16()?;
-        self.seek(page_number, offset + 5)?;
-        let cell_content_area_offset = self.read_u16()?;
-
-        let mut buffer = [0; 1];
-        self.read_exact(&mut buffer)?;
-        let fragmented_free_bytes = u8::from_be_bytes(buffer);
+        let free_block = reader.read_u16()?;
+        let cell_count = reader.read_u16()?;
+        let cell_content_area_offset = reader.read_u16()?;
+        let fragmented_free_bytes = reader.read_u8()?;
 
         let right_cell = match page_type {
-            PageType::IndexInterior | PageType::TableInterior => self.read_u32()?,
+            PageType::IndexInterior | PageType::TableInterior => reader.read_u32()?,
             _ => 0,
         };
 
         let mut cell_array: Vec<u8> = vec![0; cell_count as usize * 2];
 
-        self.read_exact(cell_array.as_mut_slice())?;
+        reader.read_exact(cell_array.as_mut_slice())?;
 
         let cell_pointers: Vec<(u32, u16)> = cell_array
             .chunks(2)
             .map(|f| (page_number, u16::from_be_bytes([f[0], f[1]])))
             .collect();
-        assert_eq!(cell_pointers.len(), cell_count as usize);
 
         let header = PageHeader {
             page_type: page_type.clone(),
@@ -228,17 +530,26 @@ impl Database {
         Ok(match &page_type {
             PageType::IndexInterior => {
                 let cells = IndexInteriorPage::read_cells(self, cell_pointers)?;
+                let Some(last) = cells.last() else {
+                    bail!(Corrupt, "interior page {} has no cells", page_number);
+                };
                 Page::Index(IndexPage::Interior(IndexInteriorPage {
                     header,
                     page_number,
-                    value: cells.last().unwrap().value.clone(),
+                    value: last.value.clone(),
                     cells,
                     right_cell,
                 }))
             }
             PageType::TableInterior => {
                 let mut cells = TableInteriorPage::read_cells(self, &cell_pointers)?;
-                let row_id = cells.last().unwrap().row_id;
+                // sqlite leaves page 1 as an interior page with only a right child when
+                // the cells of its child don't fit below the database header
+                let row_id = match cells.last() {
+                    Some(cell) => cell.row_id,
+                    None if page_number == 1 => 0,
+                    None => bail!(Corrupt, "interior page {} has no cells", page_number),
+                };
 
                 cells.push(TableInteriorCell {
                     row_id: 0,
@@ -257,7 +568,7 @@ impl Database {
                 header,
                 page_number,
                 cell_pointers,
-                value: first_key.unwrap(),
+                value: first_key,
             })),
             PageType::TableLeaf => Page::Table(TablePage::Leaf(TableLeafPage {
                 header,
@@ -268,85 +579,61 @@ impl Database {
         })
     }
 
-    pub fn read_raw_cell(&self, cell_type: &CellType) -> Result<CellValue> {
-        return Ok(match &cell_type {
-            CellType::Null => CellValue::Null,
-            CellType::Varint(size) => {
-                let mut buff = [0; 8];
-                let size = *size as usize;
-                if size == 0 {
-                    CellValue::Int(0)
-                } else {
-                    self.read_exact(&mut buff[8 - size..8])?;
-                    CellValue::Int(i64::from_be_bytes(buff))
-                }
-            }
-            CellType::Float64 => {
-                let mut buff = [0; 8];
-                self.file.borrow_mut().read_exact(&mut buff).unwrap();
-                CellValue::Float(f64::from_be_bytes(buff))
-            }
-            CellType::Blob(len) => {
-                let mut data = vec![0u8; *len as usize];
-                self.file.borrow_mut().read_exact(&mut data).unwrap();
-                CellValue::Blob(data)
-            }
-            CellType::String(len) => {
-                let mut data = vec![0u8; *len as usize];
-                self.file.borrow_mut().read_exact(&mut data).unwrap();
-                CellValue::String(String::from_utf8(data)?)
-            }
-        });
+    pub fn read_raw_cell(&self, cell_type: &CellType, data: &[u8]) -> Result<CellValue> {
+        codec::decode_value(cell_type, data, self.header.text_encoding)
     }
 
     pub fn read_record_cell(&self, record: &Record, index: usize) -> Result<CellValue> {
-        self.seek_position(record.get_cell_position(index))?;
-        self.read_raw_cell(&record.record_header.headers[index])
-    }
-
-    pub fn read_varint(&self) -> Result<Varint> {
-        let mut buf = [0; 1];
-        let mut more = true;
-        let mut value: i64 = 0;
-        let mut size = 0;
-        while more {
-            self.read_exact(&mut buf)?;
-            size += 1;
-            let byte = buf[0];
-            more = byte & 0b1000_0000 != 0;
-            value <<= 7;
-            value |= i64::from(0b0111_1111 & byte);
-        }
-        Ok(Varint { value, size })
+        let Some(cell_type) = record.record_header.headers.get(index) else {
+            bail!(
+                Corrupt,
+                "record only has {} cells",
+                record.record_header.headers.len()
+            );
+        };
+        let offset = record.get_cell_position(index);
+        let Some(data) = record.payload().get(offset..) else {
+            bail!(
+                Corrupt,
+                "cell {} starts past the end of the record payload",
+                index
+            );
+        };
+        self.read_raw_cell(cell_type, data)
     }
 
     pub fn get_table_indexes(&self, table_name: impl AsRef<str>) -> HashSet<String> {
+        // index entries of WITHOUT ROWID tables end with the primary key instead of a rowid
+        let without_rowid = matches!(
+            self.get_table_schema(&table_name).as_deref(),
+            Ok(SqliteSchema::Table(t)) if t.without_rowid
+        );
+        if without_rowid {
+            return HashSet::new();
+        }
         self.schema
             .iter()
             .filter_map(|f| match f.as_ref() {
-                SqliteSchema::Table(_) => None,
                 SqliteSchema::Index(i) => {
-                    if i.parent_table.as_ref() == table_name.as_ref() {
-                        Some(i.column_name.to_string())
+                    if i.parent_table.as_ref() == table_name.as_ref() && i.is_searchable() {
+                        Some(i.column_name().to_string())
                     } else {
                         None
                     }
                 }
+                _ => None,
             })
             .collect()
     }
-    pub fn get_table_schema(&self, table_name: impl AsRef<str>) -> Result<Rc<SqliteSchema>> {
+    pub fn get_table_schema(&self, table_name: impl AsRef<str>) -> Result<Arc<SqliteSchema>> {
         let schema = self
             .schema
             .iter()
             .find(|f| match f.as_ref() {
                 SqliteSchema::Table(t) => t.name.as_ref() == table_name.as_ref(),
-                SqliteSchema::Index(_) => false,
+                _ => false,
             })
-            .context(format!(
-                "clould not find table named {}",
-                table_name.as_ref()
-            ))?
+            .ok_or_else(|| Error::NoSuchTable(table_name.as_ref().to_string()))?
             .clone();
         Ok(schema)
     }
@@ -355,119 +642,110 @@ impl Database {
         &self,
         table_name: impl AsRef<str>,
         column_name: impl AsRef<str>,
-    ) -> Result<Rc<SqliteSchema>> {
+    ) -> Result<Arc<SqliteSchema>> {
         let schema = self
             .schema
             .iter()
             .find(|f| match f.as_ref() {
                 SqliteSchema::Index(t) => {
-                    t.column_name.as_ref() == column_name.as_ref()
+                    t.column_name() == column_name.as_ref()
                         && t.parent_table.as_ref() == table_name.as_ref()
+                        && t.is_searchable()
                 }
-                SqliteSchema::Table(_) => false,
+                _ => false,
             })
-            .context(format!(
-                "could not find index named {}",
-                table_name.as_ref()
-            ))?
+            .ok_or_else(|| {
+                error!(
+                    Unsupported,
+                    "no index on {}({}) can be searched",
+                    table_name.as_ref(),
+                    column_name.as_ref()
+                )
+            })?
             .clone();
         Ok(schema)
     }
 
-    pub fn get_schemas(&self) -> Vec<Rc<SqliteSchema>> {
+    pub fn get_view_schema(&self, view_name: impl AsRef<str>) -> Option<Arc<SqliteSchema>> {
+        self.schema
+            .iter()
+            .find(|f| match f.as_ref() {
+                SqliteSchema::View(v) => v.name.as_ref() == view_name.as_ref(),
+                _ => false,
+            })
+            .cloned()
+    }
+
+    pub fn get_freelist(&self) -> Result<Freelist> {
+        Freelist::read(self)
+    }
+
+    pub fn get_schemas(&self) -> Vec<Arc<SqliteSchema>> {
         self.schema.clone()
     }
 
     fn read_schemas(&self) -> Result<Vec<SqliteSchema>> {
-        let page = self.read_table_page(1, None)?;
+        // the schema table is an ordinary table b-tree rooted at page 1
+        let schema_table = Arc::new(SqliteSchema::Table(TableSchema::sqlite_schema()));
+        let tree = TableBTree::new(self, schema_table)?;
         let mut schemas: Vec<SqliteSchema> = Vec::new();
-        let TablePage::Leaf(page) = page else {
-            bail!("sql schhemaa table must be a leaf page")
-        };
-        for pointer in page.cell_pointers {
-            let record = self.read_record(pointer.0, pointer.1)?;
+        for row in tree.row_reader(self) {
+            let record = row?.record;
             if record.record_header.headers.len() != 5 {
-                bail!("Schema table must have 5 fields");
+                bail!(Corrupt, "Schema table must have 5 fields");
             }
 
             let CellValue::String(name) = self.read_record_cell(&record, 1)? else {
-                bail!("name must be a string")
+                bail!(Corrupt, "name must be a string")
             };
             let CellValue::String(table_name) = self.read_record_cell(&record, 2)? else {
-                bail!("table_name must be a string field")
+                bail!(Corrupt, "table_name must be a string field")
             };
             let CellValue::Int(root_page) = self.read_record_cell(&record, 3)? else {
-                bail!("root_page must be an int")
+                bail!(Corrupt, "root_page must be an int")
             };
-            let CellValue::String(sql) = self.read_record_cell(&record, 4)? else {
-                bail!("sql must be a string field")
+            let sql = match self.read_record_cell(&record, 4)? {
+                CellValue::String(sql) => Some(sql),
+                CellValue::Null => None,
+                _ => bail!(Corrupt, "sql must be a string field"),
             };
             let schema = match self.read_record_cell(&record, 0)? {
                 CellValue::String(s) => match s.as_ref() {
-                    "table" => {
-                        let ast = match Parser::parse_sql(&DIALECT, &sql) {
-                            Result::Ok(ast) => ast,
-                            Result::Err(err) => {
-                                if let ParserError::ParserError(ref msg) = err {
-                                    //sqlparser doesn't support create tables with datatypeless
-                                    //columns https://github.com/sqlparser-rs/sqlparser-rs/issues/743
-                                    //one of the default schema columns does this
-                                    //the sqlite sequence table does this Result::Err(err) => return Err(err.into()),
-                                    if msg.contains("Expected a data type name, found:") {
-                                        continue;
-                                    }
-                                }
-                                bail!(err)
-                            }
-                        };
-                        if ast.len() != 1 {
-                            bail!("table sqchema sql can only have 1 expression");
-                        }
-                        let ast::Statement::CreateTable { columns, .. } =
-                            ast.first().expect("item is 1 item long")
-                        else {
-                            bail!("create table statement expected")
-                        };
-                        let columns = columns
-                            .iter()
-                            .map(|f| {
-                                let name = Rc::from(f.name.value.to_owned());
-                                Rc::new(Column {
-                                    type_affinity: (&f.data_type).into(),
-                                    name,
-                                })
-                            })
-                            .collect();
-                        SqliteSchema::Table(TableSchema {
-                            row_id: record.row_id,
-                            name: name.into(),
-                            table_name: table_name.into(),
-                            root_page: root_page as u32,
-                            sql,
-                            columns,
-                        })
-                    }
+                    "table" => SqliteSchema::Table(TableSchema::new(
+                        record.row_id,
+                        name.into(),
+                        table_name.into(),
+                        root_page as u32,
+                        sql.ok_or_else(|| error!(Corrupt, "table sql can't be null"))?,
+                    )?),
                     "index" => {
-                        let (_, parent_name, column_name) = name
-                            .split('_')
-                            .collect_tuple()
-                            .expect("only single column indexes are supported");
-
-                        SqliteSchema::Index(IndexSchema {
-                            row_id: record.row_id,
-                            root_page: root_page as u32,
+                        let table = schemas.iter().find_map(|s| match s {
+                            SqliteSchema::Table(t) if t.name.as_ref() == table_name => Some(t),
+                            _ => None,
+                        });
+                        SqliteSchema::Index(IndexSchema::new(
+                            record.row_id,
+                            name.into(),
+                            table_name.into(),
+                            root_page as u32,
                             sql,
-
-                            column_name: column_name.into(),
-                            parent_table: parent_name.into(),
-                            name: name.into(),
-                        })
+                            table,
+                        )?)
                     }
-                    "view" => bail!("views are not currenty supported"),
-                    "trigger" => bail!("triggers are not currenty supported"),
-                    _ => bail!("invalid schema type"),
+                    "view" => SqliteSchema::View(ViewSchema::new(
+                        record.row_id,
+                        name.into(),
+                        sql.ok_or_else(|| error!(Corrupt, "view sql can't be null"))?,
+                    )?),
+                    "trigger" => SqliteSchema::Trigger(TriggerSchema::new(
+                        record.row_id,
+                        name.into(),
+                        table_name.into(),
+                        sql.ok_or_else(|| error!(Corrupt, "trigger sql can't be null"))?,
+                    )?),
+                    _ => bail!(Corrupt, "invalid schema type"),
                 },
-                _ => bail!("type column must be string"),
+                _ => bail!(Corrupt, "type column must be string"),
             };
             schemas.push(schema);
         }
@@ -475,66 +753,136 @@ impl Database {
         Ok(schemas)
     }
 
-    pub fn read_u8(&self) -> Result<u8> {
+    /// A reader positioned at an offset into a page
+    pub fn reader(&self, page_number: u32, offset: u16) -> Result<PageReader> {
+        if offset as u32 > self.header.page_size {
+            bail!(Corrupt, "page offset can't be larger than page size");
+        }
+        Ok(PageReader {
+            page_number,
+            page: self.read_page(page_number)?,
+            offset: offset as usize,
+        })
+    }
+
+    /// Reads a whole page as it is currently committed, from the cache when it is there
+    pub fn read_page(&self, page_number: u32) -> Result<Arc<[u8]>> {
+        if let Some(page) = self.cache().get(page_number) {
+            return Ok(page);
+        }
+        let page: Arc<[u8]> = self
+            .load_page(page_number)
+            .with_context(|| format!("failed to read page {}", page_number))?
+            .into();
+        self.cache().insert(page_number, page.clone());
+        Ok(page)
+    }
+
+    fn load_page(&self, page_number: u32) -> Result<Vec<u8>> {
+        if let Some(page) = self.journal.as_ref().and_then(|j| j.page(page_number)) {
+            return Ok(page.to_vec());
+        }
+        let mut page = vec![0; self.header.page_size as usize];
+        // pages committed to the WAL are newer than the copy in the database file
+        if let Some(wal) = &self.wal {
+            if let Some(offset) = wal.frame_offset(page_number) {
+                wal.read_at(offset, &mut page)?;
+                return Ok(page);
+            }
+        }
+        self.storage.read_page(page_number, &mut page)?;
+        Ok(page)
+    }
+
+    fn cache(&self) -> MutexGuard<'_, PageCache> {
+        // the cache is consistent between calls, a thread that panicked while holding
+        // the lock can't have left it half updated
+        self.cache.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub fn cache_stats(&self) -> CacheStats {
+        self.cache().stats()
+    }
+
+    pub fn cache_size(&self) -> usize {
+        self.cache().capacity()
+    }
+
+    pub fn set_cache_size(&self, pages: usize) {
+        self.cache().set_capacity(pages);
+    }
+
+    pub fn wal(&self) -> Option<&Wal> {
+        self.wal.as_ref()
+    }
+}
+pub struct Varint {
+    pub value: i64,
+    pub size: u8,
+}
+
+/// Reads the fields of a page one after another. Every reader holds its own handle on
+/// the page and its own position in it, so any number of them can read at once.
+pub struct PageReader {
+    page_number: u32,
+    page: Arc<[u8]>,
+    offset: usize,
+}
+
+impl PageReader {
+    pub fn read_exact(&mut self, buf: &mut [u8]) -> Result<()> {
+        let Some(data) = self.page.get(self.offset..self.offset + buf.len()) else {
+            bail!(Corrupt, "read past the end of page {}", self.page_number);
+        };
+        buf.copy_from_slice(data);
+        self.offset += buf.len();
+        Ok(())
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8> {
         let mut buffer = [0; 1];
         self.read_exact(&mut buffer)?;
         Ok(u8::from_be_bytes(buffer))
     }
-    pub fn read_u16(&self) -> Result<u16> {
+
+    pub fn read_u16(&mut self) -> Result<u16> {
         let mut buffer = [0; 2];
         self.read_exact(&mut buffer)?;
         Ok(u16::from_be_bytes(buffer))
     }
 
-    pub fn read_u32(&self) -> Result<u32> {
+    pub fn read_u32(&mut self) -> Result<u32> {
         let mut buffer = [0; 4];
         self.read_exact(&mut buffer)?;
         Ok(u32::from_be_bytes(buffer))
     }
 
-    pub fn read_u64(&self) -> Result<u64> {
+    pub fn read_u64(&mut self) -> Result<u64> {
         let mut buffer = [0; 8];
         self.read_exact(&mut buffer)?;
         Ok(u64::from_be_bytes(buffer))
     }
 
-    fn seek_position(&self, pos: Position) -> Result<()> {
-        match pos {
-            Position::Relative => (),
-            Position::Absolute {
-                page_number,
-                pointer,
-            } => self.seek(page_number, pointer)?,
+    pub fn read_varint(&mut self) -> Result<Varint> {
+        let mut value: i64 = 0;
+        for size in 1..9 {
+            let byte = self.read_u8()?;
+            value = (value << 7) | i64::from(0b0111_1111 & byte);
+            if byte & 0b1000_0000 == 0 {
+                return Ok(Varint { value, size });
+            }
         }
-        Ok(())
-    }
-
-    pub fn seek(&self, page_number: u32, pointer: u16) -> Result<()> {
-        self.file.borrow_mut().seek(SeekFrom::Start(
-            self.get_location(page_number, pointer)? as u64
-        ))?;
-        Ok(())
-    }
-
-    fn read_exact(&self, buf: &mut [u8]) -> Result<()> {
-        self.file.borrow_mut().read_exact(buf)?;
-        Ok(())
+        // the ninth byte contributes all 8 bits, which negative numbers need
+        let byte = self.read_u8()?;
+        Ok(Varint {
+            value: (value << 8) | i64::from(byte),
+            size: 9,
+        })
     }
 }
-pub struct Varint {
-    pub value: i64,
-    pub size: u8,
-}
-#[derive(Debug)]
-pub enum Position {
-    Relative,
-    Absolute { page_number: u32, pointer: u16 },
-}
-impl Position {
-    pub fn new(page_number: u32, pointer: u16) -> Position {
-        Position::Absolute {
-            page_number,
-            pointer,
-        }
-    }
+
+#[derive(Debug, Clone, Copy)]
+pub enum PayloadKind {
+    Table,
+    Index,
 }
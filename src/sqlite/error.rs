@@ -0,0 +1,108 @@
+// Everything the library can fail with. Each kind keeps the message it was raised with,
+// so callers can match on what went wrong and still print what happened.
+
+use std::{
+    fmt, io,
+    string::{FromUtf16Error, FromUtf8Error},
+};
+
+use sqlparser::parser::ParserError;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    /// The file isn't a database or something in it contradicts the file format
+    #[error("{0}")]
+    Corrupt(String),
+    /// Valid SQL or a valid file that uses something not implemented here
+    #[error("{0}")]
+    Unsupported(String),
+    #[error("{0}")]
+    Parse(String),
+    #[error("no such table: {0}")]
+    NoSuchTable(String),
+    #[error("no such column: {0}")]
+    NoSuchColumn(String),
+    /// A value of one type where another was needed
+    #[error("{0}")]
+    TypeMismatch(String),
+    /// A change that would break a NOT NULL or UNIQUE constraint
+    #[error("{0}")]
+    Constraint(String),
+    /// Another process holds a lock on the database that conflicts with the one needed
+    #[error("{0}")]
+    Busy(String),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+impl Error {
+    /// Puts a note on what was being done in front of the message, keeping the kind
+    pub fn context(self, note: impl fmt::Display) -> Error {
+        match self {
+            Error::Corrupt(m) => Error::Corrupt(format!("{}: {}", note, m)),
+            Error::Unsupported(m) => Error::Unsupported(format!("{}: {}", note, m)),
+            Error::Parse(m) => Error::Parse(format!("{}: {}", note, m)),
+            Error::TypeMismatch(m) => Error::TypeMismatch(format!("{}: {}", note, m)),
+            Error::Constraint(m) => Error::Constraint(format!("{}: {}", note, m)),
+            Error::Busy(m) => Error::Busy(format!("{}: {}", note, m)),
+            // the name is the whole message of these
+            e @ (Error::NoSuchTable(_) | Error::NoSuchColumn(_)) => e,
+            Error::Io(e) => Error::Io(io::Error::new(e.kind(), format!("{}: {}", note, e))),
+        }
+    }
+}
+
+impl From<ParserError> for Error {
+    fn from(e: ParserError) -> Error {
+        Error::Parse(e.to_string())
+    }
+}
+
+// text read from the file that doesn't decode in the database's encoding
+impl From<FromUtf8Error> for Error {
+    fn from(e: FromUtf8Error) -> Error {
+        Error::Corrupt(e.to_string())
+    }
+}
+
+impl From<FromUtf16Error> for Error {
+    fn from(e: FromUtf16Error) -> Error {
+        Error::Corrupt(e.to_string())
+    }
+}
+
+/// Notes what was being done on a failed result, keeping the kind of its error
+pub trait Context<T> {
+    fn context(self, note: impl fmt::Display) -> Result<T>;
+
+    fn with_context<D: fmt::Display>(self, note: impl FnOnce() -> D) -> Result<T>;
+}
+
+impl<T, E: Into<Error>> Context<T> for Result<T, E> {
+    fn context(self, note: impl fmt::Display) -> Result<T> {
+        self.map_err(|e| e.into().context(note))
+    }
+
+    fn with_context<D: fmt::Display>(self, note: impl FnOnce() -> D) -> Result<T> {
+        self.map_err(|e| e.into().context(note()))
+    }
+}
+
+/// Returns early with an error of the given kind, the rest is formatted like `format!`
+macro_rules! bail {
+    ($kind:ident, $($arg:tt)+) => {
+        return Err($crate::sqlite::error::Error::$kind(format!($($arg)+)))
+    };
+}
+pub(crate) use bail;
+
+/// Builds an error of the given kind, for `ok_or_else` and `map_err`
+macro_rules! error {
+    ($kind:ident, $($arg:tt)+) => {
+        $crate::sqlite::error::Error::$kind(format!($($arg)+))
+    };
+}
+pub(crate) use error;
@@ -0,0 +1,95 @@
+// https://www.sqlite.org/fileformat2.html#the_freelist
+//
+// Unused pages are kept on the freelist, a linked list of trunk pages that starts at
+// the header's first freelist trunk page. Each trunk page is laid out as
+//
+// Offset  Size  Description
+// 0       4     Page number of the next trunk page, zero for the last one
+// 4       4     Number of leaf page numbers L that follow
+// 8       4*L   Page numbers of the freelist leaf pages
+
+use std::collections::HashSet;
+
+use super::{
+    database::Database,
+    error::{bail, Result},
+};
+
+#[derive(Debug, Clone, Default)]
+pub struct Freelist {
+    pub trunk_pages: Vec<u32>,
+    pub leaf_pages: Vec<u32>,
+}
+
+impl Freelist {
+    pub fn read(db: &Database) -> Result<Freelist> {
+        let mut freelist = Freelist::default();
+        let mut seen = HashSet::new();
+        let database_size = db.header.database_size;
+        let max_leaves = db.header.usable_size() / 4 - 2;
+
+        let mut check = |page_number: u32, kind: &str| {
+            if page_number == 0 || page_number > database_size {
+                bail!(
+                    Corrupt,
+                    "freelist {} page {} is outside the database of {} pages",
+                    kind,
+                    page_number,
+                    database_size
+                );
+            }
+            if !seen.insert(page_number) {
+                bail!(
+                    Corrupt,
+                    "freelist {} page {} appears twice",
+                    kind,
+                    page_number
+                );
+            }
+            Ok(())
+        };
+
+        let mut trunk = db.header.first_freelist_trunk_page;
+        while trunk != 0 {
+            check(trunk, "trunk")?;
+            freelist.trunk_pages.push(trunk);
+
+            let mut reader = db.reader(trunk, 0)?;
+            let next = reader.read_u32()?;
+            let leaf_count = reader.read_u32()?;
+            if leaf_count > max_leaves {
+                bail!(
+                    Corrupt,
+                    "freelist trunk page {} claims {} leaves but can hold at most {}",
+                    trunk,
+                    leaf_count,
+                    max_leaves
+                );
+            }
+            for _ in 0..leaf_count {
+                let leaf = reader.read_u32()?;
+                check(leaf, "leaf")?;
+                freelist.leaf_pages.push(leaf);
+            }
+            trunk = next;
+        }
+        Ok(freelist)
+    }
+
+    /// Every free page, trunks first
+    pub fn pages(&self) -> Vec<u32> {
+        self.trunk_pages
+            .iter()
+            .chain(&self.leaf_pages)
+            .copied()
+            .collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.trunk_pages.len() + self.leaf_pages.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.trunk_pages.is_empty()
+    }
+}
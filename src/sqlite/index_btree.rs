@@ -1,15 +1,21 @@
-use std::{borrow::Cow, fs::File, rc::Rc, vec};
+use std::{borrow::Cow, cmp::Ordering, fs::File, ops::Bound, slice, sync::Arc, vec};
 
 use super::{
-    database::Database,
-    page::{index_interior::IndexInteriorPage, index_leaf::IndexLeafPage, IndexPage},
-    record::CellValue,
-    schema::SqliteSchema,
+    balance::{self, Position},
+    cursor::{self, IndexCursor},
+    database::{Database, PayloadKind},
+    error::{bail, Result},
+    page::{
+        btree_page::BTreePage, index_interior::IndexInteriorPage, page_header::PageType, IndexPage,
+    },
+    record::{codec, known_collation, CellValue, Record},
+    schema::{index_schema::IndexSchema, schema_parser::SortOrder, SqliteSchema},
+    transaction::Transaction,
 };
-use anyhow::{bail, Result};
 use itertools::Itertools;
 use ptree::{print_tree_with, write_tree_with, PrintConfig, Style, TreeItem};
 
+/// An index b-tree read into memory as a whole, used to print it
 #[derive(Debug, Clone)]
 pub struct IndexNode {
     pub page: IndexPage,
@@ -18,124 +24,375 @@ pub struct IndexNode {
 
 #[derive(Debug)]
 pub struct IndexBTree {
-    pub root_node: IndexNode,
-    pub schema: Rc<SqliteSchema>,
+    pub root_page: u32,
+    pub schema: Arc<SqliteSchema>,
 }
 
 impl IndexNode {
     pub fn new(page: IndexPage, db: &Database) -> Result<IndexNode> {
+        IndexNode::read(page, db, 0)
+    }
+
+    /// Reads the subtree below a page that has `depth` pages above it
+    fn read(page: IndexPage, db: &Database, depth: usize) -> Result<IndexNode> {
         Ok(match &page {
             IndexPage::Leaf(_) => IndexNode {
                 page,
                 children: Vec::new(),
             },
             IndexPage::Interior(i) => {
-                let children = IndexBTree::get_child_pages(db, i)?;
+                let children = IndexBTree::get_child_pages(db, i, depth + 1)?;
                 IndexNode { page, children }
             }
         })
     }
-    pub fn get_row_ids(&self, db: &Database, value: &CellValue) -> Result<Vec<i64>> {
-        match &self.page {
-            IndexPage::Leaf(leaf) => self.handle_leaf_page(db, leaf, value),
-            IndexPage::Interior(int) => self.handle_interior_page(db, int, value),
-        }
+}
+
+impl IndexBTree {
+    pub fn new(db: &Database, schema: Arc<SqliteSchema>) -> Result<Self> {
+        let SqliteSchema::Index(t_schema) = schema.as_ref() else {
+            bail!(TypeMismatch, "expected index schema but got table");
+        };
+        // reading the root page checks that it really is an index b-tree
+        db.read_index_page(t_schema.root_page, None)?;
+
+        Ok(IndexBTree {
+            root_page: t_schema.root_page,
+            schema: schema.clone(),
+        })
     }
 
-    pub fn handle_leaf_page(
+    /// Locations of every entry in key order
+    pub fn entries<'a>(&self, db: &'a Database) -> IndexCursor<'a> {
+        IndexCursor::new(db, self.root_page)
+    }
+
+    /// The rowids of the entries whose first column equals `value`, read from the index
+    /// as the iterator advances
+    pub fn get_row_ids<'a>(
         &self,
-        db: &Database,
-        leaf: &IndexLeafPage,
+        db: &'a Database,
         value: &CellValue,
-    ) -> Result<Vec<i64>> {
-        Ok(leaf.cell_pointers
+    ) -> Result<impl Iterator<Item = Result<i64>> + 'a> {
+        let bound = Bound::Included(value.clone());
+        self.range(db, bound.clone(), bound, SortOrder::Asc)
+    }
+
+    /// The rowids of the entries whose first column lies between the bounds, walking the
+    /// index forward for `Asc` and backward for `Desc`. Bounds follow the order of the
+    /// index, on a DESC column the lower bound is the larger value.
+    pub fn range<'a>(
+        &self,
+        db: &'a Database,
+        lower: Bound<CellValue>,
+        upper: Bound<CellValue>,
+        direction: SortOrder,
+    ) -> Result<impl Iterator<Item = Result<i64>> + 'a> {
+        let SqliteSchema::Index(schema) = self.schema.as_ref() else {
+            bail!(TypeMismatch, "expected index schema but got table");
+        };
+        let orders = schema
+            .columns
             .iter()
-            .filter_map(|(page_number, pointer)| {
-                let record = db.read_index_record(*page_number, *pointer).unwrap();
-                let row_id = db.read_record_cell(&record, 1).unwrap();
-                let CellValue::Int(row_id) = row_id else { panic!("row_id must be an int {}",row_id); };
-                let cell = db.read_record_cell(&record, 0).unwrap();
-if &cell == value { Some(row_id) } else { None } 
+            .map(|c| (c.order, c.collation.clone()))
+            .collect_vec();
+        let (start, end) = match direction {
+            SortOrder::Asc => (lower, upper),
+            SortOrder::Desc => (upper, lower),
+        };
+        // entries equal to the start are skipped when it's excluded from the range
+        let (start_key, equal_skipped) = match start {
+            Bound::Included(key) => (Some(key), direction == SortOrder::Desc),
+            Bound::Excluded(key) => (Some(key), direction == SortOrder::Asc),
+            Bound::Unbounded => (None, direction == SortOrder::Desc),
+        };
+        let cursor = IndexCursor::seek_by(db, self.root_page, direction, |record| {
+            let Some(key) = &start_key else {
+                return Ok(equal_skipped);
+            };
+            Ok(
+                match cursor::compare(db, record, slice::from_ref(key), &orders)? {
+                    Ordering::Less => true,
+                    Ordering::Equal => equal_skipped,
+                    Ordering::Greater => false,
+                },
+            )
+        })?;
+        let beyond = match direction {
+            SortOrder::Asc => Ordering::Greater,
+            SortOrder::Desc => Ordering::Less,
+        };
+        Ok(cursor.map_while(move |location| {
+            let row_id = || -> Result<Option<i64>> {
+                let (page_number, pointer) = location?;
+                let record = db.read_index_record(page_number, pointer)?;
+                let (key, excluded) = match &end {
+                    Bound::Included(key) => (key, false),
+                    Bound::Excluded(key) => (key, true),
+                    Bound::Unbounded => return Ok(Some(IndexBTree::row_id(db, &record)?)),
+                };
+                let ordering = cursor::compare(db, &record, slice::from_ref(key), &orders)?;
+                if ordering == beyond || (ordering == Ordering::Equal && excluded) {
+                    return Ok(None);
+                }
+                Ok(Some(IndexBTree::row_id(db, &record)?))
+            };
+            row_id().transpose()
+        }))
+    }
+
+    /// Adds an entry, the values of the key columns followed by the rowid. A UNIQUE index
+    /// fails to take a key that an entry already has, unless it holds a NULL.
+    pub fn insert(
+        &self,
+        db: &Database,
+        transaction: &mut Transaction,
+        entry: &[CellValue],
+    ) -> Result<()> {
+        let schema = self.writable_schema(entry)?;
+        let Position {
+            path,
+            page_number,
+            index,
+            found,
+        } = self.seek(db, transaction, entry, false)?;
+        if found {
+            bail!(
+                Corrupt,
+                "index {} already has an entry for row {}",
+                schema.name,
+                entry[entry.len() - 1]
+            );
+        }
+        // entries with the same key would be the ones on either side of the new one,
+        // which are in the leaf or else in the dividers passed on the way down
+        if schema.unique && !entry.contains(&CellValue::Null) {
+            let mut neighbours = Vec::new();
+            for (page_number, i) in path.iter().chain([&(page_number, index)]) {
+                let page = transaction.btree_page(db, *page_number)?;
+                if *i > 0 {
+                    neighbours.push(read_entry(db, transaction, &page, i - 1)?);
+                }
+                if *i < page.cell_count() {
+                    neighbours.push(read_entry(db, transaction, &page, *i)?);
+                }
+            }
+            let duplicate = neighbours
+                .iter()
+                .any(|values| compare_entries(schema, values, entry, true).is_eq());
+            if duplicate {
+                let columns = schema
+                    .columns
+                    .iter()
+                    .map(|c| format!("{}.{}", schema.parent_table, c.name))
+                    .join(", ");
+                bail!(Constraint, "UNIQUE constraint failed: {}", columns);
+            }
+        }
 
-            })
-            .collect_vec())
+        // an index leaf cell is the payload size followed by the payload, with the first
+        // overflow page at the end when it doesn't fit on the page
+        let payload = codec::encode_record(entry, db.header.text_encoding, db.header.schema_format);
+        let mut cell = codec::encode_varint(payload.len() as i64);
+        let local = db.local_payload_size(payload.len(), PayloadKind::Index);
+        cell.extend_from_slice(&payload[..local]);
+        if local < payload.len() {
+            let overflow = transaction.write_overflow(db, &payload[local..])?;
+            cell.extend(overflow.to_be_bytes());
+        }
+        balance::insert_cells(db, transaction, &path, page_number, index, &[cell])
     }
 
-    pub fn handle_interior_page(
+    /// Removes an entry, the values of the key columns followed by the rowid
+    pub fn delete(
         &self,
         db: &Database,
-        int: &IndexInteriorPage,
-        value: &CellValue,
-    ) -> Result<Vec<i64>> {
-        let start_index = &int
-            .cells
-            .iter()
-            .find_position(|f| &f.value <= value)
-            .map(|f| f.0);
-        let end_index = int
-            .cells
-            .iter()
-            .rev()
-            .find_position(|f| &f.value >= value)
-            .map(|f| f.0);
-        if start_index.is_none() && end_index.is_none() {
-            return Ok(vec![]);
+        transaction: &mut Transaction,
+        entry: &[CellValue],
+    ) -> Result<()> {
+        let schema = self.writable_schema(entry)?;
+        let Position {
+            path,
+            page_number,
+            index,
+            found,
+        } = self.seek(db, transaction, entry, false)?;
+        if !found {
+            bail!(
+                Corrupt,
+                "index {} has no entry for row {}",
+                schema.name,
+                entry[entry.len() - 1]
+            );
+        }
+        let page = transaction.btree_page(db, page_number)?;
+        if let (_, Some((first_page, size))) = page.cell_payload(db, index)? {
+            transaction.free_overflow(db, first_page, size)?;
+        }
+        let page = transaction.btree_page(db, page_number)?;
+        if page.is_leaf() {
+            return balance::delete_cell(db, transaction, &path, page_number, index);
         }
 
-        let start = start_index.unwrap_or(0).saturating_sub(1);
-        let end = self
-            .children
-            .len()
-            .checked_sub(end_index.unwrap_or(0))
-            .unwrap_or(self.children.len());
+        // an entry on an interior page is replaced by the one before it, the last entry
+        // of the leaves under its left child, which then goes from its leaf
+        let left_child = page.left_child(index)?;
+        let mut leaf = left_child;
+        for depth in path.len() + 1.. {
+            cursor::check_depth(depth, leaf)?;
+            let page = transaction.btree_page(db, leaf)?;
+            if page.is_leaf() {
+                break;
+            }
+            leaf = page.right_child();
+        }
+        let page = transaction.btree_page(db, leaf)?;
+        let count = page.cell_count();
+        if count == 0 {
+            bail!(Corrupt, "index leaf page {} has no cells", leaf);
+        }
+        let previous = read_entry(db, transaction, &page, count - 1)?;
+        let cell = [&left_child.to_be_bytes()[..], page.cell(db, count - 1)?].concat();
+        transaction
+            .btree_page_mut(db, page_number)?
+            .remove_cell(db, index)?;
+        balance::insert_cells(db, transaction, &path, page_number, index, &[cell])?;
 
-        if start > end {
-            return Ok(vec![]);
+        // the divider may have moved up a page, the leaf entry is still under its left
+        // child and keeps its overflow pages, which the divider now uses
+        let Position {
+            path,
+            page_number,
+            index,
+            found,
+        } = self.seek(db, transaction, &previous, true)?;
+        if !found {
+            bail!(
+                Corrupt,
+                "index {} lost an entry while deleting",
+                schema.name
+            );
         }
-        int.cells[start..end]
-            .iter()
-            .map(|f| f.value.clone())
-            .collect_vec();
-        let row_ids = &self.children[start..end]
-            .iter()
-            .flat_map(|child| match child.get_row_ids(db, value) {
-                Ok(ids) => ids.into_iter().map(Ok).collect_vec(), // Convert each id into an Ok result
-                Err(e) => vec![Err(e)], // Convert the error into a single-element vector with an Err result
-            })
-            .collect::<Result<Vec<_>, _>>()?;
-        Ok(row_ids.to_owned())
+        balance::delete_cell(db, transaction, &path, page_number, index)
     }
-}
 
-impl IndexBTree {
-    pub fn new(db: &Database, schema: Rc<SqliteSchema>) -> Result<Self> {
-        let SqliteSchema::Index(t_schema) = schema.as_ref() else {
-            bail!("expected index schema but got table");
+    /// The schema of the index, once it is known entries like this one can be added to
+    /// and removed from it
+    fn writable_schema(&self, entry: &[CellValue]) -> Result<&IndexSchema> {
+        let SqliteSchema::Index(schema) = self.schema.as_ref() else {
+            bail!(TypeMismatch, "expected index schema but got table");
         };
-        let root_node = IndexNode::new(db.read_index_page(t_schema.root_page, None)?, db)?;
+        if entry.len() != schema.columns.len() + 1 {
+            bail!(
+                TypeMismatch,
+                "index {} has {} key columns",
+                schema.name,
+                schema.columns.len()
+            );
+        }
+        if let Some(c) = schema
+            .columns
+            .iter()
+            .find(|c| !known_collation(c.collation.as_deref()))
+        {
+            bail!(
+                Unsupported,
+                "index {} uses the unknown collation {}",
+                schema.name,
+                c.collation.as_deref().unwrap_or_default()
+            );
+        }
+        Ok(schema)
+    }
 
-        Ok(IndexBTree {
-            root_node,
-            schema: schema.clone(),
-        })
+    /// Finds the page an entry is on or belongs on, with the interior pages passed on the
+    /// way down and whether the entry is there. With `to_leaf` an entry that is on an
+    /// interior page is looked for in the leaves under it instead.
+    fn seek(
+        &self,
+        db: &Database,
+        transaction: &Transaction,
+        entry: &[CellValue],
+        to_leaf: bool,
+    ) -> Result<Position> {
+        let SqliteSchema::Index(schema) = self.schema.as_ref() else {
+            bail!(TypeMismatch, "expected index schema but got table");
+        };
+        let mut path = Vec::new();
+        let mut page_number = self.root_page;
+        loop {
+            cursor::check_depth(path.len(), page_number)?;
+            let page = transaction.btree_page(db, page_number)?;
+            let count = page.cell_count();
+            let compare = |i: usize| -> Result<Ordering> {
+                let values = read_entry(db, transaction, &page, i)?;
+                Ok(compare_entries(schema, &values, entry, false))
+            };
+            let i = cursor::partition_point(count, |i| Ok(compare(i)?.is_lt()))?;
+            let found = i < count && compare(i)?.is_eq();
+            match page.page_type() {
+                PageType::IndexInterior if found && !to_leaf => {
+                    return Ok(Position {
+                        path,
+                        page_number,
+                        index: i,
+                        found: true,
+                    })
+                }
+                PageType::IndexInterior => {
+                    path.push((page_number, i));
+                    page_number = match i {
+                        i if i == count => page.right_child(),
+                        i => page.left_child(i)?,
+                    }
+                }
+                PageType::IndexLeaf => {
+                    return Ok(Position {
+                        path,
+                        page_number,
+                        index: i,
+                        found,
+                    })
+                }
+                _ => bail!(
+                    Corrupt,
+                    "page {} of {} is not an index page",
+                    page_number,
+                    schema.name
+                ),
+            }
+        }
     }
 
-    pub fn get_row_ids(&self, db: &Database, value: &CellValue) -> Result<Vec<i64>> {
-        self.root_node.get_row_ids(db, value)
+    fn row_id(db: &Database, record: &Record) -> Result<i64> {
+        // the rowid is always the last value of an index record
+        let last = record.record_header.headers.len().saturating_sub(1);
+        match db.read_record_cell(record, last)? {
+            CellValue::Int(row_id) => Ok(row_id),
+            value => bail!(Corrupt, "row_id must be an int {}", value),
+        }
     }
 
-    fn get_child_pages(db: &Database, page: &IndexInteriorPage) -> Result<Vec<IndexNode>> {
+    fn get_child_pages(
+        db: &Database,
+        page: &IndexInteriorPage,
+        depth: usize,
+    ) -> Result<Vec<IndexNode>> {
         let mut result = Vec::new();
         for cell in &page.cells {
+            cursor::check_depth(depth, cell.left_child_page_number)?;
             let page =
                 db.read_index_page(cell.left_child_page_number, Some(cell.value.to_owned()))?;
-            let node = IndexNode::new(page, db)?;
+            let node = IndexNode::read(page, db, depth)?;
             result.push(node);
         }
+        cursor::check_depth(depth, page.right_cell)?;
+        let page = db.read_index_page(page.right_cell, None)?;
+        result.push(IndexNode::read(page, db, depth)?);
         Ok(result)
     }
 
-    pub fn pretty_print(&self) -> Result<()> {
+    pub fn pretty_print(&self, db: &Database) -> Result<()> {
         let config = PrintConfig {
             leaf: Style {
                 bold: true,
@@ -146,31 +403,77 @@ impl IndexBTree {
         };
         let file_name = format!("{}-tree.txt", self.schema.get_name());
         let file = File::create(file_name)?;
-        write_tree_with(&self.root_node, &file, &config)?;
-        print_tree_with(&self.root_node, &config)?;
+        let root_node = IndexNode::new(db.read_index_page(self.root_page, None)?, db)?;
+        write_tree_with(&root_node, &file, &config)?;
+        print_tree_with(&root_node, &config)?;
         Ok(())
     }
 }
 
-
 impl TreeItem for IndexNode {
     type Child = Self;
 
     fn write_self<W: std::io::Write>(&self, f: &mut W, _: &ptree::Style) -> std::io::Result<()> {
         match &self.page {
-            IndexPage::Leaf(leaf) => {
-                write!(f, "Leaf-{}", leaf.value) // Writ
-            }
+            IndexPage::Leaf(leaf) => match &leaf.value {
+                Some(value) => write!(f, "Leaf-{}", value),
+                None => write!(f, "Leaf"),
+            },
             IndexPage::Interior(int) => {
                 write!(f, "Interior-{}", int.value)
             }
         }
     }
 
-    fn children(&self) -> std::borrow::Cow<[Self::Child]> {
+    fn children(&self) -> std::borrow::Cow<'_, [Self::Child]> {
         match &self.page {
             IndexPage::Leaf(_) => Cow::from(vec![]),
             IndexPage::Interior(_) => self.children.to_owned().into(),
         }
     }
 }
+
+/// The values of an index entry, reading what doesn't fit on the page from its overflow
+/// pages
+fn read_entry<T: AsRef<[u8]>>(
+    db: &Database,
+    transaction: &Transaction,
+    page: &BTreePage<T>,
+    index: usize,
+) -> Result<Vec<CellValue>> {
+    let (local, overflow) = page.cell_payload(db, index)?;
+    let payload = match overflow {
+        Some((first_page, size)) => {
+            [local, &transaction.read_overflow(db, first_page, size)?].concat()
+        }
+        None => local.to_vec(),
+    };
+    codec::decode_record(&payload, db.header.text_encoding)
+}
+
+/// Orders a stored entry against another by the key columns, in the order and collation
+/// of each, and then by rowid unless `key_only`
+fn compare_entries(
+    schema: &IndexSchema,
+    stored: &[CellValue],
+    entry: &[CellValue],
+    key_only: bool,
+) -> Ordering {
+    let key = schema
+        .columns
+        .iter()
+        .zip(stored.iter().zip(entry))
+        .map(|(column, (l, r))| match column.order {
+            SortOrder::Asc => l.collate_cmp(r, column.collation.as_deref()),
+            SortOrder::Desc => l.collate_cmp(r, column.collation.as_deref()).reverse(),
+        })
+        .find(|o| o.is_ne())
+        .unwrap_or(Ordering::Equal);
+    match key_only {
+        true => key,
+        false => key.then_with(|| match (stored.last(), entry.last()) {
+            (Some(l), Some(r)) => l.sqlite_cmp(r),
+            _ => Ordering::Equal,
+        }),
+    }
+}
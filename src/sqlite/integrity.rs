@@ -0,0 +1,675 @@
+// https://www.sqlite.org/pragma.html#pragma_integrity_check
+//
+// Walks every b-tree, overflow chain and the freelist and reports what is wrong with
+// the file instead of stopping at the first problem. The messages follow the ones
+// sqlite prints so the two can be compared.
+
+use std::{cmp::Ordering, collections::HashSet};
+
+use super::{
+    btree::TableBTree,
+    column::TypeAffinity,
+    cursor,
+    database::{Database, PayloadKind},
+    error::{bail, Result},
+    freelist::Freelist,
+    index_btree::IndexBTree,
+    record::{codec, known_collation, CellValue},
+    schema::{
+        index_schema::IndexSchema, schema_parser::SortOrder, table_schema::TableSchema,
+        SqliteSchema,
+    },
+};
+
+const PAGE_TYPE_INDEX_INTERIOR: u8 = 0x02;
+const PAGE_TYPE_TABLE_INTERIOR: u8 = 0x05;
+const PAGE_TYPE_INDEX_LEAF: u8 = 0x0a;
+const PAGE_TYPE_TABLE_LEAF: u8 = 0x0d;
+
+// the page holding the bytes sqlite uses for file locks is never part of the database
+const LOCK_BYTE_OFFSET: u64 = 0x40000000;
+
+/// The number of problems reported unless asked for another, the same as PRAGMA
+/// integrity_check
+pub const DEFAULT_MAX_ERRORS: usize = 100;
+
+/// A b-tree to check, starting from its root page
+struct Tree {
+    root: u32,
+    kind: TreeKind,
+}
+
+enum TreeKind {
+    /// Cells are keyed by rowid
+    Table,
+    /// Cells are keyed by a record, compared column by column. None when a column uses a
+    /// collation this crate doesn't implement and the entries can't be ordered.
+    Index(Option<Vec<KeyColumn>>),
+}
+
+struct KeyColumn {
+    order: SortOrder,
+    collation: Option<String>,
+}
+
+enum Key {
+    Rowid(i64),
+    Entry(Vec<CellValue>),
+}
+
+struct CellInfo {
+    size: usize,
+    rowid: i64,
+    left_child: Option<u32>,
+    /// The first overflow page and the length of the chain
+    overflow: Option<(u32, usize)>,
+}
+
+pub struct IntegrityCheck<'a> {
+    db: &'a Database,
+    usable_size: usize,
+    page_count: u32,
+    /// Every page reached so far from a b-tree, an overflow chain or the freelist
+    referenced: HashSet<u32>,
+    errors: Vec<String>,
+    /// The check stops once it has found this many problems
+    max_errors: usize,
+}
+
+impl<'a> IntegrityCheck<'a> {
+    /// Checks the whole database, returning the problems found up to the given number.
+    /// An empty list means the database is intact.
+    pub fn run(db: &'a Database, max_errors: usize) -> Result<Vec<String>> {
+        let mut check = IntegrityCheck {
+            db,
+            usable_size: db.header.usable_size() as usize,
+            page_count: db.header.database_size,
+            referenced: HashSet::new(),
+            errors: Vec::new(),
+            max_errors,
+        };
+
+        check.check_freelist();
+        for tree in check.trees() {
+            check.check_page(&tree, tree.root, 0, None, None);
+        }
+        check.check_unused_pages();
+
+        // comparing indexes with their tables reads through the b-trees, which is only
+        // safe once their structure is known to be sound
+        if check.errors.is_empty() {
+            check.check_indexes()?;
+        }
+        Ok(check.errors)
+    }
+
+    fn trees(&self) -> Vec<Tree> {
+        let mut trees = vec![Tree {
+            root: 1,
+            kind: TreeKind::Table,
+        }];
+        for schema in self.db.get_schemas() {
+            match schema.as_ref() {
+                SqliteSchema::Table(table) if table.without_rowid => {
+                    let key = table
+                        .primary_key
+                        .iter()
+                        .map(|(i, order)| key_column(table, &table.columns[*i].name, *order))
+                        .collect();
+                    trees.push(Tree {
+                        root: table.root_page,
+                        kind: TreeKind::Index(key),
+                    });
+                }
+                SqliteSchema::Table(table) => trees.push(Tree {
+                    root: table.root_page,
+                    kind: TreeKind::Table,
+                }),
+                SqliteSchema::Index(index) => trees.push(Tree {
+                    root: index.root_page,
+                    kind: TreeKind::Index(self.index_key(index)),
+                }),
+                SqliteSchema::View(_) | SqliteSchema::Trigger(_) => {}
+            }
+        }
+        trees
+    }
+
+    // index records hold the key columns followed by the rowid, or by the primary key
+    // columns the index doesn't already have for WITHOUT ROWID tables
+    fn index_key(&self, index: &IndexSchema) -> Option<Vec<KeyColumn>> {
+        let table = self.db.get_table_schema(&index.parent_table).ok()?;
+        let SqliteSchema::Table(table) = table.as_ref() else {
+            return None;
+        };
+        let mut key = index
+            .columns
+            .iter()
+            .map(|c| {
+                let collation = c.collation.as_deref();
+                known_collation(collation).then(|| KeyColumn {
+                    order: c.order,
+                    collation: collation.map(|c| c.to_string()),
+                })
+            })
+            .collect::<Option<Vec<_>>>()?;
+        if !table.without_rowid {
+            key.push(KeyColumn {
+                order: SortOrder::Asc,
+                collation: None,
+            });
+            return Some(key);
+        }
+        for (i, order) in &table.primary_key {
+            let name = &table.columns[*i].name;
+            if !index
+                .columns
+                .iter()
+                .any(|c| c.name.eq_ignore_ascii_case(name))
+            {
+                key.push(key_column(table, name, *order)?);
+            }
+        }
+        Some(key)
+    }
+
+    fn check_freelist(&mut self) {
+        match Freelist::read(self.db) {
+            Ok(freelist) => {
+                for page_number in freelist.pages() {
+                    self.reference(page_number);
+                }
+                if freelist.len() != self.db.header.freelist_count as usize {
+                    self.report(format!(
+                        "Freelist: size is {} but should be {}",
+                        freelist.len(),
+                        self.db.header.freelist_count
+                    ));
+                }
+            }
+            Err(e) => self.report(format!("Freelist: {}", e)),
+        }
+    }
+
+    fn check_unused_pages(&mut self) {
+        // autovacuum databases keep pointer map pages, the first is page 2 and every
+        // usable_size/5 pages after it another one follows
+        let ptrmap_interval = self.usable_size as u32 / 5 + 1;
+        let is_ptrmap = |page_number: u32| {
+            self.db.header.largest_root_page != 0
+                && page_number >= 2
+                && (page_number - 2).is_multiple_of(ptrmap_interval)
+        };
+        let lock_page = (LOCK_BYTE_OFFSET / self.db.header.page_size as u64 + 1) as u32;
+
+        for page_number in 1..=self.page_count {
+            if self.errors.len() >= self.max_errors {
+                break;
+            }
+            if !self.referenced.contains(&page_number)
+                && !is_ptrmap(page_number)
+                && page_number != lock_page
+            {
+                self.report(format!("Page {} is never used", page_number));
+            }
+        }
+    }
+
+    fn report(&mut self, problem: String) {
+        if self.errors.len() < self.max_errors {
+            self.errors.push(problem);
+        }
+    }
+
+    /// Marks a page as used, reporting pages outside the file and pages reached twice
+    fn reference(&mut self, page_number: u32) -> bool {
+        if page_number == 0 || page_number > self.page_count {
+            self.report(format!("invalid page number {}", page_number));
+            return false;
+        }
+        if !self.referenced.insert(page_number) {
+            self.report(format!("2nd reference to page {}", page_number));
+            return false;
+        }
+        true
+    }
+
+    /// Checks a page `level` pages below the root and the subtree below it, returning
+    /// the depth of the subtree. Every key on the page must be above lower, and at most
+    /// upper for table b-trees or below it for index b-trees whose interior cells are
+    /// entries themselves.
+    fn check_page(
+        &mut self,
+        tree: &Tree,
+        page_number: u32,
+        level: usize,
+        lower: Option<&Key>,
+        upper: Option<&Key>,
+    ) -> Option<usize> {
+        if !self.reference(page_number) {
+            return None;
+        }
+        let on_page =
+            |message: String| format!("Tree {} page {}: {}", tree.root, page_number, message);
+        if let Err(e) = cursor::check_depth(level, page_number) {
+            self.report(on_page(e.to_string()));
+            return None;
+        }
+        let data = match self.db.read_page(page_number) {
+            Ok(data) => data,
+            Err(e) => {
+                self.report(on_page(e.to_string()));
+                return None;
+            }
+        };
+
+        // page 1 starts with the database header
+        let header_offset = if page_number == 1 { 100 } else { 0 };
+        let page_type = data[header_offset];
+        let interior = match (&tree.kind, page_type) {
+            (TreeKind::Table, PAGE_TYPE_TABLE_INTERIOR)
+            | (TreeKind::Index(_), PAGE_TYPE_INDEX_INTERIOR) => true,
+            (TreeKind::Table, PAGE_TYPE_TABLE_LEAF)
+            | (TreeKind::Index(_), PAGE_TYPE_INDEX_LEAF) => false,
+            _ => {
+                self.report(on_page(format!("invalid page type {:#04x}", page_type)));
+                return None;
+            }
+        };
+
+        let cell_count = u16_at(&data, header_offset + 3) as usize;
+        let content_start = match u16_at(&data, header_offset + 5) {
+            0 => 65536,
+            n => n as usize,
+        };
+        let fragmented = data[header_offset + 7] as usize;
+        let pointers_start = header_offset + if interior { 12 } else { 8 };
+        let pointers_end = pointers_start + 2 * cell_count;
+        if pointers_end > content_start || content_start > self.usable_size {
+            self.report(on_page(format!(
+                "{} cells don't fit before the content area at {}",
+                cell_count, content_start
+            )));
+            return None;
+        }
+
+        let mut used = vec![false; self.usable_size];
+        mark(&mut used, 0, pointers_end);
+        let mut overlap = None;
+        let mut cells = Vec::with_capacity(cell_count);
+        for i in 0..cell_count {
+            let on_cell = |message: String| {
+                format!(
+                    "Tree {} page {} cell {}: {}",
+                    tree.root, page_number, i, message
+                )
+            };
+            let pointer = u16_at(&data, pointers_start + 2 * i) as usize;
+            if pointer < content_start || pointer >= self.usable_size {
+                self.report(on_cell(format!(
+                    "offset {} out of range {}..{}",
+                    pointer, content_start, self.usable_size
+                )));
+                continue;
+            }
+            let cell = match self.parse_cell(&data, pointer, page_type) {
+                Ok(cell) if pointer + cell.size <= self.usable_size => cell,
+                Ok(_) => {
+                    self.report(on_cell("extends past the end of the page".to_string()));
+                    continue;
+                }
+                Err(e) => {
+                    self.report(on_cell(e.to_string()));
+                    continue;
+                }
+            };
+            overlap = overlap.or(mark(&mut used, pointer, cell.size));
+            if let Some((first_page, length)) = cell.overflow {
+                if let Some(message) = self.check_overflow(first_page, length) {
+                    self.report(on_cell(message));
+                }
+            }
+
+            let key = match tree.kind {
+                TreeKind::Table => Key::Rowid(cell.rowid),
+                // the record of an interior index cell follows the child page number
+                TreeKind::Index(_) => {
+                    let record_pointer = pointer + cell.left_child.map_or(0, |_| 4);
+                    let values = self
+                        .db
+                        .read_index_record(page_number, record_pointer as u16)
+                        .and_then(|r| {
+                            codec::decode_record(r.payload(), self.db.header.text_encoding)
+                        });
+                    match values {
+                        Ok(values) => Key::Entry(values),
+                        Err(e) => {
+                            self.report(on_cell(e.to_string()));
+                            continue;
+                        }
+                    }
+                }
+            };
+            cells.push((i, key, cell.left_child));
+        }
+
+        // freeblocks form a chain in ascending order through the content area
+        let mut freeblock = u16_at(&data, header_offset + 1) as usize;
+        let mut previous_end = content_start;
+        while freeblock != 0 {
+            if freeblock < previous_end || freeblock + 4 > self.usable_size {
+                self.report(on_page(format!(
+                    "freeblock at offset {} is out of order or out of range",
+                    freeblock
+                )));
+                break;
+            }
+            let size = u16_at(&data, freeblock + 2) as usize;
+            if size < 4 || freeblock + size > self.usable_size {
+                self.report(on_page(format!(
+                    "freeblock at offset {} has invalid size {}",
+                    freeblock, size
+                )));
+                break;
+            }
+            overlap = overlap.or(mark(&mut used, freeblock, size));
+            previous_end = freeblock + size;
+            freeblock = u16_at(&data, freeblock) as usize;
+        }
+
+        if let Some(byte) = overlap {
+            self.report(format!(
+                "Multiple uses for byte {} of page {}",
+                byte, page_number
+            ));
+        } else {
+            // whatever the cells and freeblocks leave of the content area are fragments
+            // too small to join the freeblock chain
+            let unused = used[content_start..].iter().filter(|u| !**u).count();
+            if unused != fragmented {
+                self.report(format!(
+                    "Fragmentation of {} bytes reported as {} on page {}",
+                    unused, fragmented, page_number
+                ));
+            }
+        }
+
+        let mut previous = lower;
+        let mut depth = None;
+        for (i, key, _) in &cells {
+            let on_cell = |message: String| {
+                format!(
+                    "Tree {} page {} cell {}: {}",
+                    tree.root, page_number, i, message
+                )
+            };
+            if let Some(previous) = previous {
+                if self.compare(tree, previous, key) != Some(Ordering::Less) {
+                    self.report(on_cell(match key {
+                        Key::Rowid(rowid) => format!("Rowid {} out of order", rowid),
+                        Key::Entry(_) => "entry out of order".to_string(),
+                    }));
+                }
+            }
+            if let Some(upper) = upper {
+                let allowed = match tree.kind {
+                    TreeKind::Table => [Ordering::Less, Ordering::Equal].as_slice(),
+                    TreeKind::Index(_) => [Ordering::Less].as_slice(),
+                };
+                if self
+                    .compare(tree, key, upper)
+                    .is_some_and(|o| !allowed.contains(&o))
+                {
+                    self.report(on_cell("key is beyond the parent cell".to_string()));
+                }
+            }
+            previous = Some(key);
+        }
+
+        if interior {
+            let right_child = u32_at(&data, header_offset + 8);
+            let mut lower = lower;
+            let children = cells
+                .iter()
+                .map(|(_, key, child)| (child.unwrap_or_default(), Some(key)))
+                .chain([(right_child, upper)]);
+            for (child, upper) in children {
+                let child_depth = self.check_page(tree, child, level + 1, lower, upper);
+                match (depth, child_depth) {
+                    (Some(depth), Some(child_depth)) if depth != child_depth => {
+                        self.report(on_page("Child page depth differs".to_string()));
+                    }
+                    (None, child_depth) => depth = child_depth,
+                    _ => {}
+                }
+                lower = upper;
+            }
+        }
+        Some(depth.map_or(0, |d| d + 1))
+    }
+
+    fn parse_cell(&self, data: &[u8], pointer: usize, page_type: u8) -> Result<CellInfo> {
+        let mut offset = pointer;
+        let varint = |offset: &mut usize| -> Result<i64> {
+            let varint = codec::decode_varint(data.get(*offset..).unwrap_or_default())?;
+            *offset += varint.size as usize;
+            Ok(varint.value)
+        };
+
+        let left_child = match page_type {
+            PAGE_TYPE_TABLE_INTERIOR | PAGE_TYPE_INDEX_INTERIOR => {
+                if pointer + 4 > data.len() {
+                    bail!(Corrupt, "cell extends past the end of the page");
+                }
+                offset += 4;
+                Some(u32_at(data, pointer))
+            }
+            _ => None,
+        };
+        if page_type == PAGE_TYPE_TABLE_INTERIOR {
+            let rowid = varint(&mut offset)?;
+            return Ok(CellInfo {
+                size: offset - pointer,
+                rowid,
+                left_child,
+                overflow: None,
+            });
+        }
+
+        let payload_size = varint(&mut offset)?;
+        if payload_size < 0 {
+            bail!(Corrupt, "invalid payload size {}", payload_size);
+        }
+        let payload_size = payload_size as usize;
+        let (rowid, kind) = match page_type {
+            PAGE_TYPE_TABLE_LEAF => (varint(&mut offset)?, PayloadKind::Table),
+            _ => (0, PayloadKind::Index),
+        };
+        let local_size = self.db.local_payload_size(payload_size, kind);
+        offset += local_size;
+
+        let overflow = match local_size < payload_size {
+            true => {
+                if offset + 4 > data.len() {
+                    bail!(Corrupt, "cell extends past the end of the page");
+                }
+                let first_page = u32_at(data, offset);
+                offset += 4;
+                let length = (payload_size - local_size).div_ceil(self.usable_size - 4);
+                Some((first_page, length))
+            }
+            false => None,
+        };
+        Ok(CellInfo {
+            size: offset - pointer,
+            rowid,
+            left_child,
+            overflow,
+        })
+    }
+
+    /// Follows an overflow chain, describing what is wrong with it
+    fn check_overflow(&mut self, first_page: u32, length: usize) -> Option<String> {
+        let mut next = first_page;
+        for followed in 0..length {
+            if next == 0 {
+                return Some(format!(
+                    "overflow list length is {} but should be {}",
+                    followed, length
+                ));
+            }
+            if !self.reference(next) {
+                return None;
+            }
+            next = match self.db.read_page(next) {
+                Ok(page) => u32_at(&page, 0),
+                Err(e) => return Some(e.to_string()),
+            };
+        }
+        (next != 0).then(|| format!("overflow list is longer than {} pages", length))
+    }
+
+    fn compare(&self, tree: &Tree, left: &Key, right: &Key) -> Option<Ordering> {
+        match (&tree.kind, left, right) {
+            (TreeKind::Table, Key::Rowid(l), Key::Rowid(r)) => Some(l.cmp(r)),
+            (TreeKind::Index(Some(columns)), Key::Entry(l), Key::Entry(r)) => {
+                let ordering = columns
+                    .iter()
+                    .zip(l.iter().zip(r))
+                    .map(|(column, (l, r))| {
+                        let ordering = l.collate_cmp(r, column.collation.as_deref());
+                        match column.order {
+                            SortOrder::Asc => ordering,
+                            SortOrder::Desc => ordering.reverse(),
+                        }
+                    })
+                    .find(|o| o.is_ne())
+                    .unwrap_or(Ordering::Equal);
+                Some(ordering)
+            }
+            _ => None,
+        }
+    }
+
+    /// Compares every index with its table: each row needs exactly one entry
+    fn check_indexes(&mut self) -> Result<()> {
+        let encoding = self.db.header.text_encoding;
+        for schema in self.db.get_schemas() {
+            let SqliteSchema::Index(index) = schema.as_ref() else {
+                continue;
+            };
+            // which rows belong in a partial index depends on its WHERE clause and
+            // expression indexes hold computed values, neither can be evaluated here
+            if index.where_clause.is_some() || index.columns.iter().any(|c| c.expression) {
+                continue;
+            }
+            let table_schema = self.db.get_table_schema(&index.parent_table)?;
+            let SqliteSchema::Table(table) = table_schema.as_ref() else {
+                continue;
+            };
+
+            let mut key_columns = index
+                .columns
+                .iter()
+                .map(|c| c.name.as_str())
+                .collect::<Vec<_>>();
+            if table.without_rowid {
+                for (i, _) in &table.primary_key {
+                    let name = table.columns[*i].name.as_ref();
+                    if !key_columns.iter().any(|c| c.eq_ignore_ascii_case(name)) {
+                        key_columns.push(name);
+                    }
+                }
+            }
+            let affinities = key_columns
+                .iter()
+                .map(|name| {
+                    table
+                        .column_index(name)
+                        .map(|i| table.columns[i].type_affinity.clone())
+                })
+                .chain([Some(TypeAffinity::Int)])
+                .collect::<Vec<_>>();
+            let normalize = |values: Vec<CellValue>| {
+                let values = values
+                    .into_iter()
+                    .zip(&affinities)
+                    .map(|(value, affinity)| match affinity {
+                        Some(affinity) => affinity.apply(value),
+                        None => value,
+                    })
+                    .collect::<Vec<_>>();
+                codec::encode_record(&values, encoding, self.db.header.schema_format)
+            };
+
+            let index_tree = IndexBTree::new(self.db, schema.clone())?;
+            let mut entries = HashSet::new();
+            let mut entry_count = 0;
+            for location in index_tree.entries(self.db) {
+                let (page_number, pointer) = location?;
+                let record = self.db.read_index_record(page_number, pointer)?;
+                entries.insert(normalize(codec::decode_record(record.payload(), encoding)?));
+                entry_count += 1;
+            }
+
+            let table_tree = TableBTree::new(self.db, table_schema.clone())?;
+            let mut row_count = 0;
+            for row in table_tree.row_reader(self.db) {
+                let row = row?;
+                let mut key = key_columns
+                    .iter()
+                    .map(|name| row.read_column(name))
+                    .collect::<Result<Vec<_>>>()?;
+                if !table.without_rowid {
+                    key.push(CellValue::Int(row.record.row_id));
+                }
+                if !entries.contains(&normalize(key)) {
+                    self.report(format!(
+                        "row {} missing from index {}",
+                        row_count + 1,
+                        index.name
+                    ));
+                }
+                row_count += 1;
+            }
+            if row_count != entry_count {
+                self.report(format!("wrong # of entries in index {}", index.name));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A key column of a table column, ordered by the column's collation
+fn key_column(table: &TableSchema, name: &str, order: SortOrder) -> Option<KeyColumn> {
+    let collation = table
+        .column_index(name)
+        .and_then(|i| table.columns[i].collation.as_ref())
+        .map(|c| c.to_string());
+    known_collation(collation.as_deref()).then_some(KeyColumn { order, collation })
+}
+
+/// Marks bytes of a page as used, returning the first one that already was
+fn mark(used: &mut [bool], start: usize, len: usize) -> Option<usize> {
+    let mut overlap = None;
+    for (offset, byte) in used[start..start + len].iter_mut().enumerate() {
+        if *byte && overlap.is_none() {
+            overlap = Some(start + offset);
+        }
+        *byte = true;
+    }
+    overlap
+}
+
+fn u16_at(data: &[u8], offset: usize) -> u16 {
+    u16::from_be_bytes([data[offset], data[offset + 1]])
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes([
+        data[offset],
+        data[offset + 1],
+        data[offset + 2],
+        data[offset + 3],
+    ])
+}
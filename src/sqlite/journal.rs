@@ -0,0 +1,298 @@
+// https://www.sqlite.org/fileformat2.html#the_rollback_journal
+//
+// A rollback journal holds the original content of every page a transaction changed.
+// If the writer dies before the transaction commits the journal is left "hot" and the
+// database file can't be trusted until the journal has been played back into it.
+//
+// The journal is made of segments, each a sector sized header followed by page records.
+//
+// Journal header
+// Offset  Size  Description
+// 0       8     Header string: 0xd9, 0xd5, 0x05, 0xf9, 0x20, 0xa1, 0x63, 0xd7
+// 8       4     The "Page Count" - The number of pages in the next segment of the journal,
+//               or -1 to mean all content to the end of the file
+// 12      4     A random nonce for the checksum
+// 16      4     Initial size of the database in pages
+// 20      4     Size of a disk sector assumed by the process that wrote this journal.
+// 24      4     Size of pages in this journal.
+//
+// Page record
+// Offset  Size  Description
+// 0       4     The page number in the database file
+// 4       N     Original content of the page prior to the start of the transaction
+// N+4     4     Checksum
+//
+// A transaction that changed several attached databases ends each journal with the name
+// of a super-journal. The transaction has committed once the super-journal is deleted,
+// so a journal naming a super-journal that no longer exists isn't hot.
+//
+// Super-journal record, at the end of the journal
+// Offset  Size  Description
+// 0       4     The page number of the lock-byte page
+// 4       N     The super-journal's file name
+// N+4     4     N
+// N+8     4     Checksum, the sum of the bytes of the name
+// N+12    8     Header string
+
+use std::{
+    collections::HashMap,
+    fs,
+    io::{self, Write},
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use super::{
+    error::{bail, Context, Result},
+    storage::Storage,
+};
+
+const JOURNAL_MAGIC: [u8; 8] = [0xd9, 0xd5, 0x05, 0xf9, 0x20, 0xa1, 0x63, 0xd7];
+const JOURNAL_HEADER_SIZE: usize = 28;
+/// The sector size written into the journals made here, the header is padded to it
+const SECTOR_SIZE: usize = 512;
+
+#[derive(Debug, Clone)]
+pub struct JournalHeader {
+    /// Number of page records in the segment, None when they run to the end of the file
+    pub record_count: Option<u32>,
+    pub nonce: u32,
+    pub initial_size: u32,
+    pub sector_size: u32,
+    pub page_size: u32,
+}
+
+impl JournalHeader {
+    /// Parses a segment header, None marks the end of the journal
+    pub fn parse(buffer: &[u8]) -> Option<JournalHeader> {
+        if buffer.len() < JOURNAL_HEADER_SIZE || buffer[..8] != JOURNAL_MAGIC {
+            return None;
+        }
+        let u32_at = |offset: usize| {
+            u32::from_be_bytes([
+                buffer[offset],
+                buffer[offset + 1],
+                buffer[offset + 2],
+                buffer[offset + 3],
+            ])
+        };
+        let header = JournalHeader {
+            record_count: match u32_at(8) {
+                u32::MAX => None,
+                count => Some(count),
+            },
+            nonce: u32_at(12),
+            initial_size: u32_at(16),
+            sector_size: u32_at(20),
+            page_size: u32_at(24),
+        };
+
+        // sqlite stops playback at a header with impossible sizes
+        let valid_size = |size: u32, range: std::ops::RangeInclusive<u32>| {
+            range.contains(&size) && size.is_power_of_two()
+        };
+        if !valid_size(header.sector_size, 32..=65536)
+            || !valid_size(header.page_size, 512..=65536)
+        {
+            return None;
+        }
+        Some(header)
+    }
+}
+
+/// The name of the super-journal a journal ends with, if it has one
+fn super_journal(data: &[u8]) -> Option<String> {
+    let end = data.len().checked_sub(16)?;
+    let u32_at = |offset: usize| {
+        u32::from_be_bytes([
+            data[offset],
+            data[offset + 1],
+            data[offset + 2],
+            data[offset + 3],
+        ])
+    };
+    if data[end + 8..] != JOURNAL_MAGIC {
+        return None;
+    }
+    let name = data.get(end.checked_sub(u32_at(end) as usize)?..end)?;
+    let sum = name.iter().fold(0u32, |sum, b| sum.wrapping_add(*b as u32));
+    if name.is_empty() || sum != u32_at(end + 4) {
+        return None;
+    }
+    Some(String::from_utf8_lossy(name).into_owned())
+}
+
+// the checksum adds the nonce to every 200th byte of the page, going backwards from
+// 200 bytes before the end and stopping before the first byte
+fn checksum(nonce: u32, page: &[u8]) -> u32 {
+    (1..)
+        .map_while(|i| page.len().checked_sub(200 * i).filter(|o| *o > 0))
+        .fold(nonce, |sum, offset| sum.wrapping_add(page[offset] as u32))
+}
+
+pub struct Journal {
+    pub page_size: u32,
+    /// The database size in pages before the interrupted transaction
+    pub initial_size: u32,
+    /// The original content of each page the transaction changed
+    pages: HashMap<u32, Vec<u8>>,
+    /// The file whose deletion commits a transaction over several databases
+    super_journal: Option<String>,
+}
+
+impl Journal {
+    /// Reads the journal next to a database, returning None when there's none or its
+    /// header shows the transaction committed. Whether it's hot is up to [`Journal::is_hot`].
+    pub fn open(path: &str) -> Result<Option<Journal>> {
+        let data = match fs::read(path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+
+        // an empty journal or one whose header was zeroed belongs to a committed
+        // transaction (journal_mode TRUNCATE and PERSIST)
+        let Some(first) = JournalHeader::parse(&data) else {
+            return Ok(None);
+        };
+
+        let mut pages = HashMap::new();
+        let mut offset = 0;
+        'segments: while let Some(header) = data.get(offset..).and_then(JournalHeader::parse) {
+            if header.page_size != first.page_size {
+                break;
+            }
+            let record_size = header.page_size as usize + 8;
+            let records_start = offset + first.sector_size as usize;
+            let record_count = match header.record_count {
+                Some(count) => count as usize,
+                None => data.len().saturating_sub(records_start) / record_size,
+            };
+            if record_count == 0 {
+                break;
+            }
+
+            for i in 0..record_count {
+                let start = records_start + i * record_size;
+                let Some(record) = data.get(start..start + record_size) else {
+                    break 'segments;
+                };
+                let page_number = u32::from_be_bytes([record[0], record[1], record[2], record[3]]);
+                let page = &record[4..record_size - 4];
+                let stored = u32::from_be_bytes([
+                    record[record_size - 4],
+                    record[record_size - 3],
+                    record[record_size - 2],
+                    record[record_size - 1],
+                ]);
+                // a record that fails its checksum was never fully written
+                if page_number == 0 || checksum(header.nonce, page) != stored {
+                    break 'segments;
+                }
+                // the first copy of a page is the one from before the transaction
+                pages.entry(page_number).or_insert_with(|| page.to_vec());
+            }
+
+            // the next segment header starts on a sector boundary
+            let end = records_start + record_count * record_size;
+            let sector_size = first.sector_size as usize;
+            offset = end.div_ceil(sector_size) * sector_size;
+        }
+
+        Ok(Some(Journal {
+            page_size: first.page_size,
+            initial_size: first.initial_size,
+            pages,
+            super_journal: super_journal(&data),
+        }))
+    }
+
+    /// Whether the journal was left by a writer that died mid transaction, the checks
+    /// sqlite runs before playing a journal back. The caller must hold a SHARED lock on
+    /// the database so no writer can start meanwhile.
+    pub fn is_hot(&self, database: &dyn Storage) -> Result<bool> {
+        // a writer holding RESERVED is alive and the journal is still in use
+        if database.reserved_by_other()? || database.size()? == 0 {
+            return Ok(false);
+        }
+        match &self.super_journal {
+            Some(super_journal) => Ok(Path::new(super_journal).exists()),
+            None => Ok(true),
+        }
+    }
+
+    pub fn page(&self, page_number: u32) -> Option<&[u8]> {
+        self.pages.get(&page_number).map(|p| p.as_slice())
+    }
+
+    /// Writes the original pages back into the database, truncates it to its size before
+    /// the transaction and deletes the journal, the same recovery sqlite runs. The caller
+    /// must hold an EXCLUSIVE lock on the database.
+    pub fn rollback(&self, database: &dyn Storage, journal_path: &str) -> Result<()> {
+        if !database.writable() {
+            bail!(
+                Unsupported,
+                "database must be writable to roll back a hot journal"
+            );
+        }
+        for (page_number, page) in &self.pages {
+            database.write_at((*page_number as u64 - 1) * self.page_size as u64, page)?;
+        }
+        database.truncate(self.initial_size as u64 * self.page_size as u64)?;
+        database.sync()?;
+        fs::remove_file(journal_path)?;
+        Ok(())
+    }
+
+    /// Writes a journal holding the original content of the pages a transaction is about
+    /// to change. Once it is on disk the database file can be written in place, a crash
+    /// before `commit` deletes it leaves it hot and the next open rolls the changes back.
+    pub fn write(
+        path: &str,
+        page_size: u32,
+        initial_size: u32,
+        originals: &[(u32, &[u8])],
+    ) -> Result<()> {
+        // the nonce only has to differ between journals, not be unpredictable
+        let nonce = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.subsec_nanos());
+        let mut data = vec![0; SECTOR_SIZE];
+        data[..8].copy_from_slice(&JOURNAL_MAGIC);
+        data[8..12].copy_from_slice(&(originals.len() as u32).to_be_bytes());
+        data[12..16].copy_from_slice(&nonce.to_be_bytes());
+        data[16..20].copy_from_slice(&initial_size.to_be_bytes());
+        data[20..24].copy_from_slice(&(SECTOR_SIZE as u32).to_be_bytes());
+        data[24..28].copy_from_slice(&page_size.to_be_bytes());
+        for (page_number, page) in originals {
+            data.extend_from_slice(&page_number.to_be_bytes());
+            data.extend_from_slice(page);
+            data.extend_from_slice(&checksum(nonce, page).to_be_bytes());
+        }
+
+        // an existing journal may still be needed to roll back another transaction
+        let mut file = fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(path)
+            .context("failed to create the journal")?;
+        file.write_all(&data)?;
+        file.sync_all()?;
+        Ok(())
+    }
+
+    /// Deletes the journal of a transaction whose pages have all reached the database
+    /// file, which is the moment the transaction commits
+    pub fn commit(path: &str) -> Result<()> {
+        fs::remove_file(path).context("failed to delete the journal")?;
+        Ok(())
+    }
+
+    /// Deletes a journal that isn't hot, if there is one
+    pub fn discard(path: &str) -> Result<()> {
+        match fs::remove_file(path) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            deleted => deleted.context("failed to delete the journal"),
+        }
+    }
+}
@@ -0,0 +1,140 @@
+// https://www.sqlite.org/lockingv3.html
+//
+// sqlite coordinates the processes using a database with POSIX advisory locks on a few
+// bytes of the database file past the 1 GiB mark, a range that is never used for data:
+//
+// PENDING_BYTE   0x40000000  held while a writer waits for readers to leave
+// RESERVED_BYTE  0x40000001  held by the one connection that is preparing a transaction
+// SHARED range   0x40000002  510 bytes, read locked by readers and write locked by the
+//                            writer that holds EXCLUSIVE
+//
+// Writes take the same locks, so a sqlite connection never reads pages this crate is
+// halfway through writing and this crate never writes pages a sqlite connection is still
+// reading. Reads only lock while the database is opened: a statement running while
+// another process commits can see a mix of old and new pages, and pages cached before
+// that commit are only dropped once the next transaction begins and finds the file
+// change counter moved. The locks belong to the process, so they don't keep two
+// connections of one process apart, and closing any descriptor of the file releases all
+// of them.
+
+use std::fs::File;
+
+use super::error::{bail, Result};
+
+const PENDING_BYTE: i64 = 0x40000000;
+const RESERVED_BYTE: i64 = PENDING_BYTE + 1;
+const SHARED_FIRST: i64 = PENDING_BYTE + 2;
+const SHARED_SIZE: i64 = 510;
+
+/// The locks a connection can hold on a database file, each stronger than the last
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LockLevel {
+    None,
+    /// The database can be read and nobody writes to it
+    Shared,
+    /// A transaction is being prepared, other connections can still read
+    Reserved,
+    /// The database file is being written to and nobody reads it
+    Exclusive,
+}
+
+/// Moves the locks held on the file from one level to another, failing with Busy when
+/// another process holds a lock that conflicts with the new level
+#[cfg(unix)]
+pub fn set_level(file: &File, from: LockLevel, to: LockLevel) -> Result<()> {
+    if to <= from {
+        return release(file, from, to);
+    }
+    let locked = acquire(file, from, to);
+    if locked.is_err() {
+        // drop whatever part of the new level was taken
+        release(file, to, from)?;
+    }
+    locked
+}
+
+#[cfg(unix)]
+fn acquire(file: &File, from: LockLevel, to: LockLevel) -> Result<()> {
+    if from == LockLevel::None {
+        // PENDING is read locked while SHARED is taken so a writer waiting for readers to
+        // leave doesn't see new ones arrive
+        fcntl_lock(file, libc::F_RDLCK, PENDING_BYTE, 1)?;
+        let shared = fcntl_lock(file, libc::F_RDLCK, SHARED_FIRST, SHARED_SIZE);
+        fcntl_lock(file, libc::F_UNLCK, PENDING_BYTE, 1)?;
+        shared?;
+    }
+    // a hot journal is rolled back by going from SHARED straight to EXCLUSIVE, passing
+    // over RESERVED like sqlite does
+    if to == LockLevel::Reserved {
+        fcntl_lock(file, libc::F_WRLCK, RESERVED_BYTE, 1)?;
+    }
+    if to == LockLevel::Exclusive {
+        fcntl_lock(file, libc::F_WRLCK, PENDING_BYTE, 1)?;
+        fcntl_lock(file, libc::F_WRLCK, SHARED_FIRST, SHARED_SIZE)?;
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn release(file: &File, from: LockLevel, to: LockLevel) -> Result<()> {
+    if from == LockLevel::Exclusive && to != LockLevel::None {
+        fcntl_lock(file, libc::F_RDLCK, SHARED_FIRST, SHARED_SIZE)?;
+    }
+    match to {
+        LockLevel::None => fcntl_lock(file, libc::F_UNLCK, PENDING_BYTE, 2 + SHARED_SIZE),
+        LockLevel::Shared => fcntl_lock(file, libc::F_UNLCK, PENDING_BYTE, 2),
+        LockLevel::Reserved => fcntl_lock(file, libc::F_UNLCK, PENDING_BYTE, 1),
+        LockLevel::Exclusive => Ok(()),
+    }
+}
+
+/// Whether another process holds RESERVED or a stronger lock, meaning a writer is in
+/// the middle of a transaction
+#[cfg(unix)]
+pub fn reserved_by_other(file: &File) -> Result<bool> {
+    use std::os::unix::io::AsRawFd;
+
+    let mut lock = flock(libc::F_WRLCK, RESERVED_BYTE, 1);
+    if unsafe { libc::fcntl(file.as_raw_fd(), libc::F_GETLK, &mut lock) } != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(lock.l_type != libc::F_UNLCK as libc::c_short)
+}
+
+#[cfg(unix)]
+fn flock(lock_type: libc::c_int, start: i64, len: i64) -> libc::flock {
+    // flock has platform specific padding, only the fields sqlite sets are filled in
+    let mut lock: libc::flock = unsafe { std::mem::zeroed() };
+    lock.l_type = lock_type as libc::c_short;
+    lock.l_whence = libc::SEEK_SET as libc::c_short;
+    lock.l_start = start as libc::off_t;
+    lock.l_len = len as libc::off_t;
+    lock
+}
+
+#[cfg(unix)]
+fn fcntl_lock(file: &File, lock_type: libc::c_int, start: i64, len: i64) -> Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let lock = flock(lock_type, start, len);
+    if unsafe { libc::fcntl(file.as_raw_fd(), libc::F_SETLK, &lock) } == 0 {
+        return Ok(());
+    }
+    let error = std::io::Error::last_os_error();
+    match error.raw_os_error() {
+        Some(libc::EAGAIN | libc::EACCES) => bail!(Busy, "database is locked"),
+        _ => Err(error.into()),
+    }
+}
+
+// sqlite locks with LockFileEx on windows, which isn't implemented here. Without locks
+// a database can only be shared safely with connections that only read.
+#[cfg(not(unix))]
+pub fn set_level(_file: &File, _from: LockLevel, _to: LockLevel) -> Result<()> {
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn reserved_by_other(_file: &File) -> Result<bool> {
+    Ok(false)
+}
@@ -3,6 +3,7 @@ use self::{
     table_interior::TableInteriorPage, table_leaf::TableLeafPage,
 };
 
+pub mod btree_page;
 pub mod index_interior;
 pub mod index_leaf;
 pub mod page_header;
@@ -0,0 +1,467 @@
+// https://www.sqlite.org/fileformat2.html#b_tree_pages
+//
+// A b-tree page as raw bytes, for changing it in place. The page header is followed by
+// the cell pointer array which grows towards the end of the page, while cells are added
+// to the cell content area from the end of the page backwards. The gap between the two
+// is free space, as are freeblocks inside the content area (chained through their first
+// two bytes, with their size in the next two) and fragments of less than 4 bytes.
+
+use crate::sqlite::{
+    database::{Database, PayloadKind, Varint},
+    error::{bail, Result},
+    record::codec,
+};
+
+use super::page_header::PageType;
+
+/// Page 1 starts with the 100 byte database header, other pages with the page header
+pub fn header_offset(page_number: u32) -> usize {
+    match page_number {
+        1 => 100,
+        _ => 0,
+    }
+}
+
+fn header_size(page_type: &PageType) -> usize {
+    match page_type {
+        PageType::TableLeaf | PageType::IndexLeaf => 8,
+        PageType::TableInterior | PageType::IndexInterior => 12,
+    }
+}
+
+/// The bytes an empty page of the type has for cells and their pointers, on any page
+/// but page 1
+pub fn capacity(db: &Database, page_type: &PageType) -> usize {
+    db.header.usable_size() as usize - header_size(page_type)
+}
+
+/// The first overflow page of a payload and the number of its bytes stored on them
+pub type Overflow = (u32, usize);
+
+pub struct BTreePage<T> {
+    pub page_number: u32,
+    page_type: PageType,
+    data: T,
+    /// Where the page header starts, page 1 begins with the database header
+    header_offset: usize,
+    /// The page size less the reserved bytes at its end
+    usable_size: usize,
+}
+
+impl<T: AsRef<[u8]>> BTreePage<T> {
+    pub fn new(db: &Database, page_number: u32, data: T) -> Result<BTreePage<T>> {
+        let header_offset = header_offset(page_number);
+        let page_type = match data.as_ref().get(header_offset) {
+            Some(0x02) => PageType::IndexInterior,
+            Some(0x05) => PageType::TableInterior,
+            Some(0x0a) => PageType::IndexLeaf,
+            Some(0x0d) => PageType::TableLeaf,
+            _ => bail!(Corrupt, "page {} is not a b-tree page", page_number),
+        };
+        let page = BTreePage {
+            page_number,
+            page_type,
+            data,
+            header_offset,
+            usable_size: db.header.usable_size() as usize,
+        };
+        if page.pointers_end() > page.content_start() || page.content_start() > page.usable_size {
+            bail!(Corrupt, "page {} has an invalid header", page_number);
+        }
+        Ok(page)
+    }
+
+    pub fn page_type(&self) -> &PageType {
+        &self.page_type
+    }
+
+    pub fn is_leaf(&self) -> bool {
+        matches!(self.page_type, PageType::TableLeaf | PageType::IndexLeaf)
+    }
+
+    fn u16_at(&self, offset: usize) -> usize {
+        let data = self.data.as_ref();
+        u16::from_be_bytes([data[offset], data[offset + 1]]) as usize
+    }
+
+    fn u32_at(&self, offset: usize) -> u32 {
+        let data = self.data.as_ref();
+        u32::from_be_bytes([
+            data[offset],
+            data[offset + 1],
+            data[offset + 2],
+            data[offset + 3],
+        ])
+    }
+
+    fn header_size(&self) -> usize {
+        header_size(&self.page_type)
+    }
+
+    pub fn cell_count(&self) -> usize {
+        self.u16_at(self.header_offset + 3)
+    }
+
+    /// The start of the cell content area, a zero in the header means 65536
+    fn content_start(&self) -> usize {
+        match self.u16_at(self.header_offset + 5) {
+            0 => 65536,
+            start => start,
+        }
+    }
+
+    /// The end of the cell pointer array
+    fn pointers_end(&self) -> usize {
+        self.header_offset + self.header_size() + 2 * self.cell_count()
+    }
+
+    pub fn right_child(&self) -> u32 {
+        self.u32_at(self.header_offset + 8)
+    }
+
+    /// The offset of a cell from the start of the page
+    pub fn cell_offset(&self, index: usize) -> Result<usize> {
+        if index >= self.cell_count() {
+            bail!(Corrupt, "page {} has no cell {}", self.page_number, index);
+        }
+        let offset = self.u16_at(self.header_offset + self.header_size() + 2 * index);
+        if offset < self.pointers_end() || offset >= self.usable_size {
+            bail!(
+                Corrupt,
+                "cell {} of page {} is out of bounds",
+                index,
+                self.page_number
+            );
+        }
+        Ok(offset)
+    }
+
+    pub fn cell(&self, db: &Database, index: usize) -> Result<&[u8]> {
+        let offset = self.cell_offset(index)?;
+        let size = self.cell_size(db, offset)?;
+        Ok(&self.data.as_ref()[offset..offset + size])
+    }
+
+    /// The left child of a cell on an interior page
+    pub fn left_child(&self, index: usize) -> Result<u32> {
+        if self.is_leaf() {
+            bail!(Corrupt, "leaf page {} has no child pages", self.page_number);
+        }
+        Ok(self.u32_at(self.cell_offset(index)?))
+    }
+
+    /// The rowid of a cell on a table page
+    pub fn cell_row_id(&self, index: usize) -> Result<i64> {
+        let offset = self.cell_offset(index)?;
+        let row_id = match self.page_type {
+            PageType::TableInterior => self.varint_at(offset + 4)?,
+            PageType::TableLeaf => {
+                let payload_size = self.varint_at(offset)?;
+                self.varint_at(offset + payload_size.size as usize)?
+            }
+            _ => bail!(Corrupt, "index page {} has no rowids", self.page_number),
+        };
+        Ok(row_id.value)
+    }
+
+    fn varint_at(&self, offset: usize) -> Result<Varint> {
+        match self.data.as_ref()[..self.usable_size].get(offset..) {
+            Some(bytes) => codec::decode_varint(bytes),
+            None => bail!(
+                Corrupt,
+                "cell on page {} is out of bounds",
+                self.page_number
+            ),
+        }
+    }
+
+    /// Where the payload of the cell at the offset starts, its size and how many bytes of
+    /// it are stored on the page
+    fn payload_layout(&self, db: &Database, offset: usize) -> Result<(usize, usize, usize)> {
+        let (header_size, payload_size, kind) = match self.page_type {
+            PageType::TableInterior => {
+                bail!(
+                    Corrupt,
+                    "cells of page {} have no payload",
+                    self.page_number
+                )
+            }
+            PageType::TableLeaf => {
+                let payload_size = self.varint_at(offset)?;
+                let row_id = self.varint_at(offset + payload_size.size as usize)?;
+                let header_size = (payload_size.size + row_id.size) as usize;
+                (header_size, payload_size.value, PayloadKind::Table)
+            }
+            PageType::IndexLeaf => {
+                let payload_size = self.varint_at(offset)?;
+                (
+                    payload_size.size as usize,
+                    payload_size.value,
+                    PayloadKind::Index,
+                )
+            }
+            PageType::IndexInterior => {
+                let payload_size = self.varint_at(offset + 4)?;
+                (
+                    4 + payload_size.size as usize,
+                    payload_size.value,
+                    PayloadKind::Index,
+                )
+            }
+        };
+        if payload_size < 0 {
+            bail!(Corrupt, "invalid payload size {}", payload_size);
+        }
+        let local = db.local_payload_size(payload_size as usize, kind);
+        Ok((offset + header_size, payload_size as usize, local))
+    }
+
+    /// The number of bytes the cell at the offset takes up on the page
+    fn cell_size(&self, db: &Database, offset: usize) -> Result<usize> {
+        if let PageType::TableInterior = self.page_type {
+            return Ok(4 + self.varint_at(offset + 4)?.size as usize);
+        }
+        let (start, payload_size, local) = self.payload_layout(db, offset)?;
+        let overflow = match local < payload_size {
+            true => 4,
+            false => 0,
+        };
+        let size = (start - offset + local + overflow).max(4);
+        if offset + size > self.usable_size {
+            bail!(
+                Corrupt,
+                "cell on page {} is out of bounds",
+                self.page_number
+            );
+        }
+        Ok(size)
+    }
+
+    /// The part of a cell's payload stored on the page, and when the rest of it is on
+    /// overflow pages the first of them and the number of bytes they hold
+    pub fn cell_payload(&self, db: &Database, index: usize) -> Result<(&[u8], Option<Overflow>)> {
+        let offset = self.cell_offset(index)?;
+        self.cell_size(db, offset)?;
+        let (start, payload_size, local) = self.payload_layout(db, offset)?;
+        let overflow =
+            (local < payload_size).then(|| (self.u32_at(start + local), payload_size - local));
+        Ok((&self.data.as_ref()[start..start + local], overflow))
+    }
+
+    /// The space between the cell pointer array and the cell content area
+    fn gap(&self) -> usize {
+        self.content_start() - self.pointers_end()
+    }
+
+    /// All the unused bytes on the page, whether they are together or not
+    pub fn free_space(&self) -> Result<usize> {
+        let fragmented = self.data.as_ref()[self.header_offset + 7] as usize;
+        let mut free = self.gap() + fragmented;
+        let mut freeblock = self.u16_at(self.header_offset + 1);
+        while freeblock != 0 {
+            // freeblocks are chained in order of their offset
+            if freeblock < self.content_start() || freeblock + 4 > self.usable_size {
+                bail!(
+                    Corrupt,
+                    "freeblock on page {} is out of bounds",
+                    self.page_number
+                );
+            }
+            free += self.u16_at(freeblock + 2);
+            let next = self.u16_at(freeblock);
+            if next != 0 && next <= freeblock {
+                bail!(
+                    Corrupt,
+                    "freeblocks on page {} are out of order",
+                    self.page_number
+                );
+            }
+            freeblock = next;
+        }
+        Ok(free)
+    }
+}
+
+impl<T: AsRef<[u8]> + AsMut<[u8]>> BTreePage<T> {
+    /// Clears a page to an empty b-tree page of the type, leaving the database header
+    /// at the start of page 1 as it is
+    pub fn init(
+        db: &Database,
+        page_number: u32,
+        mut data: T,
+        page_type: PageType,
+    ) -> Result<BTreePage<T>> {
+        let header_offset = header_offset(page_number);
+        let usable_size = db.header.usable_size() as usize;
+        let bytes = data.as_mut();
+        if bytes.len() < usable_size {
+            bail!(Corrupt, "page {} is shorter than a page", page_number);
+        }
+        bytes[header_offset..usable_size].fill(0);
+        bytes[header_offset] = page_type.flag();
+        // the cell content area starts out empty at the end of the page
+        let content_start = (usable_size % 65536) as u16;
+        bytes[header_offset + 5..header_offset + 7].copy_from_slice(&content_start.to_be_bytes());
+        BTreePage::new(db, page_number, data)
+    }
+
+    fn set_u16(&mut self, offset: usize, value: usize) {
+        self.data.as_mut()[offset..offset + 2].copy_from_slice(&(value as u16).to_be_bytes());
+    }
+
+    fn set_cell_count(&mut self, count: usize) {
+        self.set_u16(self.header_offset + 3, count);
+    }
+
+    fn set_content_start(&mut self, start: usize) {
+        // 65536 doesn't fit in the two bytes and is written as 0
+        self.set_u16(self.header_offset + 5, start % 65536);
+    }
+
+    pub fn set_right_child(&mut self, page_number: u32) {
+        let offset = self.header_offset + 8;
+        self.data.as_mut()[offset..offset + 4].copy_from_slice(&page_number.to_be_bytes());
+    }
+
+    /// Adds a cell at a position of the cell pointer array, moving the cells after it up.
+    /// Returns false without changing the page when the cell doesn't fit on it.
+    pub fn insert_cell(&mut self, db: &Database, index: usize, cell: &[u8]) -> Result<bool> {
+        if index > self.cell_count() {
+            bail!(Corrupt, "page {} has no cell {}", self.page_number, index);
+        }
+        // a cell needs a pointer as well, and is never smaller than 4 bytes
+        let needed = cell.len().max(4) + 2;
+        if self.gap() < needed {
+            if self.free_space()? < needed {
+                return Ok(false);
+            }
+            self.defragment(db)?;
+        }
+
+        let start = self.content_start() - cell.len().max(4);
+        self.data.as_mut()[start..start + cell.len()].copy_from_slice(cell);
+        let pointer = self.header_offset + self.header_size() + 2 * index;
+        let end = self.pointers_end();
+        self.data.as_mut().copy_within(pointer..end, pointer + 2);
+        self.set_u16(pointer, start);
+        self.set_cell_count(self.cell_count() + 1);
+        self.set_content_start(start);
+        Ok(true)
+    }
+
+    /// Takes a cell off the page, moving the cells after it in the cell pointer array down
+    pub fn remove_cell(&mut self, db: &Database, index: usize) -> Result<()> {
+        let offset = self.cell_offset(index)?;
+        let size = self.cell_size(db, offset)?;
+        let pointer = self.header_offset + self.header_size() + 2 * index;
+        let end = self.pointers_end();
+        self.data.as_mut().copy_within(pointer + 2..end, pointer);
+        self.set_cell_count(self.cell_count() - 1);
+        if self.cell_count() == 0 {
+            // an empty page starts over with all of its space in one piece
+            self.set_u16(self.header_offset + 1, 0);
+            self.data.as_mut()[self.header_offset + 7] = 0;
+            self.set_content_start(self.usable_size);
+            return Ok(());
+        }
+        self.free_block(offset, size)
+    }
+
+    /// Returns the bytes of a removed cell to the free space, as part of the gap when they
+    /// are at the start of the cell content area and as a freeblock otherwise. Like
+    /// sqlite it joins the freeblock with the ones next to it, along with fragments of
+    /// less than 4 bytes between them.
+    fn free_block(&mut self, start: usize, size: usize) -> Result<()> {
+        let first = self.header_offset + 1;
+        // the pointer to the first freeblock after the block, in the header or in the
+        // freeblock before it
+        let mut previous = first;
+        let mut next = self.u16_at(first);
+        while next != 0 && next < start {
+            if next + 4 > self.usable_size || self.u16_at(next) <= next && self.u16_at(next) != 0 {
+                bail!(
+                    Corrupt,
+                    "freeblocks on page {} are out of order",
+                    self.page_number
+                );
+            }
+            previous = next;
+            next = self.u16_at(next);
+        }
+
+        let (mut start, mut end) = (start, start + size);
+        let mut fragments = 0;
+        if next != 0 && end + 3 >= next {
+            if next < end || next + 4 > self.usable_size {
+                bail!(
+                    Corrupt,
+                    "freeblock on page {} overlaps a cell",
+                    self.page_number
+                );
+            }
+            fragments = next - end;
+            end = next + self.u16_at(next + 2);
+            next = self.u16_at(next);
+        }
+        if previous != first {
+            let previous_end = previous + self.u16_at(previous + 2);
+            if previous_end > start {
+                bail!(
+                    Corrupt,
+                    "freeblock on page {} overlaps a cell",
+                    self.page_number
+                );
+            }
+            if previous_end + 3 >= start {
+                fragments += start - previous_end;
+                start = previous;
+            }
+        }
+        let fragmented = self.data.as_ref()[self.header_offset + 7] as usize;
+        if fragments > fragmented || end > self.usable_size {
+            bail!(
+                Corrupt,
+                "free space on page {} is miscounted",
+                self.page_number
+            );
+        }
+        self.data.as_mut()[self.header_offset + 7] = (fragmented - fragments) as u8;
+
+        if start <= self.content_start() {
+            if start < self.content_start() || previous != first {
+                bail!(
+                    Corrupt,
+                    "cell on page {} is outside the cell content area",
+                    self.page_number
+                );
+            }
+            self.set_u16(first, next);
+            self.set_content_start(end);
+        } else {
+            self.set_u16(previous, start);
+            self.set_u16(start, next);
+            self.set_u16(start + 2, end - start);
+        }
+        Ok(())
+    }
+
+    /// Moves the cells to the end of the page so all free space is in one piece between
+    /// them and the cell pointer array, leaving no freeblocks or fragments
+    pub fn defragment(&mut self, db: &Database) -> Result<()> {
+        let cells = (0..self.cell_count())
+            .map(|i| self.cell(db, i).map(<[u8]>::to_vec))
+            .collect::<Result<Vec<_>>>()?;
+        let pointers_end = self.pointers_end();
+        let mut start = self.usable_size;
+        self.data.as_mut()[pointers_end..start].fill(0);
+        for (i, cell) in cells.iter().enumerate() {
+            start -= cell.len();
+            self.data.as_mut()[start..start + cell.len()].copy_from_slice(cell);
+            self.set_u16(self.header_offset + self.header_size() + 2 * i, start);
+        }
+        self.set_u16(self.header_offset + 1, 0);
+        self.data.as_mut()[self.header_offset + 7] = 0;
+        self.set_content_start(start);
+        Ok(())
+    }
+}
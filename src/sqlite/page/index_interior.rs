@@ -1,5 +1,4 @@
-use crate::sqlite::{database::Database, record::CellValue};
-use anyhow::Result;
+use crate::sqlite::{database::Database, error::Result, record::CellValue};
 
 use super::page_header::PageHeader;
 
@@ -26,14 +25,14 @@ impl IndexInteriorPage {
 
 impl IndexInteriorCell {
     pub fn read_cell(page_number: u32, offset: u16, db: &Database) -> Result<Self> {
-        db.seek(page_number, offset)?;
-
-        let left_child = db.read_u32()?;
+        let left_child = db.reader(page_number, offset)?.read_u32()?;
         let record = db.read_index_record(page_number, offset + 4)?;
         let key = db.read_record_cell(&record, 0)?;
         Ok(IndexInteriorCell {
             left_child_page_number: left_child,
             value: key,
+            page_number,
+            record_pointer: offset + 4,
         })
     }
 }
@@ -42,4 +41,7 @@ impl IndexInteriorCell {
 pub struct IndexInteriorCell {
     pub left_child_page_number: u32,
     pub value: CellValue,
+    // unlike table interior cells every index interior cell holds a full index entry
+    pub page_number: u32,
+    pub record_pointer: u16,
 }
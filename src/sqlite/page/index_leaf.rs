@@ -6,6 +6,7 @@ use super::page_header::PageHeader;
 pub struct IndexLeafPage {
     pub page_number: u32,
     pub header: PageHeader,
-    pub value: CellValue,
+    /// The key of the divider cell above the leaf, when it's read through its parent
+    pub value: Option<CellValue>,
     pub cell_pointers: Vec<(u32, u16)>,
 }
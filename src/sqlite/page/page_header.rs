@@ -14,3 +14,23 @@ pub enum PageType {
     IndexLeaf,
     IndexInterior,
 }
+
+impl PageType {
+    /// The first byte of the page header, which tells the page types apart
+    pub fn flag(&self) -> u8 {
+        match self {
+            PageType::IndexInterior => 0x02,
+            PageType::TableInterior => 0x05,
+            PageType::IndexLeaf => 0x0a,
+            PageType::TableLeaf => 0x0d,
+        }
+    }
+
+    /// The type of the pages above pages of this type in the same b-tree
+    pub fn interior(&self) -> PageType {
+        match self {
+            PageType::TableLeaf | PageType::TableInterior => PageType::TableInterior,
+            PageType::IndexLeaf | PageType::IndexInterior => PageType::IndexInterior,
+        }
+    }
+}
@@ -1,7 +1,7 @@
 use crate::sqlite::database::Database;
 
 use super::page_header::PageHeader;
-use anyhow::Result;
+use crate::sqlite::error::Result;
 use itertools::Itertools;
 
 #[derive(Debug, Clone)]
@@ -24,18 +24,16 @@ impl TableInteriorPage {
             .map(|&offset| TableInteriorCell::read_cell(offset.0, offset.1, db))
             .try_collect()?;
 
-        assert_eq!(cells.len(), cell_pointers.len());
-        Ok(cells.into_iter().sorted_by_key(|f| f.row_id).collect_vec()) 
-
+        Ok(cells.into_iter().sorted_by_key(|f| f.row_id).collect_vec())
     }
 }
 
 impl TableInteriorCell {
     pub fn read_cell(page_number: u32, offset: u16, db: &Database) -> Result<Self> {
-        db.seek(page_number, offset)?;
+        let mut reader = db.reader(page_number, offset)?;
 
-        let left_child = db.read_u32()?;
-        let row_id = db.read_varint()?.value;
+        let left_child = reader.read_u32()?;
+        let row_id = reader.read_varint()?.value;
 
         Ok(TableInteriorCell {
             left_child_page_number: left_child,
@@ -0,0 +1,97 @@
+// Whole pages are read once and kept in memory, parsing then works on these buffers
+// instead of going back to the file for every varint and header field. When the cache
+// is full the least recently used page makes room.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Arc,
+};
+
+/// The number of pages kept when no size is configured
+pub const DEFAULT_CACHE_SIZE: usize = 2000;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+pub struct PageCache {
+    capacity: usize,
+    /// Each cached page with the tick of its last use
+    pages: HashMap<u32, (Arc<[u8]>, u64)>,
+    /// Pages by their last use, the first one is evicted next
+    recency: BTreeMap<u64, u32>,
+    clock: u64,
+    stats: CacheStats,
+}
+
+impl PageCache {
+    pub fn new(capacity: usize) -> PageCache {
+        PageCache {
+            capacity,
+            pages: HashMap::new(),
+            recency: BTreeMap::new(),
+            clock: 0,
+            stats: CacheStats::default(),
+        }
+    }
+
+    /// Looks up a page, counting the lookup as a hit or a miss
+    pub fn get(&mut self, page_number: u32) -> Option<Arc<[u8]>> {
+        self.clock += 1;
+        let Some((page, last_used)) = self.pages.get_mut(&page_number) else {
+            self.stats.misses += 1;
+            return None;
+        };
+        self.stats.hits += 1;
+        self.recency.remove(last_used);
+        self.recency.insert(self.clock, page_number);
+        *last_used = self.clock;
+        Some(page.clone())
+    }
+
+    pub fn insert(&mut self, page_number: u32, page: Arc<[u8]>) {
+        if self.capacity == 0 {
+            return;
+        }
+        self.clock += 1;
+        if let Some((_, last_used)) = self.pages.insert(page_number, (page, self.clock)) {
+            self.recency.remove(&last_used);
+        }
+        self.recency.insert(self.clock, page_number);
+        self.evict();
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Changes the number of pages kept, dropping the least recently used ones that no
+    /// longer fit
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        self.evict();
+    }
+
+    pub fn len(&self) -> usize {
+        self.pages.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pages.is_empty()
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.stats
+    }
+
+    fn evict(&mut self) {
+        while self.pages.len() > self.capacity {
+            let Some((_, page_number)) = self.recency.pop_first() else {
+                break;
+            };
+            self.pages.remove(&page_number);
+        }
+    }
+}
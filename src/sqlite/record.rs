@@ -1,14 +1,15 @@
-use std::fmt;
+use std::{cmp::Ordering, fmt};
 
-use super::database::Position;
+pub mod codec;
 
 #[derive(Debug, Default)]
 pub struct Record {
     pub row_id: i64,
     pub record_header: RecordHeader,
     pub page_number: u32,
-    pointer: u16,
-    cell_header_size: i64,
+    pub pointer: u16,
+    // the full payload, local bytes plus anything read from overflow pages
+    payload: Vec<u8>,
 }
 
 #[derive(Debug, Default)]
@@ -32,37 +33,31 @@ impl Record {
         record_header: RecordHeader,
         page_number: u32,
         pointer: u16,
-        cell_header_size: i64,
+        payload: Vec<u8>,
     ) -> Self {
         Self {
             row_id,
             record_header,
             page_number,
             pointer,
-            cell_header_size,
+            payload,
         }
     }
 
-    pub fn get_cell_position(&self, cell_index: usize) -> Position {
-        let offset: i64 = self
+    pub fn payload(&self) -> &[u8] {
+        &self.payload
+    }
+
+    /// Offset of the cell's value from the start of the record payload
+    pub fn get_cell_position(&self, cell_index: usize) -> usize {
+        let offset: usize = self
             .record_header
             .headers
             .iter()
             .take(cell_index)
-            .map(|f| match f {
-                CellType::Null => 0_i64,
-                CellType::Float64 => 8_i64,
-                CellType::Blob(s) => *s as i64,
-                CellType::String(s) => *s as i64,
-                CellType::Varint(s) => *s as i64,
-            })
+            .map(|f| f.size())
             .sum();
-        let pointer =
-            self.pointer as i64 + self.cell_header_size + self.record_header.header_size + offset;
-        Position::Absolute {
-            page_number: self.page_number,
-            pointer: pointer as u16,
-        }
+        self.record_header.header_size as usize + offset
     }
 }
 
@@ -86,11 +81,64 @@ pub enum CellValue {
     String(String),
 }
 
-#[derive(Debug)]
+impl CellValue {
+    // https://www.sqlite.org/datatype3.html#sort_order
+    //
+    // NULLs sort first, then INTEGER and REAL values compared numerically, then TEXT
+    // and finally BLOBs, both compared with memcmp
+    pub fn sqlite_cmp(&self, other: &CellValue) -> Ordering {
+        let class = |value: &CellValue| match value {
+            CellValue::Null => 0,
+            CellValue::Int(_) | CellValue::Float(_) => 1,
+            CellValue::String(_) => 2,
+            CellValue::Blob(_) => 3,
+        };
+        match (self, other) {
+            (CellValue::Int(l), CellValue::Int(r)) => l.cmp(r),
+            (CellValue::Int(l), CellValue::Float(r)) => (*l as f64).total_cmp(r),
+            (CellValue::Float(l), CellValue::Int(r)) => l.total_cmp(&(*r as f64)),
+            (CellValue::Float(l), CellValue::Float(r)) => l.total_cmp(r),
+            (CellValue::String(l), CellValue::String(r)) => l.as_bytes().cmp(r.as_bytes()),
+            (CellValue::Blob(l), CellValue::Blob(r)) => l.cmp(r),
+            (l, r) => class(l).cmp(&class(r)),
+        }
+    }
+
+    // https://www.sqlite.org/datatype3.html#collation
+    pub fn collate_cmp(&self, other: &CellValue, collation: Option<&str>) -> Ordering {
+        match (self, other, collation) {
+            (CellValue::String(l), CellValue::String(r), Some(c))
+                if c.eq_ignore_ascii_case("NOCASE") =>
+            {
+                l.to_ascii_lowercase().cmp(&r.to_ascii_lowercase())
+            }
+            (CellValue::String(l), CellValue::String(r), Some(c))
+                if c.eq_ignore_ascii_case("RTRIM") =>
+            {
+                l.trim_end_matches(' ').cmp(r.trim_end_matches(' '))
+            }
+            _ => self.sqlite_cmp(other),
+        }
+    }
+}
+
+/// Whether values can be compared with the collation, the built in ones are
+pub fn known_collation(collation: Option<&str>) -> bool {
+    collation.is_none_or(|c| {
+        ["BINARY", "NOCASE", "RTRIM"]
+            .iter()
+            .any(|k| c.eq_ignore_ascii_case(k))
+    })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum CellType {
     Null,
+    // serial types 8 and 9 are the integers 0 and 1 with no payload
+    Constant(i64),
+    // a big-endian twos-complement integer of 1, 2, 3, 4, 6 or 8 bytes
     Varint(u8),
     Float64,
-    Blob(isize),
-    String(isize),
+    Blob(usize),
+    String(usize),
 }
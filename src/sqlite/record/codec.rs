@@ -0,0 +1,238 @@
+// https://www.sqlite.org/fileformat2.html#record_format
+//
+// A record is a header followed by a body. The header starts with a varint holding
+// the size of the header (including that varint) followed by one serial type varint
+// per column, the body holds the values back to back in the same order.
+//
+// Serial Type   Content Size  Meaning
+// 0             0             Value is a NULL.
+// 1             1             Value is an 8-bit twos-complement integer.
+// 2             2             Value is a big-endian 16-bit twos-complement integer.
+// 3             3             Value is a big-endian 24-bit twos-complement integer.
+// 4             4             Value is a big-endian 32-bit twos-complement integer.
+// 5             6             Value is a big-endian 48-bit twos-complement integer.
+// 6             8             Value is a big-endian 64-bit twos-complement integer.
+// 7             8             Value is a big-endian IEEE 754-2008 64-bit floating point number.
+// 8             0             Value is the integer 0. (Only available for schema format 4 and higher.)
+// 9             0             Value is the integer 1. (Only available for schema format 4 and higher.)
+// 10,11         variable      Reserved for internal use, never found in well-formed files.
+// N≥12 and even (N-12)/2      Value is a BLOB that is (N-12)/2 bytes in length.
+// N≥13 and odd  (N-13)/2      Value is a string in the text encoding and (N-13)/2 bytes in length.
+
+use crate::sqlite::{
+    connection::TextEncoding,
+    database::Varint,
+    error::{bail, Result},
+};
+
+use super::{CellType, CellValue, RecordHeader};
+
+impl CellType {
+    pub fn from_serial_type(serial_type: i64) -> Result<CellType> {
+        Ok(match serial_type {
+            0 => CellType::Null,
+            1 => CellType::Varint(1),
+            2 => CellType::Varint(2),
+            3 => CellType::Varint(3),
+            4 => CellType::Varint(4),
+            5 => CellType::Varint(6),
+            6 => CellType::Varint(8),
+            7 => CellType::Float64,
+            8 => CellType::Constant(0),
+            9 => CellType::Constant(1),
+            10 | 11 => bail!(
+                Corrupt,
+                "serial type {} is reserved for internal use",
+                serial_type
+            ),
+            code if code < 0 => bail!(Corrupt, "invalid serial type {}", code),
+            code if code % 2 == 0 => CellType::Blob(((code - 12) / 2) as usize),
+            code => CellType::String(((code - 13) / 2) as usize),
+        })
+    }
+
+    pub fn serial_type(&self) -> i64 {
+        match self {
+            CellType::Null => 0,
+            CellType::Varint(1) => 1,
+            CellType::Varint(2) => 2,
+            CellType::Varint(3) => 3,
+            CellType::Varint(4) => 4,
+            CellType::Varint(6) => 5,
+            CellType::Varint(_) => 6,
+            CellType::Float64 => 7,
+            CellType::Constant(0) => 8,
+            CellType::Constant(_) => 9,
+            CellType::Blob(len) => *len as i64 * 2 + 12,
+            CellType::String(len) => *len as i64 * 2 + 13,
+        }
+    }
+
+    /// Number of bytes the value takes up in the record body
+    pub fn size(&self) -> usize {
+        match self {
+            CellType::Null | CellType::Constant(_) => 0,
+            CellType::Varint(size) => *size as usize,
+            CellType::Float64 => 8,
+            CellType::Blob(len) | CellType::String(len) => *len,
+        }
+    }
+
+    /// The smallest serial type that can hold an integer, files before schema format 4
+    /// don't have the ones for 0 and 1
+    fn for_int(value: i64, schema_format: u32) -> CellType {
+        match value {
+            0 | 1 if schema_format >= 4 => CellType::Constant(value),
+            -0x80..=0x7f => CellType::Varint(1),
+            -0x8000..=0x7fff => CellType::Varint(2),
+            -0x80_0000..=0x7f_ffff => CellType::Varint(3),
+            -0x8000_0000..=0x7fff_ffff => CellType::Varint(4),
+            -0x8000_0000_0000..=0x7fff_ffff_ffff => CellType::Varint(6),
+            _ => CellType::Varint(8),
+        }
+    }
+}
+
+pub fn decode_varint(buf: &[u8]) -> Result<Varint> {
+    let mut value: i64 = 0;
+    for (i, byte) in buf.iter().take(9).enumerate() {
+        // the ninth byte contributes all 8 bits
+        if i == 8 {
+            value = (value << 8) | i64::from(*byte);
+            return Ok(Varint { value, size: 9 });
+        }
+        value = (value << 7) | i64::from(byte & 0b0111_1111);
+        if byte & 0b1000_0000 == 0 {
+            return Ok(Varint {
+                value,
+                size: i as u8 + 1,
+            });
+        }
+    }
+    bail!(Corrupt, "varint extends past the end of the buffer")
+}
+
+pub fn encode_varint(value: i64) -> Vec<u8> {
+    let mut value = value as u64;
+    // values that need more than 56 bits use all 8 bits of the ninth byte
+    if value & (0xff00_0000 << 32) != 0 {
+        let mut buf = vec![0u8; 9];
+        buf[8] = value as u8;
+        value >>= 8;
+        for byte in buf[..8].iter_mut().rev() {
+            *byte = (value as u8 & 0b0111_1111) | 0b1000_0000;
+            value >>= 7;
+        }
+        return buf;
+    }
+
+    let mut buf = vec![value as u8 & 0b0111_1111];
+    value >>= 7;
+    while value != 0 {
+        buf.push((value as u8 & 0b0111_1111) | 0b1000_0000);
+        value >>= 7;
+    }
+    buf.reverse();
+    buf
+}
+
+pub fn varint_size(value: i64) -> usize {
+    encode_varint(value).len()
+}
+
+pub fn decode_header(payload: &[u8]) -> Result<RecordHeader> {
+    let Varint { value, size } = decode_varint(payload)?;
+    let header_size = value;
+    if header_size < size as i64 || header_size as usize > payload.len() {
+        bail!(Corrupt, "invalid record header size {}", header_size);
+    }
+    let mut offset = size as usize;
+    let mut headers: Vec<CellType> = Vec::new();
+    while offset < header_size as usize {
+        let varint = decode_varint(&payload[offset..header_size as usize])?;
+        offset += varint.size as usize;
+        headers.push(CellType::from_serial_type(varint.value)?);
+    }
+    Ok(RecordHeader::new(headers, header_size))
+}
+
+pub fn decode_value(
+    cell_type: &CellType,
+    data: &[u8],
+    encoding: TextEncoding,
+) -> Result<CellValue> {
+    let Some(data) = data.get(..cell_type.size()) else {
+        bail!(Corrupt, "cell extends past the end of the record payload");
+    };
+    Ok(match cell_type {
+        CellType::Null => CellValue::Null,
+        CellType::Constant(value) => CellValue::Int(*value),
+        CellType::Varint(size) => {
+            // sign extend the big-endian twos-complement value to 64 bits
+            let shift = 64 - 8 * *size as u32;
+            let value = data.iter().fold(0_i64, |acc, b| (acc << 8) | *b as i64);
+            CellValue::Int(value.wrapping_shl(shift).wrapping_shr(shift))
+        }
+        CellType::Float64 => {
+            let mut buff = [0; 8];
+            buff.copy_from_slice(data);
+            CellValue::Float(f64::from_be_bytes(buff))
+        }
+        CellType::Blob(_) => CellValue::Blob(data.to_vec()),
+        CellType::String(_) => CellValue::String(encoding.decode(data)?),
+    })
+}
+
+pub fn decode_record(payload: &[u8], encoding: TextEncoding) -> Result<Vec<CellValue>> {
+    let header = decode_header(payload)?;
+    let mut offset = header.header_size as usize;
+    let mut values = Vec::with_capacity(header.headers.len());
+    for cell_type in &header.headers {
+        let Some(data) = payload.get(offset..) else {
+            bail!(Corrupt, "cell starts past the end of the record payload");
+        };
+        values.push(decode_value(cell_type, data, encoding)?);
+        offset += cell_type.size();
+    }
+    Ok(values)
+}
+
+pub fn encode_record(values: &[CellValue], encoding: TextEncoding, schema_format: u32) -> Vec<u8> {
+    let mut serial_types = Vec::new();
+    let mut body = Vec::new();
+    for value in values {
+        let cell_type = match value {
+            CellValue::Null => CellType::Null,
+            CellValue::Int(i) => {
+                let cell_type = CellType::for_int(*i, schema_format);
+                body.extend_from_slice(&i.to_be_bytes()[8 - cell_type.size()..]);
+                cell_type
+            }
+            CellValue::Float(f) => {
+                body.extend_from_slice(&f.to_be_bytes());
+                CellType::Float64
+            }
+            CellValue::Blob(b) => {
+                body.extend_from_slice(b);
+                CellType::Blob(b.len())
+            }
+            CellValue::String(s) => {
+                let text = encoding.encode(s);
+                body.extend_from_slice(&text);
+                CellType::String(text.len())
+            }
+        };
+        serial_types.extend(encode_varint(cell_type.serial_type()));
+    }
+
+    // the header size includes the varint holding it, which can change its own length
+    let mut header_size = serial_types.len() + 1;
+    while varint_size(header_size as i64) + serial_types.len() != header_size {
+        header_size = varint_size(header_size as i64) + serial_types.len();
+    }
+
+    let mut record = encode_varint(header_size as i64);
+    record.extend(serial_types);
+    record.extend(body);
+    record
+}
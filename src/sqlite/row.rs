@@ -1,8 +1,50 @@
-use std::{collections::HashMap, rc::Rc};
+use std::{collections::HashMap, sync::Arc};
 
-use crate::sqlite::record::CellValue;
+use crate::sqlite::{
+    column::TypeAffinity,
+    error::{Error, Result},
+    record::CellValue,
+};
 #[derive(Debug)]
 pub struct Row {
-    pub columns: Rc<HashMap<String, usize>>,
+    pub columns: Arc<HashMap<String, usize>>,
     pub cells: Vec<CellValue>,
 }
+
+/// What a comparison with a column takes from its definition
+#[derive(Debug, Clone)]
+pub struct ColumnType {
+    pub affinity: TypeAffinity,
+    /// None for BINARY
+    pub collation: Option<Arc<str>>,
+}
+
+/// Anything a WHERE clause or a selection can read columns from, a stored table row or a
+/// row produced by a subquery
+pub trait ColumnReader {
+    fn read_column(&self, column_name: &str) -> Result<CellValue>;
+    /// Names of every column in order, what `*` expands to
+    fn column_names(&self) -> Vec<String>;
+
+    /// The affinity and collation of a column, None when its values come with neither
+    /// like the results of a subquery
+    fn column_type(&self, _column_name: &str) -> Option<ColumnType> {
+        None
+    }
+}
+
+impl ColumnReader for Row {
+    fn read_column(&self, column_name: &str) -> Result<CellValue> {
+        let index = self
+            .columns
+            .get(column_name)
+            .ok_or_else(|| Error::NoSuchColumn(column_name.to_string()))?;
+        Ok(self.cells[*index].clone())
+    }
+
+    fn column_names(&self) -> Vec<String> {
+        let mut names = self.columns.iter().collect::<Vec<_>>();
+        names.sort_by_key(|(_, i)| **i);
+        names.into_iter().map(|(name, _)| name.to_owned()).collect()
+    }
+}
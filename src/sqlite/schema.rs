@@ -1,21 +1,31 @@
-use std::rc::Rc;
+use std::sync::Arc;
 
-use self::{index_schema::IndexSchema, table_schema::TableSchema};
+use self::{
+    index_schema::IndexSchema, table_schema::TableSchema, trigger_schema::TriggerSchema,
+    view_schema::ViewSchema,
+};
 
 pub mod index_schema;
+pub mod schema_parser;
 pub mod table_schema;
+pub mod trigger_schema;
+pub mod view_schema;
 
 #[derive(Debug)]
 pub enum SqliteSchema {
     Table(TableSchema),
     Index(IndexSchema),
+    View(ViewSchema),
+    Trigger(TriggerSchema),
 }
 
 impl SqliteSchema {
-    pub fn get_name(&self) -> Rc<str> {
+    pub fn get_name(&self) -> Arc<str> {
         match self {
             SqliteSchema::Table(t) => t.name.clone(),
             SqliteSchema::Index(i) => i.name.clone(),
+            SqliteSchema::View(v) => v.name.clone(),
+            SqliteSchema::Trigger(t) => t.name.clone(),
         }
     }
 }
@@ -1,11 +1,105 @@
-use std::rc::Rc;
+use std::sync::Arc;
+
+use super::{
+    super::error::{bail, error, Context, Result},
+    schema_parser::{self, IndexedColumn, SortOrder},
+    table_schema::TableSchema,
+};
 
 #[derive(Debug)]
 pub struct IndexSchema {
     pub row_id: i64,
-    pub name: Rc<str>,
+    pub name: Arc<str>,
     pub root_page: u32,
-    pub sql: String,
-    pub parent_table: Rc<str>,
-    pub column_name: Rc<str>,
+    /// NULL for the indexes sqlite creates for UNIQUE and PRIMARY KEY constraints
+    pub sql: Option<String>,
+    pub parent_table: Arc<str>,
+    /// The key columns in index order, the rowid follows them in every index record
+    pub columns: Vec<IndexedColumn>,
+    pub unique: bool,
+    /// The sql of the WHERE clause of a partial index
+    pub where_clause: Option<String>,
+}
+
+impl IndexSchema {
+    pub fn new(
+        row_id: i64,
+        name: Arc<str>,
+        parent_table: Arc<str>,
+        root_page: u32,
+        sql: Option<String>,
+        table: Option<&TableSchema>,
+    ) -> Result<IndexSchema> {
+        let (mut columns, unique, where_clause) = match &sql {
+            Some(sql) => {
+                let index = schema_parser::parse_create_index(sql)
+                    .with_context(|| format!("failed to parse schema for index {}", name))?;
+                (index.columns, index.unique, index.where_clause)
+            }
+            None => (IndexSchema::autoindex_columns(&name, table)?, true, None),
+        };
+
+        // key columns use the collation of the table column unless the index overrides it
+        if let Some(table) = table {
+            for column in columns.iter_mut().filter(|c| c.collation.is_none()) {
+                column.collation = table
+                    .column_index(&column.name)
+                    .and_then(|i| table.columns[i].collation.as_ref())
+                    .map(|c| c.to_string());
+            }
+        }
+
+        Ok(IndexSchema {
+            row_id,
+            name,
+            root_page,
+            sql,
+            parent_table,
+            columns,
+            unique,
+            where_clause,
+        })
+    }
+
+    // indexes created for UNIQUE and PRIMARY KEY constraints are named
+    // sqlite_autoindex_<table>_<N> where N counts the constraints from 1
+    fn autoindex_columns(name: &str, table: Option<&TableSchema>) -> Result<Vec<IndexedColumn>> {
+        let Some(table) = table else {
+            bail!(Corrupt, "could not find the table for index {}", name);
+        };
+        let number = name
+            .strip_prefix(&format!("sqlite_autoindex_{}_", table.name))
+            .and_then(|n| n.parse::<usize>().ok())
+            .ok_or_else(|| error!(Corrupt, "index {} has no sql and is not an autoindex", name))?;
+        let definition = schema_parser::parse_create_table(&table.sql)
+            .with_context(|| format!("failed to parse schema for table {}", table.name))?;
+        definition
+            .autoindexes()
+            .into_iter()
+            .nth(number.wrapping_sub(1))
+            .ok_or_else(|| {
+                error!(
+                    Corrupt,
+                    "table {} has no constraint for {}", table.name, name
+                )
+            })
+    }
+
+    /// The first key column, the one lookups through this index can search on
+    pub fn column_name(&self) -> &str {
+        &self.columns[0].name
+    }
+
+    /// Whether the index holds an entry for every row ordered by the plain value of its
+    /// first column, which is what searching the index b-tree relies on
+    pub fn is_searchable(&self) -> bool {
+        let column = &self.columns[0];
+        self.where_clause.is_none()
+            && !column.expression
+            && column.order == SortOrder::Asc
+            && column
+                .collation
+                .as_ref()
+                .is_none_or(|c| c.eq_ignore_ascii_case("BINARY"))
+    }
 }
@@ -0,0 +1,946 @@
+// A small parser for the sql stored in the sqlite_schema table.
+//
+// sqlparser doesn't understand a fair bit of sqlite's CREATE TABLE grammar (typeless
+// columns, PRIMARY KEY DESC, indexed columns with ASC/DESC in table constraints...) so
+// schema sql that it rejects is parsed here instead.
+// https://www.sqlite.org/lang_createtable.html
+//
+// sqlparser has no support for CREATE TRIGGER at all, so triggers are split up here and
+// only their WHEN clause and body statements are handed to sqlparser.
+
+use crate::sqlite::error::{bail, error, Result};
+
+use crate::sqlite::column::TypeAffinity;
+
+#[derive(Debug, Clone, PartialEq)]
+enum TokenKind {
+    // bare identifiers and keywords
+    Word(String),
+    // "identifier", [identifier] or `identifier`
+    Quoted(String),
+    String(String),
+    Number,
+    Blob,
+    Symbol(char),
+}
+
+#[derive(Debug, Clone)]
+struct Token {
+    kind: TokenKind,
+    start: usize,
+    end: usize,
+}
+
+fn tokenize(sql: &str) -> Result<Vec<Token>> {
+    let bytes = sql.as_bytes();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+
+    // reads a quoted run where the closing quote is escaped by doubling it
+    let quoted = |start: usize, close: u8| -> Result<(String, usize)> {
+        let mut value = Vec::new();
+        let mut i = start + 1;
+        loop {
+            match bytes.get(i) {
+                None => bail!(Parse, "unterminated quote starting at {}", start),
+                Some(&b) if b == close && bytes.get(i + 1) == Some(&close) && close != b']' => {
+                    value.push(close);
+                    i += 2;
+                }
+                Some(&b) if b == close => {
+                    let value = String::from_utf8(value)
+                        .map_err(|_| error!(Parse, "invalid utf-8 in quote at {}", start))?;
+                    return Ok((value, i + 1));
+                }
+                Some(&b) => {
+                    value.push(b);
+                    i += 1;
+                }
+            }
+        }
+    };
+
+    while i < bytes.len() {
+        let start = i;
+        let b = bytes[i];
+        let kind = match b {
+            b if b.is_ascii_whitespace() => {
+                i += 1;
+                continue;
+            }
+            b'-' if bytes.get(i + 1) == Some(&b'-') => {
+                while i < bytes.len() && bytes[i] != b'\n' {
+                    i += 1;
+                }
+                continue;
+            }
+            b'/' if bytes.get(i + 1) == Some(&b'*') => {
+                i += 2;
+                while i < bytes.len() && !(bytes[i] == b'*' && bytes.get(i + 1) == Some(&b'/')) {
+                    i += 1;
+                }
+                i = (i + 2).min(bytes.len());
+                continue;
+            }
+            b'\'' => {
+                let (value, end) = quoted(i, b'\'')?;
+                i = end;
+                TokenKind::String(value)
+            }
+            b'"' | b'`' | b'[' => {
+                let close = match b {
+                    b'[' => b']',
+                    b => b,
+                };
+                let (value, end) = quoted(i, close)?;
+                i = end;
+                TokenKind::Quoted(value)
+            }
+            b'x' | b'X' if bytes.get(i + 1) == Some(&b'\'') => {
+                let (_, end) = quoted(i + 1, b'\'')?;
+                i = end;
+                TokenKind::Blob
+            }
+            b if b.is_ascii_digit()
+                || (b == b'.' && bytes.get(i + 1).is_some_and(u8::is_ascii_digit)) =>
+            {
+                while i < bytes.len() && (bytes[i].is_ascii_alphanumeric() || bytes[i] == b'.') {
+                    // exponents can carry a sign, 1e-5
+                    if matches!(bytes[i], b'e' | b'E')
+                        && matches!(bytes.get(i + 1), Some(b'+' | b'-'))
+                    {
+                        i += 1;
+                    }
+                    i += 1;
+                }
+                TokenKind::Number
+            }
+            b if b.is_ascii_alphabetic() || b == b'_' || b >= 0x80 => {
+                while i < bytes.len()
+                    && (bytes[i].is_ascii_alphanumeric()
+                        || matches!(bytes[i], b'_' | b'$')
+                        || bytes[i] >= 0x80)
+                {
+                    i += 1;
+                }
+                TokenKind::Word(sql[start..i].to_string())
+            }
+            b => {
+                i += 1;
+                TokenKind::Symbol(b as char)
+            }
+        };
+        tokens.push(Token {
+            kind,
+            start,
+            end: i,
+        });
+    }
+    Ok(tokens)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SortOrder {
+    #[default]
+    Asc,
+    Desc,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct IndexedColumn {
+    /// The column name, or the sql of the expression for expression indexes
+    pub name: String,
+    pub expression: bool,
+    pub collation: Option<String>,
+    pub order: SortOrder,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ColumnConstraint {
+    PrimaryKey {
+        order: SortOrder,
+        autoincrement: bool,
+    },
+    NotNull,
+    Null,
+    Unique,
+    Check,
+    Default,
+    Collate(String),
+    References,
+    Generated,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TableConstraint {
+    PrimaryKey(Vec<IndexedColumn>),
+    Unique(Vec<IndexedColumn>),
+    Check,
+    ForeignKey,
+}
+
+#[derive(Debug, Clone)]
+pub struct ColumnDefinition {
+    pub name: String,
+    pub declared_type: Option<String>,
+    pub constraints: Vec<ColumnConstraint>,
+}
+
+impl ColumnDefinition {
+    pub fn type_affinity(&self) -> TypeAffinity {
+        TypeAffinity::from_declared_type(self.declared_type.as_deref())
+    }
+
+    pub fn collation(&self) -> Option<&str> {
+        self.constraints.iter().find_map(|c| match c {
+            ColumnConstraint::Collate(collation) => Some(collation.as_str()),
+            _ => None,
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CreateTable {
+    pub name: String,
+    pub columns: Vec<ColumnDefinition>,
+    pub constraints: Vec<TableConstraint>,
+    pub without_rowid: bool,
+}
+
+impl CreateTable {
+    // https://www.sqlite.org/lang_createtable.html#rowid
+    //
+    // A PRIMARY KEY on a single column declared exactly as INTEGER makes that column an
+    // alias for the rowid. `INTEGER PRIMARY KEY DESC` as a column constraint is the one
+    // exception, the same key declared as a table constraint is still an alias.
+    pub fn rowid_alias(&self) -> Option<usize> {
+        if self.without_rowid {
+            return None;
+        }
+        let table_key = self.constraints.iter().find_map(|c| match c {
+            TableConstraint::PrimaryKey(columns) => Some(columns),
+            _ => None,
+        });
+        let index = match table_key {
+            Some(key) if key.len() != 1 => return None,
+            Some(key) => self
+                .columns
+                .iter()
+                .position(|c| c.name.eq_ignore_ascii_case(&key[0].name))?,
+            None => {
+                let index = self.columns.iter().position(|c| {
+                    c.constraints
+                        .iter()
+                        .any(|c| matches!(c, ColumnConstraint::PrimaryKey { .. }))
+                })?;
+                let desc = self.columns[index].constraints.iter().any(|c| {
+                    matches!(
+                        c,
+                        ColumnConstraint::PrimaryKey {
+                            order: SortOrder::Desc,
+                            ..
+                        }
+                    )
+                });
+                if desc {
+                    return None;
+                }
+                index
+            }
+        };
+
+        match &self.columns[index].declared_type {
+            Some(t) if t.eq_ignore_ascii_case("INTEGER") => Some(index),
+            _ => None,
+        }
+    }
+
+    /// Column indexes of the PRIMARY KEY in key order
+    pub fn primary_key(&self) -> Vec<(usize, SortOrder)> {
+        let position = |name: &str| {
+            self.columns
+                .iter()
+                .position(|c| c.name.eq_ignore_ascii_case(name))
+        };
+        let table_key = self.constraints.iter().find_map(|c| match c {
+            TableConstraint::PrimaryKey(columns) => Some(columns),
+            _ => None,
+        });
+        match table_key {
+            Some(key) => key
+                .iter()
+                .filter_map(|k| Some((position(&k.name)?, k.order)))
+                .collect(),
+            None => self
+                .columns
+                .iter()
+                .enumerate()
+                .find_map(|(i, c)| {
+                    c.constraints.iter().find_map(|c| match c {
+                        ColumnConstraint::PrimaryKey { order, .. } => Some((i, *order)),
+                        _ => None,
+                    })
+                })
+                .into_iter()
+                .collect(),
+        }
+    }
+
+    /// The indexes sqlite creates on its own for UNIQUE and PRIMARY KEY constraints, in the
+    /// order they are numbered in sqlite_autoindex_<table>_<N>. Column constraints are
+    /// created as they are parsed so they come before the table constraints.
+    pub fn autoindexes(&self) -> Vec<Vec<IndexedColumn>> {
+        // the rowid and WITHOUT ROWID tables already store their rows by primary key
+        let primary_key_indexed = !self.without_rowid && self.rowid_alias().is_none();
+        let column_indexes = self.columns.iter().flat_map(|column| {
+            column.constraints.iter().filter_map(move |c| {
+                let order = match c {
+                    ColumnConstraint::PrimaryKey { order, .. } if primary_key_indexed => *order,
+                    ColumnConstraint::Unique => SortOrder::Asc,
+                    _ => return None,
+                };
+                Some(vec![IndexedColumn {
+                    name: column.name.clone(),
+                    expression: false,
+                    collation: None,
+                    order,
+                }])
+            })
+        });
+        let table_indexes = self.constraints.iter().filter_map(|c| match c {
+            TableConstraint::PrimaryKey(columns) if primary_key_indexed => Some(columns.clone()),
+            TableConstraint::Unique(columns) => Some(columns.clone()),
+            _ => None,
+        });
+        column_indexes.chain(table_indexes).collect()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CreateIndex {
+    pub name: String,
+    pub table: String,
+    pub unique: bool,
+    pub columns: Vec<IndexedColumn>,
+    /// The sql of the WHERE clause of a partial index
+    pub where_clause: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TriggerTiming {
+    Before,
+    After,
+    InsteadOf,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TriggerEvent {
+    Delete,
+    Insert,
+    /// The columns of `UPDATE OF a, b`, empty when an update of any column fires it
+    Update(Vec<String>),
+}
+
+#[derive(Debug, Clone)]
+pub struct CreateTrigger {
+    pub name: String,
+    pub table: String,
+    pub timing: TriggerTiming,
+    pub event: TriggerEvent,
+    /// The sql of the WHEN clause
+    pub when_clause: Option<String>,
+    /// The sql of each statement between BEGIN and END
+    pub body: Vec<String>,
+}
+
+struct SchemaParser<'a> {
+    sql: &'a str,
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl<'a> SchemaParser<'a> {
+    fn new(sql: &'a str) -> Result<Self> {
+        Ok(SchemaParser {
+            sql,
+            tokens: tokenize(sql)?,
+            pos: 0,
+        })
+    }
+
+    fn peek(&self) -> Option<&TokenKind> {
+        self.tokens.get(self.pos).map(|t| &t.kind)
+    }
+
+    fn peek_keyword(&self, keyword: &str) -> bool {
+        matches!(self.peek(), Some(TokenKind::Word(w)) if w.eq_ignore_ascii_case(keyword))
+    }
+
+    fn parse_keyword(&mut self, keyword: &str) -> bool {
+        if self.peek_keyword(keyword) {
+            self.pos += 1;
+            return true;
+        }
+        false
+    }
+
+    /// Consumes the keywords only if all of them are next
+    fn parse_keywords(&mut self, keywords: &[&str]) -> bool {
+        let matched = keywords.iter().enumerate().all(|(i, k)| {
+            matches!(self.tokens.get(self.pos + i).map(|t| &t.kind),
+                Some(TokenKind::Word(w)) if w.eq_ignore_ascii_case(k))
+        });
+        if matched {
+            self.pos += keywords.len();
+        }
+        matched
+    }
+
+    fn expect_keyword(&mut self, keyword: &str) -> Result<()> {
+        if !self.parse_keyword(keyword) {
+            bail!(
+                Parse,
+                "expected {} but found {}",
+                keyword,
+                self.describe_next()
+            );
+        }
+        Ok(())
+    }
+
+    fn peek_symbol(&self, symbol: char) -> bool {
+        self.peek() == Some(&TokenKind::Symbol(symbol))
+    }
+
+    fn parse_symbol(&mut self, symbol: char) -> bool {
+        if self.peek_symbol(symbol) {
+            self.pos += 1;
+            return true;
+        }
+        false
+    }
+
+    fn expect_symbol(&mut self, symbol: char) -> Result<()> {
+        if !self.parse_symbol(symbol) {
+            bail!(
+                Parse,
+                "expected {} but found {}",
+                symbol,
+                self.describe_next()
+            );
+        }
+        Ok(())
+    }
+
+    fn describe_next(&self) -> String {
+        match self.tokens.get(self.pos) {
+            Some(t) => format!("'{}'", &self.sql[t.start..t.end]),
+            None => "end of input".to_string(),
+        }
+    }
+
+    fn parse_name(&mut self) -> Result<String> {
+        let name = match self.peek() {
+            Some(TokenKind::Word(w)) | Some(TokenKind::Quoted(w)) | Some(TokenKind::String(w)) => {
+                w.clone()
+            }
+            _ => bail!(Parse, "expected a name but found {}", self.describe_next()),
+        };
+        self.pos += 1;
+        Ok(name)
+    }
+
+    /// Parses `[schema.]name`, dropping the schema
+    fn parse_object_name(&mut self) -> Result<String> {
+        let name = self.parse_name()?;
+        if self.parse_symbol('.') {
+            return self.parse_name();
+        }
+        Ok(name)
+    }
+
+    /// Skips a parenthesized group starting at the current `(` and returns the sql
+    /// between the parentheses
+    fn skip_parenthesized(&mut self) -> Result<&'a str> {
+        let open = self.pos;
+        self.expect_symbol('(')?;
+        let mut depth = 1;
+        while depth > 0 {
+            match self.peek() {
+                Some(TokenKind::Symbol('(')) => depth += 1,
+                Some(TokenKind::Symbol(')')) => depth -= 1,
+                None => bail!(Parse, "unbalanced parentheses"),
+                _ => {}
+            }
+            self.pos += 1;
+        }
+        let start = self.tokens[open].end;
+        let end = self.tokens[self.pos - 1].start;
+        Ok(&self.sql[start..end])
+    }
+
+    /// Skips tokens up to (but not including) the next `,` or `)` at the current depth
+    /// and returns the sql that was skipped
+    fn skip_expression(&mut self, stop_keywords: &[&str]) -> Result<&'a str> {
+        let start = self.pos;
+        loop {
+            match self.peek() {
+                None | Some(TokenKind::Symbol(',')) | Some(TokenKind::Symbol(')')) => break,
+                Some(TokenKind::Symbol('(')) => {
+                    self.skip_parenthesized()?;
+                }
+                Some(TokenKind::Word(w))
+                    if stop_keywords.iter().any(|k| w.eq_ignore_ascii_case(k)) =>
+                {
+                    break
+                }
+                _ => self.pos += 1,
+            }
+        }
+        if start == self.pos {
+            bail!(
+                Parse,
+                "expected an expression but found {}",
+                self.describe_next()
+            );
+        }
+        Ok(&self.sql[self.tokens[start].start..self.tokens[self.pos - 1].end])
+    }
+
+    fn parse_sort_order(&mut self) -> SortOrder {
+        if self.parse_keyword("DESC") {
+            return SortOrder::Desc;
+        }
+        self.parse_keyword("ASC");
+        SortOrder::Asc
+    }
+
+    fn parse_conflict_clause(&mut self) -> Result<()> {
+        if self.parse_keywords(&["ON", "CONFLICT"]) {
+            self.parse_name()?;
+        }
+        Ok(())
+    }
+
+    // indexed-column: ( column-name | expr ) [COLLATE collation-name] [ASC | DESC]
+    fn parse_indexed_columns(&mut self) -> Result<Vec<IndexedColumn>> {
+        self.expect_symbol('(')?;
+        let mut columns = Vec::new();
+        loop {
+            let name = self
+                .skip_expression(&["COLLATE", "ASC", "DESC"])?
+                .to_string();
+            // a quoted identifier on its own is just the column name
+            let (name, expression) = match tokenize(&name)?.as_slice() {
+                [Token {
+                    kind: TokenKind::Quoted(n) | TokenKind::Word(n) | TokenKind::String(n),
+                    ..
+                }] => (n.clone(), false),
+                _ => (name, true),
+            };
+            let collation = match self.parse_keyword("COLLATE") {
+                true => Some(self.parse_name()?),
+                false => None,
+            };
+            let order = self.parse_sort_order();
+            columns.push(IndexedColumn {
+                name,
+                expression,
+                collation,
+                order,
+            });
+            if !self.parse_symbol(',') {
+                break;
+            }
+        }
+        self.expect_symbol(')')?;
+        Ok(columns)
+    }
+
+    // foreign-key-clause: REFERENCES foreign-table [( column-name, ... )]
+    //     [ON (DELETE | UPDATE) action | MATCH name]... [[NOT] DEFERRABLE [INITIALLY (DEFERRED | IMMEDIATE)]]
+    fn parse_foreign_key_clause(&mut self) -> Result<()> {
+        self.expect_keyword("REFERENCES")?;
+        self.parse_object_name()?;
+        if self.peek_symbol('(') {
+            self.skip_parenthesized()?;
+        }
+        loop {
+            if self.parse_keyword("ON") {
+                self.parse_name()?;
+                if !(self.parse_keywords(&["SET", "NULL"])
+                    || self.parse_keywords(&["SET", "DEFAULT"])
+                    || self.parse_keywords(&["NO", "ACTION"])
+                    || self.parse_keyword("CASCADE")
+                    || self.parse_keyword("RESTRICT"))
+                {
+                    bail!(Parse, "invalid foreign key action {}", self.describe_next());
+                }
+            } else if self.parse_keyword("MATCH") {
+                self.parse_name()?;
+            } else if self.parse_keywords(&["NOT", "DEFERRABLE"])
+                || self.parse_keyword("DEFERRABLE")
+            {
+                if self.parse_keyword("INITIALLY") {
+                    self.parse_name()?;
+                }
+            } else {
+                return Ok(());
+            }
+        }
+    }
+
+    fn parse_column_constraint(&mut self) -> Result<Option<ColumnConstraint>> {
+        if self.parse_keyword("CONSTRAINT") {
+            self.parse_name()?;
+        }
+        let constraint = if self.parse_keywords(&["PRIMARY", "KEY"]) {
+            let order = self.parse_sort_order();
+            self.parse_conflict_clause()?;
+            let autoincrement = self.parse_keyword("AUTOINCREMENT");
+            ColumnConstraint::PrimaryKey {
+                order,
+                autoincrement,
+            }
+        } else if self.parse_keywords(&["NOT", "NULL"]) {
+            self.parse_conflict_clause()?;
+            ColumnConstraint::NotNull
+        } else if self.parse_keyword("NULL") {
+            self.parse_conflict_clause()?;
+            ColumnConstraint::Null
+        } else if self.parse_keyword("UNIQUE") {
+            self.parse_conflict_clause()?;
+            ColumnConstraint::Unique
+        } else if self.parse_keyword("CHECK") {
+            self.skip_parenthesized()?;
+            ColumnConstraint::Check
+        } else if self.parse_keyword("DEFAULT") {
+            match self.peek() {
+                Some(TokenKind::Symbol('(')) => {
+                    self.skip_parenthesized()?;
+                }
+                Some(TokenKind::Symbol('-')) | Some(TokenKind::Symbol('+')) => self.pos += 2,
+                Some(_) => self.pos += 1,
+                None => bail!(Parse, "expected a default value"),
+            }
+            ColumnConstraint::Default
+        } else if self.parse_keyword("COLLATE") {
+            ColumnConstraint::Collate(self.parse_name()?)
+        } else if self.peek_keyword("REFERENCES") {
+            self.parse_foreign_key_clause()?;
+            ColumnConstraint::References
+        } else if self.parse_keywords(&["GENERATED", "ALWAYS", "AS"]) || self.parse_keyword("AS") {
+            self.skip_parenthesized()?;
+            if !self.parse_keyword("STORED") {
+                self.parse_keyword("VIRTUAL");
+            }
+            ColumnConstraint::Generated
+        } else {
+            return Ok(None);
+        };
+        Ok(Some(constraint))
+    }
+
+    // column-def: column-name [type-name] [column-constraint]...
+    fn parse_column_definition(&mut self) -> Result<ColumnDefinition> {
+        let name = self.parse_name()?;
+
+        // the type name is every name up to the first constraint keyword, plus an
+        // optional (size) or (precision, scale)
+        let mut type_parts = Vec::new();
+        while let Some(TokenKind::Word(_) | TokenKind::Quoted(_)) = self.peek() {
+            if [
+                "CONSTRAINT",
+                "PRIMARY",
+                "NOT",
+                "NULL",
+                "UNIQUE",
+                "CHECK",
+                "DEFAULT",
+                "COLLATE",
+                "REFERENCES",
+                "GENERATED",
+                "AS",
+            ]
+            .iter()
+            .any(|k| self.peek_keyword(k))
+            {
+                break;
+            }
+            type_parts.push(self.parse_name()?);
+        }
+        let mut declared_type = type_parts.join(" ");
+        if !type_parts.is_empty() && self.peek_symbol('(') {
+            let size = self.skip_parenthesized()?;
+            declared_type = format!("{}({})", declared_type, size.trim());
+        }
+
+        let mut constraints = Vec::new();
+        while let Some(constraint) = self.parse_column_constraint()? {
+            constraints.push(constraint);
+        }
+        Ok(ColumnDefinition {
+            name,
+            declared_type: (!declared_type.is_empty()).then_some(declared_type),
+            constraints,
+        })
+    }
+
+    fn parse_table_constraint(&mut self) -> Result<Option<TableConstraint>> {
+        let start = self.pos;
+        if self.parse_keyword("CONSTRAINT") {
+            self.parse_name()?;
+        }
+        let constraint = if self.parse_keywords(&["PRIMARY", "KEY"]) {
+            let columns = self.parse_indexed_columns()?;
+            self.parse_conflict_clause()?;
+            TableConstraint::PrimaryKey(columns)
+        } else if self.parse_keyword("UNIQUE") {
+            let columns = self.parse_indexed_columns()?;
+            self.parse_conflict_clause()?;
+            TableConstraint::Unique(columns)
+        } else if self.parse_keyword("CHECK") {
+            self.skip_parenthesized()?;
+            TableConstraint::Check
+        } else if self.parse_keywords(&["FOREIGN", "KEY"]) {
+            self.skip_parenthesized()?;
+            self.parse_foreign_key_clause()?;
+            TableConstraint::ForeignKey
+        } else {
+            self.pos = start;
+            return Ok(None);
+        };
+        Ok(Some(constraint))
+    }
+
+    /// Returns the sql of everything left to parse, minus a trailing `;`
+    fn rest(&mut self) -> Result<&'a str> {
+        let start = self.pos;
+        let mut end = self.tokens.len();
+        if end > start && self.tokens[end - 1].kind == TokenKind::Symbol(';') {
+            end -= 1;
+        }
+        if start == end {
+            bail!(Parse, "unexpected end of input");
+        }
+        self.pos = self.tokens.len();
+        Ok(&self.sql[self.tokens[start].start..self.tokens[end - 1].end])
+    }
+
+    // CREATE [UNIQUE] INDEX [IF NOT EXISTS] [schema-name.]index-name
+    //     ON table-name ( indexed-column, ... ) [WHERE expr]
+    fn parse_create_index(&mut self) -> Result<CreateIndex> {
+        self.expect_keyword("CREATE")?;
+        let unique = self.parse_keyword("UNIQUE");
+        self.expect_keyword("INDEX")?;
+        self.parse_keywords(&["IF", "NOT", "EXISTS"]);
+        let name = self.parse_object_name()?;
+        self.expect_keyword("ON")?;
+        let table = self.parse_name()?;
+        let columns = self.parse_indexed_columns()?;
+        let where_clause = match self.parse_keyword("WHERE") {
+            true => Some(self.rest()?.to_string()),
+            false => None,
+        };
+        self.parse_symbol(';');
+        if self.peek().is_some() {
+            bail!(
+                Parse,
+                "unexpected {} after create index",
+                self.describe_next()
+            );
+        }
+        Ok(CreateIndex {
+            name,
+            table,
+            unique,
+            columns,
+            where_clause,
+        })
+    }
+
+    // CREATE [TEMP | TEMPORARY] TRIGGER [IF NOT EXISTS] [schema-name.]trigger-name
+    //     [BEFORE | AFTER | INSTEAD OF] {DELETE | INSERT | UPDATE [OF column-name, ...]}
+    //     ON table-name [FOR EACH ROW] [WHEN expr] BEGIN stmt; ... END
+    // https://www.sqlite.org/lang_createtrigger.html
+    fn parse_create_trigger(&mut self) -> Result<CreateTrigger> {
+        self.expect_keyword("CREATE")?;
+        if !self.parse_keyword("TEMP") {
+            self.parse_keyword("TEMPORARY");
+        }
+        self.expect_keyword("TRIGGER")?;
+        self.parse_keywords(&["IF", "NOT", "EXISTS"]);
+        let name = self.parse_object_name()?;
+
+        let timing = if self.parse_keyword("AFTER") {
+            TriggerTiming::After
+        } else if self.parse_keywords(&["INSTEAD", "OF"]) {
+            TriggerTiming::InsteadOf
+        } else {
+            // BEFORE is the default
+            self.parse_keyword("BEFORE");
+            TriggerTiming::Before
+        };
+
+        let event = if self.parse_keyword("DELETE") {
+            TriggerEvent::Delete
+        } else if self.parse_keyword("INSERT") {
+            TriggerEvent::Insert
+        } else if self.parse_keyword("UPDATE") {
+            let mut columns = Vec::new();
+            if self.parse_keyword("OF") {
+                columns.push(self.parse_name()?);
+                while self.parse_symbol(',') {
+                    columns.push(self.parse_name()?);
+                }
+            }
+            TriggerEvent::Update(columns)
+        } else {
+            bail!(
+                Parse,
+                "expected DELETE, INSERT or UPDATE but found {}",
+                self.describe_next()
+            );
+        };
+
+        self.expect_keyword("ON")?;
+        let table = self.parse_object_name()?;
+        self.parse_keywords(&["FOR", "EACH", "ROW"]);
+
+        let when_clause = match self.parse_keyword("WHEN") {
+            true => {
+                let start = self.pos;
+                while !self.peek_keyword("BEGIN") {
+                    match self.peek() {
+                        Some(TokenKind::Symbol('(')) => {
+                            self.skip_parenthesized()?;
+                        }
+                        Some(_) => self.pos += 1,
+                        None => bail!(Parse, "expected BEGIN but found end of input"),
+                    }
+                }
+                if start == self.pos {
+                    bail!(
+                        Parse,
+                        "expected an expression but found {}",
+                        self.describe_next()
+                    );
+                }
+                Some(self.sql[self.tokens[start].start..self.tokens[self.pos - 1].end].to_string())
+            }
+            false => None,
+        };
+
+        // the body ends at the last END, statements inside it can use END themselves
+        // (CASE ... END) so it can't be searched for from the front
+        self.expect_keyword("BEGIN")?;
+        let mut end = self.tokens.len();
+        if end > self.pos && self.tokens[end - 1].kind == TokenKind::Symbol(';') {
+            end -= 1;
+        }
+        if end == self.pos
+            || !matches!(&self.tokens[end - 1].kind, TokenKind::Word(w) if w.eq_ignore_ascii_case("END"))
+        {
+            bail!(Parse, "trigger body must finish with END");
+        }
+
+        let mut body = Vec::new();
+        let mut start = self.pos;
+        for i in self.pos..end {
+            if self.tokens[i].kind == TokenKind::Symbol(';') || i == end - 1 {
+                if i > start {
+                    body.push(self.sql[self.tokens[start].start..self.tokens[i - 1].end].to_string());
+                }
+                start = i + 1;
+            }
+        }
+        if body.is_empty() {
+            bail!(Parse, "trigger {} must have at least one statement", name);
+        }
+        self.pos = self.tokens.len();
+
+        Ok(CreateTrigger {
+            name,
+            table,
+            timing,
+            event,
+            when_clause,
+            body,
+        })
+    }
+
+    // CREATE [TEMP | TEMPORARY] TABLE [IF NOT EXISTS] [schema-name.]table-name
+    //     ( column-def, ... [, table-constraint ...] ) [table-options]
+    fn parse_create_table(&mut self) -> Result<CreateTable> {
+        self.expect_keyword("CREATE")?;
+        if !self.parse_keyword("TEMP") {
+            self.parse_keyword("TEMPORARY");
+        }
+        self.expect_keyword("TABLE")?;
+        self.parse_keywords(&["IF", "NOT", "EXISTS"]);
+        let name = self.parse_object_name()?;
+
+        self.expect_symbol('(')?;
+        let mut columns = Vec::new();
+        let mut constraints = Vec::new();
+        loop {
+            // table constraints always come after the column definitions
+            match self.parse_table_constraint()? {
+                Some(constraint) => constraints.push(constraint),
+                None if constraints.is_empty() => columns.push(self.parse_column_definition()?),
+                None => bail!(
+                    Parse,
+                    "expected a table constraint but found {}",
+                    self.describe_next()
+                ),
+            }
+            if !self.parse_symbol(',') {
+                break;
+            }
+        }
+        self.expect_symbol(')')?;
+        if columns.is_empty() {
+            bail!(Parse, "table {} must have at least one column", name);
+        }
+
+        // table-options: WITHOUT ROWID and STRICT separated by commas
+        let mut without_rowid = false;
+        loop {
+            if self.parse_keywords(&["WITHOUT", "ROWID"]) {
+                without_rowid = true;
+            } else if !self.parse_keyword("STRICT") {
+                break;
+            }
+            if !self.parse_symbol(',') {
+                break;
+            }
+        }
+        self.parse_symbol(';');
+        if self.peek().is_some() {
+            bail!(
+                Parse,
+                "unexpected {} after create table",
+                self.describe_next()
+            );
+        }
+
+        Ok(CreateTable {
+            name,
+            columns,
+            constraints,
+            without_rowid,
+        })
+    }
+}
+
+pub fn parse_create_index(sql: &str) -> Result<CreateIndex> {
+    SchemaParser::new(sql)?.parse_create_index()
+}
+
+pub fn parse_create_table(sql: &str) -> Result<CreateTable> {
+    SchemaParser::new(sql)?.parse_create_table()
+}
+
+pub fn parse_create_trigger(sql: &str) -> Result<CreateTrigger> {
+    SchemaParser::new(sql)?.parse_create_trigger()
+}
@@ -1,13 +1,251 @@
-use std::rc::Rc;
+use std::sync::Arc;
 
-use crate::sqlite::column::Column;
+use sqlparser::{ast, dialect::SQLiteDialect, parser::Parser};
+
+use crate::sqlite::{
+    column::{Column, TypeAffinity},
+    error::{bail, Context, Result},
+};
+
+use super::schema_parser::{self, ColumnConstraint, SortOrder};
+
+static DIALECT: SQLiteDialect = SQLiteDialect {};
 
 #[derive(Debug)]
 pub struct TableSchema {
     pub row_id: i64,
-    pub name: Rc<str>,
-    pub table_name: Rc<str>,
+    pub name: Arc<str>,
+    pub table_name: Arc<str>,
     pub root_page: u32,
     pub sql: String,
-    pub columns: Vec<Rc<Column>>,
+    pub columns: Vec<Arc<Column>>,
+    /// Index of the INTEGER PRIMARY KEY column that is stored as the rowid
+    pub rowid_alias: Option<usize>,
+    pub without_rowid: bool,
+    /// Column indexes of the PRIMARY KEY in key order, WITHOUT ROWID tables store the
+    /// key columns first in every record
+    pub primary_key: Vec<(usize, SortOrder)>,
+}
+
+impl TableSchema {
+    pub fn new(
+        row_id: i64,
+        name: Arc<str>,
+        table_name: Arc<str>,
+        root_page: u32,
+        sql: String,
+    ) -> Result<TableSchema> {
+        let (columns, rowid_alias, without_rowid, primary_key) =
+            match Parser::parse_sql(&DIALECT, &sql) {
+                Ok(ast) => {
+                    if ast.len() != 1 {
+                        bail!(Corrupt, "table sqchema sql can only have 1 expression");
+                    }
+                    let [ast::Statement::CreateTable {
+                        columns,
+                        constraints,
+                        without_rowid,
+                        ..
+                    }] = ast.as_slice()
+                    else {
+                        bail!(Corrupt, "create table statement expected")
+                    };
+                    let rowid_alias = match without_rowid {
+                        true => None,
+                        false => TableSchema::find_rowid_alias(columns, constraints),
+                    };
+                    // sqlparser rejects ASC and DESC in a PRIMARY KEY, keys in either
+                    // order only parse with schema_parser below
+                    let primary_key = TableSchema::find_primary_key(columns, constraints)
+                        .into_iter()
+                        .map(|i| (i, SortOrder::Asc))
+                        .collect();
+                    let columns = columns
+                        .iter()
+                        .map(|f| {
+                            Arc::new(Column {
+                                type_affinity: TypeAffinity::from_declared_type(Some(
+                                    &f.data_type.to_string(),
+                                )),
+                                name: Arc::from(f.name.value.to_owned()),
+                                collation: f.collation.as_ref().map(|c| Arc::from(c.to_string())),
+                                not_null: f
+                                    .options
+                                    .iter()
+                                    .any(|o| matches!(o.option, ast::ColumnOption::NotNull)),
+                                has_default: f
+                                    .options
+                                    .iter()
+                                    .any(|o| matches!(o.option, ast::ColumnOption::Default(_))),
+                                generated: f.options.iter().any(|o| {
+                                    matches!(o.option, ast::ColumnOption::Generated { .. })
+                                }),
+                            })
+                        })
+                        .collect();
+                    (columns, rowid_alias, *without_rowid, primary_key)
+                }
+                // sqlparser doesn't support all of sqlite's create table grammar, for example
+                // datatypeless columns https://github.com/sqlparser-rs/sqlparser-rs/issues/743
+                // which the sqlite_sequence table uses
+                Err(_) => {
+                    let table = schema_parser::parse_create_table(&sql)
+                        .with_context(|| format!("failed to parse schema for table {}", name))?;
+                    let columns = table
+                        .columns
+                        .iter()
+                        .map(|f| {
+                            Arc::new(Column {
+                                type_affinity: f.type_affinity(),
+                                name: Arc::from(f.name.to_owned()),
+                                collation: f.collation().map(Arc::from),
+                                not_null: f.constraints.contains(&ColumnConstraint::NotNull),
+                                has_default: f.constraints.contains(&ColumnConstraint::Default),
+                                generated: f.constraints.contains(&ColumnConstraint::Generated),
+                            })
+                        })
+                        .collect();
+                    (
+                        columns,
+                        table.rowid_alias(),
+                        table.without_rowid,
+                        table.primary_key(),
+                    )
+                }
+            };
+
+        Ok(TableSchema {
+            row_id,
+            name,
+            table_name,
+            root_page,
+            sql,
+            columns,
+            rowid_alias,
+            without_rowid,
+            primary_key,
+        })
+    }
+
+    /// The built in table every database stores its schema in, rooted at page 1
+    pub fn sqlite_schema() -> TableSchema {
+        let column = |name: &str, type_affinity: TypeAffinity| {
+            Arc::new(Column {
+                type_affinity,
+                name: name.into(),
+                collation: None,
+                not_null: false,
+                has_default: false,
+                generated: false,
+            })
+        };
+        TableSchema {
+            row_id: 0,
+            name: "sqlite_schema".into(),
+            table_name: "sqlite_schema".into(),
+            root_page: 1,
+            sql: "CREATE TABLE sqlite_schema(type text, name text, tbl_name text, rootpage integer, sql text)".to_string(),
+            columns: vec![
+                column("type", TypeAffinity::Text),
+                column("name", TypeAffinity::Text),
+                column("tbl_name", TypeAffinity::Text),
+                column("rootpage", TypeAffinity::Int),
+                column("sql", TypeAffinity::Text),
+            ],
+            rowid_alias: None,
+            without_rowid: false,
+            primary_key: Vec::new(),
+        }
+    }
+
+    // https://www.sqlite.org/lang_createtable.html#rowid
+    //
+    // A PRIMARY KEY on a single column declared exactly as INTEGER makes that column an
+    // alias for the rowid, unless the table is WITHOUT ROWID or the column constraint is
+    // declared DESC (sqlparser rejects `PRIMARY KEY DESC` so that can't show up here)
+    pub fn find_rowid_alias(
+        columns: &[ast::ColumnDef],
+        constraints: &[ast::TableConstraint],
+    ) -> Option<usize> {
+        match TableSchema::find_primary_key(columns, constraints)[..] {
+            [index] if columns[index].data_type == ast::DataType::Integer(None) => Some(index),
+            _ => None,
+        }
+    }
+
+    fn find_primary_key(
+        columns: &[ast::ColumnDef],
+        constraints: &[ast::TableConstraint],
+    ) -> Vec<usize> {
+        let table_key = constraints.iter().find_map(|c| match c {
+            ast::TableConstraint::Unique {
+                columns,
+                is_primary: true,
+                ..
+            } => Some(columns),
+            _ => None,
+        });
+
+        match table_key {
+            Some(key) => key
+                .iter()
+                .filter_map(|k| {
+                    columns
+                        .iter()
+                        .position(|c| c.name.value.eq_ignore_ascii_case(&k.value))
+                })
+                .collect(),
+            None => columns
+                .iter()
+                .position(|c| {
+                    c.options
+                        .iter()
+                        .any(|o| matches!(o.option, ast::ColumnOption::Unique { is_primary: true }))
+                })
+                .into_iter()
+                .collect(),
+        }
+    }
+
+    pub fn column_index(&self, column_name: &str) -> Option<usize> {
+        self.columns
+            .iter()
+            .position(|c| c.name.as_ref() == column_name)
+    }
+
+    /// Position of the column's value in the table's records. Rowid tables store columns
+    /// in table order, WITHOUT ROWID tables store the primary key columns first followed
+    /// by the remaining columns in table order.
+    pub fn record_index(&self, column_index: usize) -> usize {
+        if !self.without_rowid {
+            return column_index;
+        }
+        match self
+            .primary_key
+            .iter()
+            .position(|(i, _)| *i == column_index)
+        {
+            Some(key_index) => key_index,
+            None => {
+                self.primary_key.len()
+                    + (0..column_index)
+                        .filter(|i| !self.primary_key.iter().any(|(k, _)| k == i))
+                        .count()
+            }
+        }
+    }
+
+    /// Whether reading the column returns the rowid, either through the INTEGER PRIMARY KEY
+    /// alias or one of the rowid, _rowid_ and oid names when no real column uses them
+    pub fn is_rowid_column(&self, column_name: &str) -> bool {
+        if self.without_rowid {
+            return false;
+        }
+        match self.column_index(column_name) {
+            Some(index) => self.rowid_alias == Some(index),
+            None => ["rowid", "_rowid_", "oid"]
+                .iter()
+                .any(|n| n.eq_ignore_ascii_case(column_name)),
+        }
+    }
 }
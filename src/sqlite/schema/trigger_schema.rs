@@ -0,0 +1,89 @@
+use std::{fmt, sync::Arc};
+
+use sqlparser::{ast, dialect::SQLiteDialect, parser::Parser, tokenizer::Token};
+
+use super::{
+    super::error::{Context, Result},
+    schema_parser::{self, TriggerEvent, TriggerTiming},
+};
+
+static DIALECT: SQLiteDialect = SQLiteDialect {};
+
+#[derive(Debug)]
+pub struct TriggerSchema {
+    pub row_id: i64,
+    pub name: Arc<str>,
+    pub table_name: Arc<str>,
+    pub sql: String,
+    pub timing: TriggerTiming,
+    pub event: TriggerEvent,
+    pub when_clause: Option<TriggerSql<ast::Expr>>,
+    /// The statements run when the trigger fires, they can refer to the affected row
+    /// through NEW.column and OLD.column
+    pub body: Vec<TriggerSql<ast::Statement>>,
+}
+
+/// A WHEN clause or body statement of a trigger, kept as it's written in the schema
+/// when sqlparser can't parse it so the database still opens
+#[derive(Debug)]
+pub enum TriggerSql<T> {
+    Parsed(T),
+    Raw(String),
+}
+
+impl<T: fmt::Display> fmt::Display for TriggerSql<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TriggerSql::Parsed(parsed) => write!(f, "{}", parsed),
+            TriggerSql::Raw(sql) => write!(f, "{}", sql),
+        }
+    }
+}
+
+impl TriggerSchema {
+    pub fn new(
+        row_id: i64,
+        name: Arc<str>,
+        table_name: Arc<str>,
+        sql: String,
+    ) -> Result<TriggerSchema> {
+        let trigger = schema_parser::parse_create_trigger(&sql)
+            .with_context(|| format!("failed to parse schema for trigger {}", name))?;
+
+        let when_clause = trigger
+            .when_clause
+            .map(|when| match TriggerSchema::parse_when(&when) {
+                Ok(expr) => TriggerSql::Parsed(expr),
+                Err(_) => TriggerSql::Raw(when),
+            });
+
+        let mut body = Vec::new();
+        for statement in trigger.body {
+            match Parser::parse_sql(&DIALECT, &statement) {
+                Ok(parsed) => body.extend(parsed.into_iter().map(TriggerSql::Parsed)),
+                Err(_) => body.push(TriggerSql::Raw(statement)),
+            }
+        }
+
+        Ok(TriggerSchema {
+            row_id,
+            name,
+            table_name,
+            sql,
+            timing: trigger.timing,
+            event: trigger.event,
+            when_clause,
+            body,
+        })
+    }
+
+    /// Parses a WHEN clause, which has to be a single expression
+    fn parse_when(when: &str) -> Result<ast::Expr> {
+        let mut parser = Parser::new(&DIALECT).try_with_sql(when)?;
+        let expr = parser.parse_expr()?;
+        parser
+            .expect_token(&Token::EOF)
+            .context("WHEN clause must be a single expression")?;
+        Ok(expr)
+    }
+}
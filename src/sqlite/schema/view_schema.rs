@@ -0,0 +1,68 @@
+use std::sync::Arc;
+
+use sqlparser::{ast, dialect::SQLiteDialect, parser::Parser};
+
+use crate::sqlite::error::{bail, Context, Result};
+
+static DIALECT: SQLiteDialect = SQLiteDialect {};
+
+#[derive(Debug)]
+pub struct ViewSchema {
+    pub row_id: i64,
+    pub name: Arc<str>,
+    pub sql: String,
+    /// The column names of `CREATE VIEW v(a, b) AS ...`, empty when the view uses the
+    /// names of its SELECT's result columns
+    pub columns: Vec<Arc<str>>,
+    /// None when sqlparser can't parse the view, see [`ViewSchema::query`]
+    pub query: Option<Box<ast::Query>>,
+}
+
+impl ViewSchema {
+    pub fn new(row_id: i64, name: Arc<str>, sql: String) -> Result<ViewSchema> {
+        // a view sqlparser rejects must not stop the database from opening, so it's kept
+        // with its raw sql and fails when it's queried
+        let (columns, query) = match Self::parse(&name, &sql) {
+            Ok((columns, query)) => (columns, Some(query)),
+            Err(_) => (Vec::new(), None),
+        };
+
+        Ok(ViewSchema {
+            row_id,
+            name,
+            sql,
+            columns,
+            query,
+        })
+    }
+
+    /// The SELECT of the view, or the error sqlparser gave for its sql
+    pub fn query(&self) -> Result<&ast::Query> {
+        match &self.query {
+            Some(query) => Ok(query),
+            None => {
+                Self::parse(&self.name, &self.sql)?;
+                bail!(
+                    Corrupt,
+                    "view {} failed to parse when the schema was read",
+                    self.name
+                )
+            }
+        }
+    }
+
+    fn parse(name: &str, sql: &str) -> Result<(Vec<Arc<str>>, Box<ast::Query>)> {
+        let mut ast = Parser::parse_sql(&DIALECT, sql)
+            .with_context(|| format!("failed to parse schema for view {}", name))?;
+        if ast.len() != 1 {
+            bail!(Corrupt, "view schema sql can only have 1 expression");
+        }
+        let ast::Statement::CreateView { columns, query, .. } = ast.remove(0) else {
+            bail!(Corrupt, "create view statement expected")
+        };
+        Ok((
+            columns.into_iter().map(|c| Arc::from(c.value)).collect(),
+            query,
+        ))
+    }
+}
@@ -1,17 +1,19 @@
 
 //Select count()
-use anyhow::{bail, Error, Ok, Result};
+use crate::sqlite::error::{bail, error, Error, Result};
 
 use itertools::Itertools;
 use sqlparser::ast;
 use sqlparser::ast::{
-    BinaryOperator, Expr, Function, Ident, Select, SelectItem, SetExpr, Statement,
+    BinaryOperator, Expr, Function, Ident, Select, SelectItem, SetExpr, Statement, UnaryOperator,
 };
 
 use crate::sqlite::record::CellValue;
 #[derive(Debug)]
 pub enum Query {
     Select(SelectQuery),
+    Insert(InsertQuery),
+    Delete(DeleteQuery),
 }
 impl TryFrom<&Statement> for Query {
     type Error = Error;
@@ -19,7 +21,54 @@ impl TryFrom<&Statement> for Query {
     fn try_from(value: &Statement) -> Result<Self> {
         match value {
             Statement::Query(q) => Ok(Query::Select(q.as_ref().try_into()?)),
-            s => bail!("{} is not currently supported", s),
+            Statement::Insert {
+                table_name,
+                columns,
+                source,
+                or: None,
+                on: None,
+                returning: None,
+                ..
+            } => {
+                if table_name.0.len() != 1 {
+                    bail!(Unsupported, "only single value table names are supported");
+                }
+                let SetExpr::Values(values) = source.body.as_ref() else {
+                    bail!(
+                        Unsupported,
+                        "only INSERT with VALUES is currently supported"
+                    );
+                };
+                Ok(Query::Insert(InsertQuery {
+                    table: table_name.0[0].value.to_owned(),
+                    columns: columns.iter().map(|c| c.value.to_owned()).collect(),
+                    rows: values
+                        .rows
+                        .iter()
+                        .map(|row| row.iter().map(|e| e.try_into()).try_collect())
+                        .try_collect()?,
+                }))
+            }
+            Statement::Delete {
+                tables,
+                from,
+                using: None,
+                selection,
+                returning: None,
+            } if tables.is_empty() => {
+                let table = match &from[..] {
+                    [ast::TableWithJoins {
+                        relation: ast::TableFactor::Table { name, .. },
+                        joins,
+                    }] if joins.is_empty() && name.0.len() == 1 => name.0[0].value.to_owned(),
+                    _ => bail!(Unsupported, "only DELETE from a single table is supported"),
+                };
+                Ok(Query::Delete(DeleteQuery {
+                    table,
+                    clause: selection.as_ref().map(|s| s.try_into()).transpose()?,
+                }))
+            }
+            s => bail!(Unsupported, "{} is not currently supported", s),
         }
     }
 }
@@ -30,12 +79,28 @@ impl TryFrom<&ast::Query> for SelectQuery {
     fn try_from(value: &ast::Query) -> Result<Self> {
         match value.body.as_ref() {
             sqlparser::ast::SetExpr::Select(select) => Ok(SelectQuery::new(select)?),
-            e => bail!("{} queries are not currently supported", e),
+            e => bail!(Unsupported, "{} queries are not currently supported", e),
         }
     }
 }
 
+/// `INSERT INTO table (columns) VALUES (...), (...)`
+#[derive(Debug)]
+pub struct InsertQuery {
+    pub table: String,
+    /// Empty when the values are given for every column in table order
+    pub columns: Vec<String>,
+    pub rows: Vec<Vec<Expression>>,
+}
+
+/// `DELETE FROM table WHERE ...`
 #[derive(Debug)]
+pub struct DeleteQuery {
+    pub table: String,
+    pub clause: Option<Expression>,
+}
+
+#[derive(Debug, Clone)]
 pub struct SelectQuery {
     pub selections: Vec<Selection>,
     pub sources: Vec<Source>,
@@ -51,7 +116,7 @@ impl SelectQuery {
             .try_collect()?;
 
         if select.from.len() != 1 {
-            bail!("only a single source is currently supported");
+            bail!(Unsupported, "only a single source is currently supported");
         }
 
         let sources: Vec<Source> = select
@@ -60,11 +125,11 @@ impl SelectQuery {
             .map(|f| match &f.relation {
                 sqlparser::ast::TableFactor::Table { name, .. } => {
                     if name.0.len() != 1 {
-                        bail!("only single value table names are supported");
+                        bail!(Unsupported, "only single value table names are supported");
                     }
                     Ok(Source::Table(name.0[0].value.to_owned()))
                 }
-                _ => bail!("Only table sources are currently supported"),
+                _ => bail!(Unsupported, "Only table sources are currently supported"),
             })
             .try_collect()?;
 
@@ -86,22 +151,26 @@ impl Query {
     pub fn new(mut ast: Vec<Statement>) -> Result<Self> {
         let exp = match (ast.pop(), ast.pop()) {
             (Some(s), None) => s,
-            _ => bail!("only a single expression is currently supported"),
+            _ => bail!(
+                Unsupported,
+                "only a single expression is currently supported"
+            ),
         };
 
         Ok(match exp {
             Statement::Query(q) => match *q.body {
                 SetExpr::Select(select) => Query::Select(SelectQuery::new(&select)?),
-                e => bail!("{} queries are not currently supported", e),
+                e => bail!(Unsupported, "{} queries are not currently supported", e),
             },
-            q => bail!("{} queries are not currently supported", q),
+            q => bail!(Unsupported, "{} queries are not currently supported", q),
         })
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum Selection {
     Identifier(String),
+    Wildcard,
     AggFn(AggregateFunction),
 }
 
@@ -112,27 +181,31 @@ impl TryFrom<&SelectItem> for Selection {
         Ok(match value {
             SelectItem::UnnamedExpr(Expr::Function(Function { name, .. })) => {
                 if name.0.len() != 1 {
-                    bail!("only single field value functions are supported");
+                    bail!(
+                        Unsupported,
+                        "only single field value functions are supported"
+                    );
                 }
                 match name.0[0].value.to_lowercase().as_ref() {
                     "count" => Selection::AggFn(AggregateFunction::Count),
-                    f => bail!("{} is not a supported select function", f),
+                    f => bail!(Unsupported, "{} is not a supported select function", f),
                 }
             }
             SelectItem::UnnamedExpr(Expr::Identifier(Ident { value, .. })) => {
                 Selection::Identifier(value.to_owned())
             }
-            t => bail!("{} is not a supported selection type", t),
+            SelectItem::Wildcard(_) => Selection::Wildcard,
+            t => bail!(Unsupported, "{} is not a supported selection type", t),
         })
     }
 }
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum AggregateFunction {
     Count,
     // Sum(Identefier)
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum Source {
     Table(String),
 }
@@ -143,14 +216,18 @@ impl TryFrom<&Expr> for Expression {
         Ok(match value {
             Expr::Identifier(ident) => Expression::Identifier(ident.value.to_owned()),
             Expr::Value(v) => match v {
-                sqlparser::ast::Value::Number(_, _) => todo!(),
+                sqlparser::ast::Value::Number(n, _) => Expression::Literal(
+                    n.parse::<i64>()
+                        .map(CellValue::Int)
+                        .or_else(|_| n.parse::<f64>().map(CellValue::Float))
+                        .map_err(|_| error!(Parse, "{} is not a valid number", n))?,
+                ),
                 sqlparser::ast::Value::RawStringLiteral(s)
                 | sqlparser::ast::Value::SingleQuotedString(s)
                 | sqlparser::ast::Value::EscapedStringLiteral(s)
                 | sqlparser::ast::Value::SingleQuotedByteStringLiteral(s)
                 | sqlparser::ast::Value::DoubleQuotedByteStringLiteral(s)
                 | sqlparser::ast::Value::NationalStringLiteral(s)
-                | sqlparser::ast::Value::HexStringLiteral(s)
                 | sqlparser::ast::Value::Placeholder(s)
                 | sqlparser::ast::Value::UnQuotedString(s)
                 | sqlparser::ast::Value::DoubleQuotedString(s) => {
@@ -161,23 +238,72 @@ impl TryFrom<&Expr> for Expression {
                     false => Expression::Literal(CellValue::Int(0)),
                 },
                 sqlparser::ast::Value::Null => Expression::Literal(CellValue::Null),
-                e => bail!("{} is as unsupported expression type", e),
+                // X'0a1B' is a blob of the bytes the pairs of hex digits spell out
+                sqlparser::ast::Value::HexStringLiteral(s) => {
+                    let bytes = (0..s.len())
+                        .step_by(2)
+                        .map(|i| s.get(i..i + 2).and_then(|b| u8::from_str_radix(b, 16).ok()))
+                        .collect::<Option<Vec<_>>>()
+                        .ok_or_else(|| error!(Parse, "X'{}' is not a valid blob literal", s))?;
+                    Expression::Literal(CellValue::Blob(bytes))
+                }
+                e => bail!(Unsupported, "{} is as unsupported expression type", e),
             },
             Expr::BinaryOp { left, op, right } => Expression::InfixExpression(
                 Box::new(left.as_ref().try_into()?),
                 op.try_into()?,
                 Box::new(right.as_ref().try_into()?),
             ),
-            _ => todo!(),
+            Expr::Between {
+                expr,
+                negated: false,
+                low,
+                high,
+            } => Expression::Between(
+                Box::new(expr.as_ref().try_into()?),
+                Box::new(low.as_ref().try_into()?),
+                Box::new(high.as_ref().try_into()?),
+            ),
+            Expr::InList {
+                expr,
+                list,
+                negated: false,
+            } => Expression::InList(
+                Box::new(expr.as_ref().try_into()?),
+                list.iter().map(|e| e.try_into()).try_collect()?,
+            ),
+            Expr::Nested(e) => e.as_ref().try_into()?,
+            Expr::UnaryOp {
+                op: op @ (UnaryOperator::Minus | UnaryOperator::Plus),
+                expr,
+            } => match (op, expr.as_ref().try_into()?) {
+                (
+                    UnaryOperator::Plus,
+                    e @ Expression::Literal(CellValue::Int(_) | CellValue::Float(_)),
+                ) => e,
+                (_, Expression::Literal(CellValue::Int(i))) => Expression::Literal(
+                    i.checked_neg()
+                        .map_or(CellValue::Float(-(i as f64)), CellValue::Int),
+                ),
+                (_, Expression::Literal(CellValue::Float(f))) => {
+                    Expression::Literal(CellValue::Float(-f))
+                }
+                _ => bail!(Unsupported, "{} is an unsupported expression", value),
+            },
+            e => bail!(Unsupported, "{} is an unsupported expression", e),
         })
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum Expression {
     InfixExpression(Box<Expression>, Operator, Box<Expression>),
     Literal(CellValue),
     Identifier(String),
+    /// `value BETWEEN low AND high`
+    Between(Box<Expression>, Box<Expression>, Box<Expression>),
+    /// `value IN (a, b, ...)`
+    InList(Box<Expression>, Vec<Expression>),
 }
 
 impl Expression {
@@ -191,6 +317,17 @@ impl Expression {
 
             Expression::Literal(_) => vec![],
             Expression::Identifier(i) => vec![i.to_owned()],
+            Expression::Between(value, low, high) => value
+                .get_columns()
+                .into_iter()
+                .chain(low.get_columns())
+                .chain(high.get_columns())
+                .collect_vec(),
+            Expression::InList(value, list) => value
+                .get_columns()
+                .into_iter()
+                .chain(list.iter().flat_map(|e| e.get_columns()))
+                .collect_vec(),
         }
     }
 }
@@ -198,13 +335,17 @@ impl Expression {
 #[derive(Debug)]
 pub enum Object {
     Bool(bool),
-    String(String),
+    Value(CellValue),
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Operator {
     Equal,
     NotEqual,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
     // Like,
     And,
     Or,
@@ -217,9 +358,26 @@ impl TryFrom<&BinaryOperator> for Operator {
         Ok(match value {
             BinaryOperator::Eq => Operator::Equal,
             BinaryOperator::NotEq => Operator::NotEqual,
+            BinaryOperator::Lt => Operator::Less,
+            BinaryOperator::LtEq => Operator::LessEqual,
+            BinaryOperator::Gt => Operator::Greater,
+            BinaryOperator::GtEq => Operator::GreaterEqual,
             BinaryOperator::And => Operator::And,
             BinaryOperator::Or => Operator::Or,
-            o => bail!("{} is an unsupported opperator", o),
+            o => bail!(Unsupported, "{} is an unsupported opperator", o),
         })
     }
 }
+
+impl Operator {
+    /// The operator that gives the same result with its operands swapped, `5 < a` is `a > 5`
+    pub fn flip(self) -> Operator {
+        match self {
+            Operator::Less => Operator::Greater,
+            Operator::LessEqual => Operator::GreaterEqual,
+            Operator::Greater => Operator::Less,
+            Operator::GreaterEqual => Operator::LessEqual,
+            op => op,
+        }
+    }
+}
@@ -0,0 +1,267 @@
+// Where the bytes of a database come from. The database only asks for whole pages, so
+// anything that can hand out a range of bytes can hold one: a file read through the
+// usual system calls, a file mapped into memory or a buffer that never touched disk.
+// Reads name their offset instead of moving a shared position, so one storage can
+// serve many threads at once. Only a file opened for writing can be written to, and
+// only a file can be locked against other processes.
+
+use std::{
+    fs::{self, File},
+    io,
+    sync::{Mutex, PoisonError},
+};
+
+use memmap2::Mmap;
+
+use super::{
+    error::{bail, Context, Result},
+    lock::{self, LockLevel},
+};
+
+pub trait Storage: Send + Sync {
+    /// Fills the buffer with the bytes starting at the offset, failing if the storage
+    /// ends first
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<()>;
+
+    /// The size in bytes
+    fn size(&self) -> Result<u64>;
+
+    fn writable(&self) -> bool {
+        false
+    }
+
+    /// Writes the buffer at the offset, growing the storage when it ends before it
+    fn write_at(&self, _offset: u64, _buf: &[u8]) -> Result<()> {
+        bail!(Unsupported, "the database storage is read only")
+    }
+
+    /// Cuts the storage down to the size in bytes
+    fn truncate(&self, _size: u64) -> Result<()> {
+        bail!(Unsupported, "the database storage is read only")
+    }
+
+    /// Waits for everything written so far to reach the disk
+    fn sync(&self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Moves the lock held on the database to the level, storage no other process can
+    /// see needs none
+    fn lock(&self, _level: LockLevel) -> Result<()> {
+        Ok(())
+    }
+
+    /// Whether a writer in another process is in the middle of a transaction
+    fn reserved_by_other(&self) -> Result<bool> {
+        Ok(false)
+    }
+
+    /// Reads a page, whose size is the length of the buffer. Pages start at 1.
+    fn read_page(&self, page_number: u32, page: &mut [u8]) -> Result<()> {
+        if page_number == 0 {
+            bail!(Corrupt, "pages start at index 1");
+        }
+        self.read_at((page_number - 1) as u64 * page.len() as u64, page)
+    }
+}
+
+pub struct FileStorage {
+    file: File,
+    writable: bool,
+    lock: Mutex<LockLevel>,
+}
+
+impl FileStorage {
+    /// Opens the file for reading, and for writing too when asked to and allowed to
+    pub fn open(path: &str, writable: bool) -> Result<FileStorage> {
+        let file = fs::OpenOptions::new().read(true).write(writable).open(path);
+        Ok(match file {
+            // like sqlite a file that can't be written to is still opened to be read
+            Err(e) if writable && e.kind() == io::ErrorKind::PermissionDenied => FileStorage {
+                file: File::open(path)?,
+                writable: false,
+                lock: Mutex::new(LockLevel::None),
+            },
+            file => FileStorage {
+                file: file?,
+                writable,
+                lock: Mutex::new(LockLevel::None),
+            },
+        })
+    }
+
+    pub fn new(file: File) -> FileStorage {
+        FileStorage {
+            file,
+            writable: false,
+            lock: Mutex::new(LockLevel::None),
+        }
+    }
+}
+
+impl Storage for FileStorage {
+    #[cfg(unix)]
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<()> {
+        use std::os::unix::fs::FileExt;
+        self.file.read_exact_at(buf, offset)?;
+        Ok(())
+    }
+
+    #[cfg(windows)]
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<()> {
+        use std::os::windows::fs::FileExt;
+        let mut read = 0;
+        while read < buf.len() {
+            let n = self
+                .file
+                .seek_read(&mut buf[read..], offset + read as u64)?;
+            if n == 0 {
+                bail!(
+                    Corrupt,
+                    "read of {} bytes at offset {} ended early",
+                    buf.len(),
+                    offset
+                );
+            }
+            read += n;
+        }
+        Ok(())
+    }
+
+    fn size(&self) -> Result<u64> {
+        Ok(self.file.metadata()?.len())
+    }
+
+    fn writable(&self) -> bool {
+        self.writable
+    }
+
+    #[cfg(unix)]
+    fn write_at(&self, offset: u64, buf: &[u8]) -> Result<()> {
+        use std::os::unix::fs::FileExt;
+        self.file.write_all_at(buf, offset)?;
+        Ok(())
+    }
+
+    #[cfg(windows)]
+    fn write_at(&self, offset: u64, buf: &[u8]) -> Result<()> {
+        use std::os::windows::fs::FileExt;
+        let mut written = 0;
+        while written < buf.len() {
+            let n = self
+                .file
+                .seek_write(&buf[written..], offset + written as u64)?;
+            if n == 0 {
+                return Err(io::Error::from(io::ErrorKind::WriteZero).into());
+            }
+            written += n;
+        }
+        Ok(())
+    }
+
+    fn truncate(&self, size: u64) -> Result<()> {
+        self.file.set_len(size)?;
+        Ok(())
+    }
+
+    fn sync(&self) -> Result<()> {
+        self.file.sync_all()?;
+        Ok(())
+    }
+
+    fn lock(&self, level: LockLevel) -> Result<()> {
+        set_lock(&self.file, &self.lock, level)
+    }
+
+    fn reserved_by_other(&self) -> Result<bool> {
+        lock::reserved_by_other(&self.file)
+    }
+}
+
+/// A file mapped into memory, pages are copied straight out of the mapping.
+///
+/// The mapping is only sound while the file doesn't shrink. Reading a part of the
+/// mapping that a truncation cut off raises SIGBUS, which kills the process instead of
+/// failing the read. sqlite truncates a database on VACUUM, on auto-vacuum and when it
+/// rolls back a journal, so only map a file no other process writes to.
+pub struct MmapStorage {
+    map: Mmap,
+    // kept open, closing it would drop the locks held on the file
+    file: File,
+    lock: Mutex<LockLevel>,
+}
+
+impl MmapStorage {
+    pub fn open(path: &str) -> Result<MmapStorage> {
+        let file = File::open(path)?;
+        // SAFETY: the mapping is only read, so a writer changing bytes underneath it is
+        // no different from one changing them between two reads of a FileStorage. A
+        // writer shrinking the file is not covered, see the warning on MmapStorage.
+        let map = unsafe { Mmap::map(&file) }.with_context(|| format!("failed to map {}", path))?;
+        Ok(MmapStorage {
+            map,
+            file,
+            lock: Mutex::new(LockLevel::None),
+        })
+    }
+}
+
+impl Storage for MmapStorage {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<()> {
+        read_slice(&self.map, offset, buf)
+    }
+
+    fn size(&self) -> Result<u64> {
+        Ok(self.map.len() as u64)
+    }
+
+    fn lock(&self, level: LockLevel) -> Result<()> {
+        set_lock(&self.file, &self.lock, level)
+    }
+
+    fn reserved_by_other(&self) -> Result<bool> {
+        lock::reserved_by_other(&self.file)
+    }
+}
+
+fn set_lock(file: &File, held: &Mutex<LockLevel>, level: LockLevel) -> Result<()> {
+    let mut held = held.lock().unwrap_or_else(PoisonError::into_inner);
+    lock::set_level(file, *held, level)?;
+    *held = level;
+    Ok(())
+}
+
+/// A database held in memory, such as a `Vec<u8>` or a `&'static [u8]`
+pub struct MemoryStorage<T> {
+    data: T,
+}
+
+impl<T: AsRef<[u8]>> MemoryStorage<T> {
+    pub fn new(data: T) -> MemoryStorage<T> {
+        MemoryStorage { data }
+    }
+}
+
+impl<T: AsRef<[u8]> + Send + Sync> Storage for MemoryStorage<T> {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<()> {
+        read_slice(self.data.as_ref(), offset, buf)
+    }
+
+    fn size(&self) -> Result<u64> {
+        Ok(self.data.as_ref().len() as u64)
+    }
+}
+
+fn read_slice(data: &[u8], offset: u64, buf: &mut [u8]) -> Result<()> {
+    let start = offset as usize;
+    let Some(bytes) = data.get(start..start + buf.len()) else {
+        bail!(
+            Corrupt,
+            "read of {} bytes at offset {} is past the end of the database",
+            buf.len(),
+            offset
+        );
+    };
+    buf.copy_from_slice(bytes);
+    Ok(())
+}
@@ -1,11 +1,89 @@
+use std::{
+    ops::Bound,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
 use sqlparser::{dialect::SQLiteDialect, parser::Parser};
 
 use crate::sqlite;
 
-use super::record::CellValue;
+use super::{
+    connection::{Connection, DatabaseHeader, TextEncoding},
+    database::OpenOptions,
+    error::{Error, Result},
+    page::TablePage,
+    page_cache::{CacheStats, PageCache},
+    record::{codec, CellType, CellValue},
+    schema::{
+        schema_parser::{self, SortOrder, TriggerEvent, TriggerTiming},
+        table_schema::TableSchema,
+        trigger_schema::TriggerSql,
+        SqliteSchema,
+    },
+};
 
 static DIALECT: SQLiteDialect = SQLiteDialect {};
 
+/// A database in a directory of its own under the temp dir, which is removed when the
+/// test is done with it however the test ends
+struct TempDb {
+    dir: PathBuf,
+    path: String,
+}
+
+impl TempDb {
+    /// Copies a fixture along with its journal when it has one
+    fn copy(fixture: &str) -> TempDb {
+        let db = TempDb::new(fixture, &std::fs::read(fixture).unwrap());
+        let journal = format!("{}-journal", fixture);
+        if std::path::Path::new(&journal).exists() {
+            std::fs::copy(journal, db.journal()).unwrap();
+        }
+        db
+    }
+
+    /// A database holding the given bytes, named like the file they came from
+    fn new(name: &str, data: &[u8]) -> TempDb {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        let count = COUNT.fetch_add(1, Ordering::Relaxed);
+        let dir =
+            std::env::temp_dir().join(format!("rusty_sqlite_{}_{}", std::process::id(), count));
+        std::fs::create_dir_all(&dir).unwrap();
+        let name = std::path::Path::new(name).file_name().unwrap();
+        let path = dir.join(name).to_str().unwrap().to_string();
+        std::fs::write(&path, data).unwrap();
+        TempDb { dir, path }
+    }
+
+    fn path(&self) -> &str {
+        &self.path
+    }
+
+    fn journal(&self) -> String {
+        format!("{}-journal", self.path)
+    }
+
+    /// What sqlite itself finds wrong with the database, just "ok" when it's fine
+    fn sqlite_integrity_check(&self) -> Vec<String> {
+        let flags = rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY;
+        let conn = rusqlite::Connection::open_with_flags(&self.path, flags).unwrap();
+        let mut statement = conn.prepare("PRAGMA integrity_check").unwrap();
+        let rows = statement.query_map([], |row| row.get(0)).unwrap();
+        rows.collect::<rusqlite::Result<_>>().unwrap()
+    }
+}
+
+impl Drop for TempDb {
+    fn drop(&mut self) {
+        // a failed test can't fail again here
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
 #[test]
 fn sql_test() {
     let _ = sqlite::open("sample.db");
@@ -19,3 +97,1387 @@ fn sql_test() {
 fn sort_test() {
     assert!(CellValue::String("aaa".to_string()) < CellValue::String("bbb".to_string()));
 }
+
+#[test]
+fn overflow_payload_test() {
+    let conn = sqlite::open("tests/fixtures/overflow.db").unwrap();
+    let tree = conn.get_tree("docs").unwrap();
+    let rows = tree
+        .row_reader(conn.get_db())
+        .map(|row| {
+            let row = row.unwrap();
+            (row.read_column("body").unwrap(), row.read_column("data").unwrap())
+        })
+        .collect::<Vec<_>>();
+
+    assert_eq!(rows.len(), 3);
+    let (CellValue::String(body), CellValue::Blob(data)) = &rows[1] else {
+        panic!("unexpected cell types {:?}", rows[1]);
+    };
+    assert_eq!(body.len(), 5003);
+    assert!(body.starts_with("aaaa") && body.ends_with("end"));
+    assert_eq!(data.len(), 3000);
+    assert_eq!(rows[2].0, CellValue::String("b".repeat(1100)));
+}
+
+#[test]
+fn database_header_test() {
+    let mut buffer = [0u8; 100];
+    let file = std::fs::read("sample.db").unwrap();
+    buffer.copy_from_slice(&file[..100]);
+
+    let header = DatabaseHeader::parse(&buffer).unwrap();
+    assert_eq!(header.page_size, 4096);
+    assert_eq!(header.database_size, 4);
+    assert_eq!(header.schema_format, 4);
+    assert_eq!(header.text_encoding, TextEncoding::Utf8);
+    assert_eq!(header.sqlite_version_number, 3034000);
+
+    let mut bad_magic = buffer;
+    bad_magic[0] = b'X';
+    assert!(DatabaseHeader::parse(&bad_magic).is_err());
+
+    let mut bad_page_size = buffer;
+    bad_page_size[16..18].copy_from_slice(&1000u16.to_be_bytes());
+    assert!(DatabaseHeader::parse(&bad_page_size).is_err());
+}
+
+#[test]
+fn utf16_database_test() {
+    for (path, encoding) in [
+        ("tests/fixtures/utf16.db", TextEncoding::Utf16le),
+        ("tests/fixtures/utf16be.db", TextEncoding::Utf16be),
+    ] {
+        let conn = sqlite::open(path).unwrap();
+        assert_eq!(conn.get_header().text_encoding, encoding);
+
+        let index = conn.get_index_tree("words", "lang").unwrap();
+        let row_ids = index
+            .get_row_ids(conn.get_db(), &CellValue::String("ja".to_string()))
+            .unwrap()
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(row_ids, vec![2]);
+
+        let tree = conn.get_tree("words").unwrap();
+        let row = tree.get_row(conn.get_db(), 2).unwrap();
+        assert_eq!(
+            row.read_column("word").unwrap(),
+            CellValue::String("日本語".to_string())
+        );
+    }
+}
+
+#[test]
+fn multi_page_schema_test() {
+    let conn = sqlite::open("tests/fixtures/many_tables.db").unwrap();
+    assert_eq!(conn.get_schema().len(), 40);
+
+    let tree = conn.get_tree("table_37").unwrap();
+    let row = tree.get_row(conn.get_db(), 1).unwrap();
+    assert_eq!(
+        row.read_column("name").unwrap(),
+        CellValue::String("thirty seven".to_string())
+    );
+}
+
+#[test]
+fn record_codec_test() {
+    // header: size 7, int8, int16, int24, int48, zero, one
+    let payload = [
+        7, 1, 2, 3, 5, 8, 9, 0xff, 0xfe, 0x0c, 0x80, 0x00, 0x00, 0xff, 0xff, 0xff, 0xff, 0xff,
+        0xfd,
+    ];
+    let values = codec::decode_record(&payload, TextEncoding::Utf8).unwrap();
+    assert_eq!(
+        values,
+        vec![
+            CellValue::Int(-1),
+            CellValue::Int(-500),
+            CellValue::Int(-8388608),
+            CellValue::Int(-3),
+            CellValue::Int(0),
+            CellValue::Int(1),
+        ]
+    );
+    // the encoder picks the smallest serial type, so -3 moves from 48 bits to 8
+    let encoded = codec::encode_record(&values, TextEncoding::Utf8, 4);
+    assert_eq!(encoded[..7], [7, 1, 2, 3, 1, 8, 9]);
+    assert_eq!(codec::decode_record(&encoded, TextEncoding::Utf8).unwrap(), values);
+    // older schema formats store 0 and 1 as 8-bit integers
+    let encoded = codec::encode_record(&values, TextEncoding::Utf8, 3);
+    assert_eq!(encoded[..7], [7, 1, 2, 3, 1, 1, 1]);
+    assert_eq!(
+        codec::decode_record(&encoded, TextEncoding::Utf8).unwrap(),
+        values
+    );
+
+    assert!(CellType::from_serial_type(10).is_err());
+    assert_eq!(CellType::from_serial_type(13).unwrap(), CellType::String(0));
+    assert_eq!(CellType::from_serial_type(19).unwrap(), CellType::String(3));
+    assert_eq!(CellType::from_serial_type(18).unwrap(), CellType::Blob(3));
+}
+
+#[test]
+fn record_codec_round_trip_property_test() {
+    // small xorshift generator so the property test is deterministic
+    let mut state = 0x2545_f491_4f6c_dd1d_u64;
+    let mut next = move || {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        state
+    };
+
+    for _ in 0..2000 {
+        let value = next() as i64 >> (next() % 64);
+        let varint = codec::encode_varint(value);
+        let decoded = codec::decode_varint(&varint).unwrap();
+        assert_eq!((decoded.value, decoded.size as usize), (value, varint.len()));
+
+        let values = (0..next() % 12)
+            .map(|_| match next() % 5 {
+                0 => CellValue::Null,
+                1 => CellValue::Int(next() as i64 >> (next() % 64)),
+                2 => CellValue::Float(f64::from_bits(next() >> 2)),
+                3 => CellValue::Blob((0..next() % 300).map(|_| next() as u8).collect()),
+                _ => CellValue::String(
+                    (0..next() % 300)
+                        .map(|_| char::from_u32((next() % 0xd000) as u32).unwrap_or('x'))
+                        .collect(),
+                ),
+            })
+            .collect::<Vec<_>>();
+
+        for encoding in [
+            TextEncoding::Utf8,
+            TextEncoding::Utf16le,
+            TextEncoding::Utf16be,
+        ] {
+            let payload = codec::encode_record(&values, encoding, 4);
+            assert_eq!(codec::decode_record(&payload, encoding).unwrap(), values);
+        }
+    }
+}
+
+#[test]
+fn rowid_alias_test() {
+    let conn = sqlite::open("tests/fixtures/rowid.db").unwrap();
+    let read = |table: &str, column: &str| {
+        let tree = conn.get_tree(table).unwrap();
+        let row = tree.row_reader(conn.get_db()).next().unwrap().unwrap();
+        row.read_column(column).unwrap()
+    };
+
+    assert_eq!(read("users", "user_id"), CellValue::Int(10));
+    assert_eq!(read("users", "oid"), CellValue::Int(10));
+    assert_eq!(read("pairs", "a"), CellValue::Int(7));
+    assert_eq!(read("tags", "id"), CellValue::String("t-1".to_string()));
+    assert_eq!(read("tags", "_rowid_"), CellValue::Int(1));
+    assert_eq!(
+        read("ints", "rowid"),
+        CellValue::String("real rowid column".to_string())
+    );
+}
+
+#[test]
+fn fallback_create_table_parser_test() {
+    let conn = sqlite::open("tests/fixtures/schema.db").unwrap();
+    let names = conn
+        .get_schema()
+        .iter()
+        .map(|s| s.get_name().to_string())
+        .collect::<Vec<_>>();
+    assert_eq!(names, vec!["t", "odd name", "checks"]);
+
+    let schema = conn.get_db().get_table_schema("odd name").unwrap();
+    let SqliteSchema::Table(odd) = schema.as_ref() else {
+        panic!("expected a table schema");
+    };
+    let affinities = odd
+        .columns
+        .iter()
+        .map(|c| (c.name.to_string(), c.type_affinity.to_string()))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        affinities,
+        vec![
+            ("first col".to_string(), "TEXT".to_string()),
+            ("second".to_string(), "INT".to_string()),
+            ("third".to_string(), "REAL".to_string()),
+            ("fourth".to_string(), "INT".to_string()),
+            ("fifth".to_string(), "BLOB".to_string()),
+        ]
+    );
+
+    let tree = conn.get_tree("checks").unwrap();
+    let row = tree.row_reader(conn.get_db()).next().unwrap().unwrap();
+    assert_eq!(row.read_column("x").unwrap(), CellValue::Int(42));
+
+    let desc = schema_parser::parse_create_table(
+        "CREATE TABLE d (id INTEGER PRIMARY KEY DESC, [v] TEXT COLLATE NOCASE UNIQUE)",
+    )
+    .unwrap();
+    assert_eq!(desc.rowid_alias(), None);
+    let asc = schema_parser::parse_create_table("create table a (id integer primary key asc)");
+    assert_eq!(asc.unwrap().rowid_alias(), Some(0));
+
+    // tables sqlparser reads get their affinities from the same rules
+    let sql = "create table c (x character large object, y double precision, z boolean)";
+    let table = TableSchema::new(0, "c".into(), "c".into(), 2, sql.to_string()).unwrap();
+    let affinities = table
+        .columns
+        .iter()
+        .map(|c| c.type_affinity.to_string())
+        .collect::<Vec<_>>();
+    assert_eq!(affinities, vec!["TEXT", "REAL", "NUM"]);
+}
+
+#[test]
+fn index_schema_test() {
+    let conn = sqlite::open("tests/fixtures/indexes.db").unwrap();
+    let indexes = conn
+        .get_schema()
+        .into_iter()
+        .filter_map(|s| match s.as_ref() {
+            SqliteSchema::Index(i) => Some((
+                i.name.to_string(),
+                i.columns
+                    .iter()
+                    .map(|c| (c.name.clone(), c.order, c.collation.clone()))
+                    .collect::<Vec<_>>(),
+                i.unique,
+                i.where_clause.clone(),
+            )),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+
+    let column = |name: &str, order: SortOrder, collation: Option<&str>| {
+        (name.to_string(), order, collation.map(str::to_string))
+    };
+    assert_eq!(
+        indexes,
+        vec![
+            (
+                "sqlite_autoindex_people_1".to_string(),
+                vec![column("email", SortOrder::Asc, None)],
+                true,
+                None
+            ),
+            (
+                "sqlite_autoindex_people_2".to_string(),
+                vec![
+                    column("first", SortOrder::Asc, None),
+                    column("last", SortOrder::Asc, None)
+                ],
+                true,
+                None
+            ),
+            (
+                "people_by_last_first".to_string(),
+                vec![
+                    column("last", SortOrder::Desc, None),
+                    column("first", SortOrder::Asc, Some("NOCASE"))
+                ],
+                false,
+                None
+            ),
+            (
+                "age_idx".to_string(),
+                vec![column("age", SortOrder::Asc, None)],
+                false,
+                Some("age > 18".to_string())
+            ),
+            (
+                "code index".to_string(),
+                vec![column("code", SortOrder::Asc, None)],
+                true,
+                None
+            ),
+        ]
+    );
+
+    // partial indexes don't hold every row so they are never used for lookups
+    assert!(conn.get_index_tree("people", "age").is_err());
+    let index = conn.get_index_tree("people", "first").unwrap();
+    let row_ids = index
+        .get_row_ids(conn.get_db(), &CellValue::String("Cy".to_string()))
+        .unwrap()
+        .collect::<Result<Vec<_>>>()
+        .unwrap();
+    assert_eq!(row_ids, vec![3]);
+}
+
+#[test]
+fn without_rowid_test() {
+    let conn = sqlite::open("tests/fixtures/without_rowid.db").unwrap();
+    let schema = conn.get_db().get_table_schema("pairs").unwrap();
+    let SqliteSchema::Table(pairs) = schema.as_ref() else {
+        panic!("pairs must be a table");
+    };
+    // stored as the key (b, a) followed by note
+    assert_eq!(
+        pairs.primary_key,
+        vec![(2, SortOrder::Asc), (0, SortOrder::Asc)]
+    );
+    assert_eq!(
+        (0..3).map(|i| pairs.record_index(i)).collect::<Vec<_>>(),
+        vec![1, 2, 0]
+    );
+
+    let tree = conn.get_tree("countries").unwrap();
+    let codes = tree
+        .row_reader(conn.get_db())
+        .map(|row| row.unwrap().read_column("code").unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(codes.len(), 300);
+    assert!(codes.windows(2).all(|w| w[0] < w[1]));
+    assert_eq!(codes[0], CellValue::String("ACG".to_string()));
+
+    let row = tree
+        .get_row_by_key(conn.get_db(), &[CellValue::String("AES".to_string())])
+        .unwrap()
+        .unwrap();
+    assert_eq!(
+        row.read_column("population").unwrap(),
+        CellValue::Int(227000)
+    );
+    assert!(tree
+        .get_row_by_key(conn.get_db(), &[CellValue::String("ZZZ".to_string())])
+        .unwrap()
+        .is_none());
+
+    let tree = conn.get_tree("pairs").unwrap();
+    let key = [CellValue::String("y".to_string()), CellValue::Int(42)];
+    let row = tree.get_row_by_key(conn.get_db(), &key).unwrap().unwrap();
+    assert_eq!(
+        row.read_column("note").unwrap(),
+        CellValue::String("n42y".to_string())
+    );
+    assert!(tree.get_row(conn.get_db(), 1).is_err());
+
+    // the key is (name COLLATE NOCASE, rank DESC)
+    let schema = conn.get_db().get_table_schema("tags").unwrap();
+    let SqliteSchema::Table(tags) = schema.as_ref() else {
+        panic!("tags must be a table");
+    };
+    assert_eq!(
+        tags.primary_key,
+        vec![(0, SortOrder::Asc), (1, SortOrder::Desc)]
+    );
+    let tree = conn.get_tree("tags").unwrap();
+    for rank in 0..4 {
+        let key = [CellValue::String("TAG7".to_string()), CellValue::Int(rank)];
+        let row = tree.get_row_by_key(conn.get_db(), &key).unwrap().unwrap();
+        let note = row.read_column("note").unwrap().to_string();
+        assert!(note.starts_with(&format!("note {} ", 28 + rank)));
+    }
+}
+
+#[test]
+fn view_test() {
+    let conn = sqlite::open("tests/fixtures/views.db").unwrap();
+    let schema = conn.get_db().get_view_schema("named").unwrap();
+    let SqliteSchema::View(view) = schema.as_ref() else {
+        panic!("named must be a view");
+    };
+    assert_eq!(view.columns, vec!["fruit".into(), "hue".into()]);
+    assert_eq!(
+        view.query().unwrap().to_string(),
+        "SELECT name, color FROM fruits"
+    );
+
+    let cells = |sql: &str| {
+        conn.query_rows(sql)
+            .unwrap()
+            .into_iter()
+            .map(|row| row.cells)
+            .collect::<Vec<_>>()
+    };
+    let text = |s: &str| CellValue::String(s.to_string());
+    assert_eq!(
+        cells("select name, id from red_fruits"),
+        vec![
+            vec![text("apple"), CellValue::Int(1)],
+            vec![text("cherry"), CellValue::Int(3)]
+        ]
+    );
+    assert_eq!(cells("select fruit from nested"), vec![vec![text("banana")], vec![text("lemon")]]);
+    assert_eq!(cells("select count(*) from everything"), vec![vec![CellValue::Int(5)]]);
+    assert!(conn.query_rows("select color from named").is_err());
+
+    // sqlparser rejects GLOB, the view is kept and only fails when it's queried
+    let schema = conn.get_db().get_view_schema("globbed").unwrap();
+    let SqliteSchema::View(view) = schema.as_ref() else {
+        panic!("globbed must be a view");
+    };
+    assert!(view.query.is_none());
+    assert!(view.sql.contains("glob 'b*'"));
+    assert!(conn.query_rows("select * from globbed").is_err());
+
+    // sqlite only lets views read from views that already exist, a loop takes editing
+    // the schema table directly
+    let db = TempDb::copy("tests/fixtures/views.db");
+    let edit = rusqlite::Connection::open(db.path()).unwrap();
+    edit.execute_batch(
+        "create view first as select * from fruits;
+        create view second as select * from first;
+        create view itself as select * from fruits;
+        pragma writable_schema = on;
+        update sqlite_schema set sql = 'create view first as select * from second'
+            where name = 'first';
+        update sqlite_schema set sql = 'create view itself as select * from itself'
+            where name = 'itself';",
+    )
+    .unwrap();
+    drop(edit);
+    let conn = sqlite::open(db.path()).unwrap();
+    for (sql, view) in [
+        ("select * from first", "first"),
+        ("select id from second", "second"),
+        ("select count(*) from itself", "itself"),
+    ] {
+        let err = conn.query_rows(sql).unwrap_err();
+        assert!(matches!(err, Error::Corrupt(_)), "{}: {}", sql, err);
+        assert_eq!(
+            err.to_string(),
+            format!("view {} is circularly defined", view)
+        );
+    }
+    assert_eq!(conn.query_rows("select * from nested").unwrap().len(), 2);
+}
+
+#[test]
+fn trigger_test() {
+    let conn = sqlite::open("tests/fixtures/triggers.db").unwrap();
+    let triggers = conn
+        .get_schema()
+        .iter()
+        .filter_map(|s| match s.as_ref() {
+            SqliteSchema::Trigger(t) => Some((
+                t.name.to_string(),
+                t.table_name.to_string(),
+                t.timing,
+                t.event.clone(),
+                t.when_clause.as_ref().map(|w| w.to_string()),
+                t.body.iter().map(|s| s.to_string()).collect::<Vec<_>>(),
+            )),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+
+    let strings = |s: &[&str]| s.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+    assert_eq!(
+        triggers,
+        vec![
+            (
+                "accounts_audit".to_string(),
+                "accounts".to_string(),
+                TriggerTiming::After,
+                TriggerEvent::Update(strings(&["balance", "owner"])),
+                Some("new.balance <> old.balance".to_string()),
+                strings(&["INSERT INTO audit VALUES (old.id, old.balance, new.balance, \
+                    CASE WHEN new.balance > old.balance THEN 'up' ELSE 'down' END)"])
+            ),
+            (
+                "no_negative".to_string(),
+                "accounts".to_string(),
+                TriggerTiming::Before,
+                TriggerEvent::Insert,
+                None,
+                strings(&["SELECT raise(abort, 'negative; balance') WHERE new.balance < 0"])
+            ),
+            (
+                "rich_delete".to_string(),
+                "rich".to_string(),
+                TriggerTiming::InsteadOf,
+                TriggerEvent::Delete,
+                None,
+                strings(&[
+                    "DELETE FROM accounts WHERE id = old.id",
+                    "UPDATE audit SET note = 'gone' WHERE account_id = old.id"
+                ])
+            ),
+            (
+                "globbed".to_string(),
+                "accounts".to_string(),
+                TriggerTiming::After,
+                TriggerEvent::Insert,
+                Some("new.owner glob 'x*'".to_string()),
+                strings(&[
+                    "update audit set note = 'x' where note glob 'y*'",
+                    "DELETE FROM audit WHERE account_id = new.id"
+                ])
+            ),
+        ]
+    );
+    // sqlparser doesn't know GLOB, those parts are kept as they're written
+    let schemas = conn.get_schema();
+    let trigger = schemas
+        .iter()
+        .find_map(|s| match s.as_ref() {
+            SqliteSchema::Trigger(t) if t.name.as_ref() == "globbed" => Some(t),
+            _ => None,
+        })
+        .unwrap();
+    assert!(matches!(trigger.when_clause, Some(TriggerSql::Raw(_))));
+    assert!(matches!(
+        trigger.body[..],
+        [TriggerSql::Raw(_), TriggerSql::Parsed(_)]
+    ));
+
+    assert!(schema_parser::parse_create_trigger("CREATE TRIGGER t INSERT ON a BEGIN END").is_err());
+}
+
+#[test]
+fn wal_test() {
+    let conn = sqlite::open("tests/fixtures/wal.db").unwrap();
+    // the main file only holds the first checkpoint, everything else is in wal.db-wal
+    // which ends with a torn frame that must be ignored
+    let wal = conn.get_db().wal().unwrap();
+    assert_eq!(wal.header.page_size, 1024);
+    assert_eq!(wal.frame_count, 17);
+    assert_eq!(conn.get_header().database_size, 13);
+
+    let cells = |sql: &str| {
+        conn.query_rows(sql)
+            .unwrap()
+            .into_iter()
+            .map(|row| row.cells)
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(cells("select count(*) from notes"), vec![vec![CellValue::Int(202)]]);
+    assert_eq!(
+        cells("select id from notes where body = 'updated'"),
+        vec![vec![CellValue::Int(1)]]
+    );
+    assert_eq!(
+        cells("select x from later"),
+        vec![vec![CellValue::String("from wal".to_string())]]
+    );
+}
+
+#[test]
+fn hot_journal_test() {
+    let count = |conn: &sqlite::connection::Connection, sql: &str| {
+        conn.query_rows(sql).unwrap()[0].cells[0].clone()
+    };
+
+    // read only recovery leaves the half written file and the journal alone
+    let options = OpenOptions {
+        read_only: true,
+        ..Default::default()
+    };
+    let conn = sqlite::open_with_options("tests/fixtures/hot_journal.db", options).unwrap();
+    assert_eq!(conn.get_header().database_size, 13);
+    assert_eq!(count(&conn, "select count(*) from items"), CellValue::Int(300));
+    let torn = std::fs::read("tests/fixtures/hot_journal.db").unwrap();
+    assert_eq!(torn.len(), 26 * 1024);
+    assert!(std::path::Path::new("tests/fixtures/hot_journal.db-journal").exists());
+
+    // without rollback_journal the journal is read in memory too and nothing can be written
+    let options = OpenOptions {
+        rollback_journal: false,
+        ..Default::default()
+    };
+    let mut conn = sqlite::open_with_options("tests/fixtures/hot_journal.db", options).unwrap();
+    assert_eq!(
+        count(&conn, "select count(*) from items"),
+        CellValue::Int(300)
+    );
+    assert!(conn.execute("delete from items").is_err());
+    assert_eq!(
+        std::fs::read("tests/fixtures/hot_journal.db").unwrap(),
+        torn
+    );
+    assert!(std::path::Path::new("tests/fixtures/hot_journal.db-journal").exists());
+
+    // by default the journal is played back into the file and deleted like sqlite does
+    let db = TempDb::copy("tests/fixtures/hot_journal.db");
+    let conn = sqlite::open(db.path()).unwrap();
+    assert_eq!(
+        count(&conn, "select count(*) from items"),
+        CellValue::Int(300)
+    );
+    assert_eq!(std::fs::metadata(db.path()).unwrap().len(), 13 * 1024);
+    assert!(!std::path::Path::new(&db.journal()).exists());
+    assert_eq!(db.sqlite_integrity_check(), vec!["ok"]);
+
+    // a journal naming a super-journal that's gone belongs to a committed transaction
+    let db = TempDb::copy("tests/fixtures/hot_journal.db");
+    let mut journal = std::fs::read(db.journal()).unwrap();
+    let name = db.dir.join("gone-mj0123");
+    let name = name.to_str().unwrap().as_bytes();
+    journal.extend_from_slice(&(0x40000000u32 / 1024 + 1).to_be_bytes());
+    journal.extend_from_slice(name);
+    journal.extend_from_slice(&(name.len() as u32).to_be_bytes());
+    let sum = name.iter().fold(0u32, |sum, b| sum + *b as u32);
+    journal.extend_from_slice(&sum.to_be_bytes());
+    journal.extend_from_slice(&[0xd9, 0xd5, 0x05, 0xf9, 0x20, 0xa1, 0x63, 0xd7]);
+    std::fs::write(db.journal(), journal).unwrap();
+    let conn = sqlite::open(db.path()).unwrap();
+    assert_eq!(
+        count(&conn, "select count(*) from items"),
+        CellValue::Int(241)
+    );
+    assert_eq!(std::fs::metadata(db.path()).unwrap().len(), 26 * 1024);
+
+    // a writer that dies after the database was opened leaves a journal the next
+    // transaction rolls back before it writes anything, or refuses to write next to
+    let db = TempDb::copy("tests/fixtures/hot_journal.db");
+    let journal = std::fs::read(db.journal()).unwrap();
+    std::fs::remove_file(db.journal()).unwrap();
+    let options = OpenOptions {
+        rollback_journal: false,
+        ..Default::default()
+    };
+    let mut refusing = sqlite::open_with_options(db.path(), options).unwrap();
+    let mut conn = sqlite::open(db.path()).unwrap();
+    std::fs::write(db.journal(), &journal).unwrap();
+    assert!(refusing.execute("delete from items").is_err());
+    assert_eq!(std::fs::read(db.journal()).unwrap(), journal);
+    assert_eq!(conn.execute("delete from items").unwrap(), 300);
+    assert!(!std::path::Path::new(&db.journal()).exists());
+    assert_eq!(db.sqlite_integrity_check(), vec!["ok"]);
+
+    // the journal of a committed transaction is replaced by the next one
+    std::fs::write(db.journal(), [0; 28]).unwrap();
+    conn.execute("insert into items values (1, 'again')")
+        .unwrap();
+    assert!(!std::path::Path::new(&db.journal()).exists());
+    assert_eq!(db.sqlite_integrity_check(), vec!["ok"]);
+}
+
+#[test]
+fn freelist_test() {
+    let conn = sqlite::open("tests/fixtures/freelist.db").unwrap();
+    let freelist = conn.get_db().get_freelist().unwrap();
+    assert_eq!(freelist.trunk_pages, vec![367, 247, 126, 4]);
+    assert_eq!(freelist.len(), 407);
+    assert_eq!(freelist.len(), conn.get_header().freelist_count as usize);
+
+    // point the last trunk page back at the first one
+    let mut file = std::fs::read("tests/fixtures/freelist.db").unwrap();
+    let last_trunk = 3 * 512;
+    file[last_trunk..last_trunk + 4].copy_from_slice(&367u32.to_be_bytes());
+    let db = TempDb::new("freelist.db", &file);
+    let conn = sqlite::open(db.path()).unwrap();
+    let err = conn.get_db().get_freelist().unwrap_err();
+    assert_eq!(err.to_string(), "freelist trunk page 367 appears twice");
+}
+
+#[test]
+fn integrity_check_test() {
+    for fixture in ["indexes", "overflow", "without_rowid", "freelist", "views"] {
+        let conn = sqlite::open(format!("tests/fixtures/{}.db", fixture)).unwrap();
+        assert_eq!(conn.integrity_check().unwrap(), Vec::<String>::new(), "{}", fixture);
+    }
+    let conn = sqlite::open("tests/fixtures/indexes.db").unwrap();
+    let rows = conn.query_rows("PRAGMA integrity_check;").unwrap();
+    assert_eq!(rows[0].cells, vec![CellValue::String("ok".to_string())]);
+
+    let corrupted = |edit: &dyn Fn(&mut Vec<u8>)| {
+        let mut file = std::fs::read("tests/fixtures/indexes.db").unwrap();
+        edit(&mut file);
+        let db = TempDb::new("indexes.db", &file);
+        sqlite::open(db.path()).unwrap().integrity_check().unwrap()
+    };
+
+    // Bob becomes Bxb in the table but not in the indexes on first
+    let problems = corrupted(&|file| {
+        let bob = file[4096..8192].windows(3).position(|w| w == b"Bob").unwrap();
+        file[4096 + bob + 1] = b'x';
+    });
+    assert_eq!(
+        problems,
+        vec![
+            "row 2 missing from index sqlite_autoindex_people_2",
+            "row 2 missing from index people_by_last_first"
+        ]
+    );
+
+    let problems = corrupted(&|file| file[4096 + 7] = 5);
+    assert_eq!(problems, vec!["Fragmentation of 0 bytes reported as 5 on page 2"]);
+
+    // swap the first two cell pointers of the table page
+    let problems = corrupted(&|file| {
+        file.swap(4096 + 8, 4096 + 10);
+        file.swap(4096 + 9, 4096 + 11);
+    });
+    assert_eq!(problems, vec!["Tree 2 page 2 cell 1: Rowid 1 out of order"]);
+
+    // pages nobody uses are reported up to the limit of 100 like sqlite does
+    let problems = corrupted(&|file| {
+        let pages = file.len() as u32 / 4096 + 150;
+        file.resize(pages as usize * 4096, 0);
+        file[28..32].copy_from_slice(&pages.to_be_bytes());
+    });
+    assert_eq!(problems.len(), 100);
+
+    // a header claiming more pages than the file holds is rejected when it's opened
+    let mut file = std::fs::read("tests/fixtures/indexes.db").unwrap();
+    file[28..32].copy_from_slice(&1_728_053_299u32.to_be_bytes());
+    assert!(matches!(sqlite::open_bytes(file), Err(Error::Corrupt(_))));
+}
+
+#[test]
+fn page_cache_test() {
+    let mut cache = PageCache::new(2);
+    cache.insert(1, Arc::from([1u8].as_slice()));
+    cache.insert(2, Arc::from([2u8].as_slice()));
+    assert!(cache.get(1).is_some());
+    // page 2 is now the least recently used
+    cache.insert(3, Arc::from([3u8].as_slice()));
+    assert!(cache.get(2).is_none());
+    assert_eq!(cache.len(), 2);
+    assert_eq!(cache.stats(), CacheStats { hits: 1, misses: 1 });
+
+    let conn = sqlite::open("tests/fixtures/without_rowid.db").unwrap();
+    let count = |conn: &Connection| conn.query_rows("select count(*) from countries").unwrap();
+    count(&conn);
+    let first = conn.get_db().cache_stats();
+    count(&conn);
+    let second = conn.get_db().cache_stats();
+    // every page is cached after the first scan
+    assert_eq!(second.misses, first.misses);
+    assert!(second.hits > first.hits);
+
+    conn.query_rows("PRAGMA cache_size = 1").unwrap();
+    let rows = conn.query_rows("PRAGMA cache_size").unwrap();
+    assert_eq!(rows[0].cells, vec![CellValue::Int(1)]);
+    count(&conn);
+    assert!(conn.get_db().cache_stats().misses > second.misses);
+
+    // a size in KiB too large to count in bytes is clamped
+    conn.query_rows("PRAGMA cache_size = -9000000000000000000")
+        .unwrap();
+    let rows = conn.query_rows("PRAGMA cache_size").unwrap();
+    assert!(matches!(rows[0].cells[0], CellValue::Int(size) if size > 1));
+}
+
+// cargo test --release page_cache_benchmark -- --ignored --nocapture
+#[test]
+#[ignore = "a benchmark, run it in release mode"]
+fn page_cache_benchmark() {
+    let scan = |cache_size: usize| {
+        let options = OpenOptions {
+            cache_size,
+            ..Default::default()
+        };
+        let conn = sqlite::open_with_options("superheroes.db", options).unwrap();
+        let start = std::time::Instant::now();
+        for _ in 0..20 {
+            conn.query_rows("select name from superheroes where eye_color = 'Blue Eyes'")
+                .unwrap();
+        }
+        (start.elapsed(), conn.get_db().cache_stats())
+    };
+    let (uncached, _) = scan(0);
+    let (cached, stats) = scan(sqlite::page_cache::DEFAULT_CACHE_SIZE);
+    println!("20 scans without a cache: {:?}", uncached);
+    println!("20 scans with a cache:    {:?} ({:?})", cached, stats);
+    assert!(cached < uncached);
+}
+
+#[test]
+fn storage_test() {
+    let query = |conn: &Connection| {
+        conn.query_rows("select name from fruits where color = 'red'")
+            .unwrap()
+            .iter()
+            .map(|r| r.cells[0].to_string())
+            .collect::<Vec<_>>()
+    };
+    let from_file = query(&sqlite::open("tests/fixtures/views.db").unwrap());
+    assert!(!from_file.is_empty());
+
+    let options = OpenOptions {
+        mmap: true,
+        ..Default::default()
+    };
+    let mapped = sqlite::open_with_options("tests/fixtures/views.db", options).unwrap();
+    assert_eq!(query(&mapped), from_file);
+
+    let owned = sqlite::open_bytes(std::fs::read("tests/fixtures/views.db").unwrap()).unwrap();
+    assert_eq!(query(&owned), from_file);
+
+    let borrowed = sqlite::open_bytes(include_bytes!("../../tests/fixtures/views.db").as_slice());
+    assert_eq!(query(&borrowed.unwrap()), from_file);
+
+    // a truncated database fails to open, or to read once its header size isn't trusted,
+    // instead of panicking
+    let mut bytes = std::fs::read("tests/fixtures/overflow.db").unwrap();
+    bytes.truncate(bytes.len() - 4096);
+    assert!(matches!(
+        sqlite::open_bytes(bytes.clone()),
+        Err(Error::Corrupt(_))
+    ));
+    bytes[92..96].copy_from_slice(&[0; 4]);
+    let conn = sqlite::open_bytes(bytes).unwrap();
+    assert!(conn.query_rows("select body from docs").is_err());
+}
+
+#[test]
+fn cursor_test() {
+    let conn = sqlite::open("tests/fixtures/without_rowid.db").unwrap();
+    let tree = conn.get_tree("countries").unwrap();
+    let codes = tree
+        .row_reader(conn.get_db())
+        .map(|row| row.unwrap().read_column("code").unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(codes.len(), 300);
+    assert!(codes.windows(2).all(|w| w[0] < w[1]));
+
+    // some of the keys sit in interior cells, seeking has to stop on those too
+    for code in &codes {
+        let row = tree
+            .get_row_by_key(conn.get_db(), std::slice::from_ref(code))
+            .unwrap()
+            .unwrap();
+        assert_eq!(&row.read_column("code").unwrap(), code);
+    }
+}
+
+#[test]
+fn cyclic_btree_test() {
+    // the right-most child of the table's root and of its index's root point back at the
+    // roots themselves, walking down never reaches a leaf
+    let mut file = std::fs::read("tests/fixtures/ranges.db").unwrap();
+    for root in [2u32, 16] {
+        let right_child = (root as usize - 1) * 1024 + 8;
+        file[right_child..right_child + 4].copy_from_slice(&root.to_be_bytes());
+    }
+    let conn = sqlite::open_bytes(file.clone()).unwrap();
+    for sql in [
+        "select count(*) from readings",
+        "select id from readings where value > 190",
+        "select label from readings where id = 590",
+    ] {
+        let err = conn.query_rows(sql).unwrap_err();
+        assert!(matches!(err, Error::Corrupt(_)), "{}: {}", sql, err);
+    }
+    // reading a whole tree into memory to print it stops at the same depth
+    let tree = conn.get_tree("readings").unwrap();
+    assert!(matches!(
+        tree.pretty_print(conn.get_db()),
+        Err(Error::Corrupt(_))
+    ));
+    let index = conn.get_index_tree("readings", "value").unwrap();
+    assert!(matches!(
+        index.pretty_print(conn.get_db()),
+        Err(Error::Corrupt(_))
+    ));
+    let problems = conn.integrity_check().unwrap();
+    assert!(problems.contains(&"2nd reference to page 2".to_string()));
+
+    // inserting seeks the leaf the new row goes on, or the largest rowid
+    let db = TempDb::new("ranges.db", &file);
+    let mut conn = sqlite::open(db.path()).unwrap();
+    for sql in [
+        "insert into readings values (1000, 1, 'x')",
+        "insert into readings (value, label) values (1, 'x')",
+    ] {
+        let err = conn.execute(sql).unwrap_err();
+        assert!(matches!(err, Error::Corrupt(_)), "{}: {}", sql, err);
+    }
+
+    // a chain of interior pages without cells, each the right-most child of the one
+    // before, is deeper than a valid tree can be without any page repeating
+    let mut file = std::fs::read("tests/fixtures/ranges.db").unwrap();
+    for page_number in 2..22 {
+        let page = &mut file[(page_number - 1) * 1024..page_number * 1024];
+        if page_number > 2 {
+            page[..12].copy_from_slice(&[0x05, 0, 0, 0, 0, 0x04, 0, 0, 0, 0, 0, 0]);
+        }
+        page[8..12].copy_from_slice(&(page_number as u32 + 1).to_be_bytes());
+    }
+    let conn = sqlite::open_bytes(file).unwrap();
+    let err = conn
+        .query_rows("select count(*) from readings")
+        .unwrap_err();
+    assert!(matches!(err, Error::Corrupt(_)), "{}", err);
+    let tree = conn.get_tree("readings").unwrap();
+    assert!(matches!(
+        tree.pretty_print(conn.get_db()),
+        Err(Error::Corrupt(_))
+    ));
+    let problems = conn.integrity_check().unwrap();
+    assert!(problems
+        .iter()
+        .any(|p| p.contains("more than 20 levels deep")));
+}
+
+#[test]
+fn rowid_seek_test() {
+    let conn = sqlite::open("tests/fixtures/wal.db").unwrap();
+    let db = conn.get_db();
+    let tree = conn.get_tree("notes").unwrap();
+    for id in 1..=202 {
+        // with the cache emptied every page the lookup reads is a miss
+        db.set_cache_size(0);
+        db.set_cache_size(100);
+        let before = db.cache_stats();
+        let row = tree.get_row(db, id).unwrap();
+        assert_eq!(row.read_column("id").unwrap(), CellValue::Int(id));
+        // the root and one leaf, not every leaf before the row
+        assert!(db.cache_stats().misses - before.misses <= 2);
+    }
+    assert!(tree.get_row(db, 0).is_err());
+    assert!(tree.get_row(db, 203).is_err());
+}
+
+#[test]
+fn index_range_test() {
+    let conn = sqlite::open("tests/fixtures/ranges.db").unwrap();
+    let ids = |sql: &str| {
+        conn.query_rows(sql)
+            .unwrap()
+            .iter()
+            .map(|row| row.cells[0].clone())
+            .collect::<Vec<_>>()
+    };
+    let all = conn.query_rows("select id, value from readings").unwrap();
+    let expected = |keep: &dyn Fn(i64) -> bool| {
+        let mut ids = all
+            .iter()
+            .filter(|row| matches!(row.cells[1], CellValue::Int(v) if keep(v)))
+            .map(|row| row.cells[0].clone())
+            .collect::<Vec<_>>();
+        ids.sort_by(|l, r| l.sqlite_cmp(r));
+        ids
+    };
+    let sorted = |mut ids: Vec<CellValue>| {
+        ids.sort_by(|l, r| l.sqlite_cmp(r));
+        ids
+    };
+    let cases: [(&str, &dyn Fn(i64) -> bool); 9] = [
+        ("select id from readings where value = 17", &|v| v == 17),
+        ("select id from readings where value < 5", &|v| v < 5),
+        ("select id from readings where value <= 5", &|v| v <= 5),
+        ("select id from readings where value > 190", &|v| v > 190),
+        ("select id from readings where value >= 190", &|v| v >= 190),
+        ("select id from readings where 190 < value", &|v| v > 190),
+        (
+            "select id from readings where value between 10 and 20",
+            &|v| (10..=20).contains(&v),
+        ),
+        (
+            "select id from readings where value in (3, 150, 3, 199)",
+            &|v| [3, 150, 199].contains(&v),
+        ),
+        // the values take the column's affinity before they're merged
+        (
+            "select id from readings where value in (13, '13', 12.0, '12')",
+            &|v| v == 12 || v == 13,
+        ),
+    ];
+    for (sql, keep) in cases {
+        let found = ids(sql);
+        assert!(!found.is_empty(), "{}", sql);
+        assert_eq!(sorted(found), expected(keep), "{}", sql);
+    }
+    // rows come back in index order
+    let values = ids("select value from readings where value > 150");
+    assert!(values.windows(2).all(|w| w[0] <= w[1]));
+
+    let index = conn.get_index_tree("readings", "value").unwrap();
+    let range = |lower, upper, direction| {
+        index
+            .range(conn.get_db(), lower, upper, direction)
+            .unwrap()
+            .collect::<Result<Vec<_>>>()
+            .unwrap()
+    };
+    let (low, high) = (CellValue::Int(10), CellValue::Int(20));
+    let forward = range(
+        Bound::Excluded(low.clone()),
+        Bound::Excluded(high.clone()),
+        SortOrder::Asc,
+    );
+    let mut backward = range(
+        Bound::Excluded(low.clone()),
+        Bound::Excluded(high.clone()),
+        SortOrder::Desc,
+    );
+    assert_eq!(forward.len(), expected(&|v| v > 10 && v < 20).len());
+    backward.reverse();
+    assert_eq!(forward, backward);
+    let everything = range(Bound::Unbounded, Bound::Unbounded, SortOrder::Desc);
+    assert_eq!(everything.len(), all.len());
+    let inclusive = range(Bound::Included(low), Bound::Included(high), SortOrder::Desc);
+    assert_eq!(inclusive.len(), expected(&|v| (10..=20).contains(&v)).len());
+}
+
+#[test]
+fn comparison_test() {
+    // items(v text, n integer, name text collate nocase, b blob), the expected rows are
+    // the ones sqlite returns
+    let conn = sqlite::open("tests/fixtures/compare.db").unwrap();
+    let column = |sql: &str| {
+        conn.query_rows(sql)
+            .unwrap()
+            .iter()
+            .map(|row| row.cells[0].to_string())
+            .collect::<Vec<_>>()
+    };
+    let cases: [(&str, &[&str]); 10] = [
+        // a TEXT column turns the literal into text, '10' sorts before '5'
+        ("select v from items where v < 5", &["10"]),
+        ("select v from items where v between 1 and 5", &["10"]),
+        // an INTEGER column turns text that looks like a number into one
+        ("select n from items where n = '10'", &["10"]),
+        ("select v from items where v = n", &["10", "9"]),
+        // the collation of the column, the left one first
+        ("select name from items where name = 'b'", &["B"]),
+        ("select name from items where 'b' = name", &["B"]),
+        (
+            "select name from items where name in ('a', 'c  ')",
+            &["a", "C  "],
+        ),
+        // BLOB columns convert nothing, not even against a TEXT column
+        ("select v from items where b = 9", &["9"]),
+        ("select v from items where b = '10'", &["10"]),
+        ("select v from items where v > b", &["9"]),
+    ];
+    for (sql, expected) in cases {
+        assert_eq!(column(sql), expected.to_vec(), "{}", sql);
+    }
+
+    // DELETE picks its rows with the same comparisons
+    let db = TempDb::copy("tests/fixtures/compare.db");
+    let mut conn = sqlite::open(db.path()).unwrap();
+    assert_eq!(conn.execute("delete from items where v < 5").unwrap(), 1);
+    assert_eq!(
+        conn.execute("delete from items where name = 'A'").unwrap(),
+        1
+    );
+    assert_eq!(conn.query_rows("select v from items").unwrap().len(), 1);
+    assert_eq!(db.sqlite_integrity_check(), vec!["ok"]);
+}
+
+#[test]
+fn concurrent_read_test() {
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<Connection>();
+
+    // a small cache keeps the threads loading pages while the others parse theirs
+    let options = OpenOptions {
+        cache_size: 4,
+        ..Default::default()
+    };
+    let conn = sqlite::open_with_options("tests/fixtures/ranges.db", options).unwrap();
+    let queries = [
+        "select id, label from readings",
+        "select id, label from readings where value between 20 and 80",
+        "select count(*) from readings where value > 100",
+    ];
+    let run = |sql: &str| {
+        conn.query_rows(sql)
+            .unwrap()
+            .into_iter()
+            .map(|row| row.cells)
+            .collect::<Vec<_>>()
+    };
+    let expected = queries.map(run);
+    std::thread::scope(|scope| {
+        for thread in 0..8 {
+            let (run, expected) = (&run, &expected);
+            scope.spawn(move || {
+                for i in 0..10 {
+                    let query = (thread + i) % queries.len();
+                    assert_eq!(run(queries[query]), expected[query]);
+                }
+            });
+        }
+    });
+}
+
+#[test]
+fn error_kind_test() {
+    let conn = sqlite::open("tests/fixtures/ranges.db").unwrap();
+    let error = |sql: &str| conn.query_rows(sql).err().unwrap();
+    assert!(matches!(error("select id from nothing"), Error::NoSuchTable(t) if t == "nothing"));
+    let column = error("select nothing from readings");
+    assert!(matches!(column, Error::NoSuchColumn(_)));
+    let parse = error("select id from readings where");
+    assert!(matches!(parse, Error::Parse(_)));
+    let unsupported = error("select id from readings where value like '1%'");
+    assert!(matches!(unsupported, Error::Unsupported(_)));
+    let mismatch = error("select id from readings where value");
+    assert!(matches!(mismatch, Error::TypeMismatch(_)));
+
+    let garbage = sqlite::open_bytes(vec![7u8; 4096]).err().unwrap();
+    assert!(matches!(garbage, Error::Corrupt(_)));
+    let missing = sqlite::open("tests/fixtures/missing.db").err().unwrap();
+    assert!(matches!(missing, Error::Io(_)));
+
+    // damaging any one page makes reads fail, never panic
+    let bytes = std::fs::read("tests/fixtures/ranges.db").unwrap();
+    for page in 1..bytes.len() / 1024 {
+        for fill in [0x00, 0xff] {
+            let mut damaged = bytes.clone();
+            damaged[page * 1024..page * 1024 + 64].fill(fill);
+            if let Ok(conn) = sqlite::open_bytes(damaged) {
+                let _ = conn.query_rows("select id, label from readings");
+                let _ = conn.query_rows("select id from readings where value between 5 and 9");
+            }
+        }
+    }
+}
+
+#[test]
+fn insert_test() {
+    let db = TempDb::copy("tests/fixtures/rowid.db");
+    let path = db.path();
+    let mut conn = sqlite::open(path).unwrap();
+    let change_counter = conn.get_header().file_change_counter;
+    let long = "long ".repeat(2000);
+    let sql = format!(
+        "insert into users (name, user_id) values ('thirty', 30), ('auto', NULL), ('{}', null)",
+        long
+    );
+    assert_eq!(conn.execute(sql).unwrap(), 3);
+    assert_eq!(
+        conn.execute("insert into users values (-5, X'00ff')")
+            .unwrap(),
+        1
+    );
+    assert_eq!(
+        conn.execute("insert into tags (rowid, id) values (7, 12)")
+            .unwrap(),
+        1
+    );
+
+    let err = conn
+        .execute("insert into users values (10, 'again')")
+        .unwrap_err();
+    assert!(matches!(&err, Error::Constraint(m) if m == "UNIQUE constraint failed: users.user_id"));
+    assert!(matches!(
+        conn.execute("insert into users (nope) values (1)"),
+        Err(Error::NoSuchColumn(_))
+    ));
+    assert!(matches!(
+        conn.execute("insert into users values (1)"),
+        Err(Error::Parse(_))
+    ));
+
+    // the changes are in the file once execute returns
+    let conn = sqlite::open(path).unwrap();
+    let header = conn.get_header();
+    assert_eq!(header.file_change_counter, change_counter + 3);
+    assert!(header.database_size_is_valid());
+    assert_eq!(
+        header.database_size as u64 * 4096,
+        std::fs::metadata(path).unwrap().len()
+    );
+    assert!(!std::path::Path::new(&format!("{}-journal", path)).exists());
+    let rows = conn
+        .query_rows("select user_id, name from users")
+        .unwrap()
+        .into_iter()
+        .map(|r| r.cells)
+        .collect::<Vec<_>>();
+    let row = |id: i64, name: CellValue| vec![CellValue::Int(id), name];
+    let text = |s: &str| CellValue::String(s.to_string());
+    assert_eq!(
+        rows,
+        vec![
+            row(-5, CellValue::Blob(vec![0, 255])),
+            row(10, text("ten")),
+            row(20, text("twenty")),
+            row(30, text("thirty")),
+            row(31, text("auto")),
+            row(32, text(&long)),
+        ]
+    );
+    let tags = conn
+        .query_rows("select rowid, id, label from tags")
+        .unwrap();
+    let last = &tags.last().unwrap().cells;
+    assert_eq!(last, &vec![CellValue::Int(7), text("12"), CellValue::Null]);
+    assert_eq!(conn.integrity_check().unwrap(), Vec::<String>::new());
+
+    // a connection that read the database before another one wrote to it reads it again
+    let mut stale = conn;
+    let mut other = sqlite::open(path).unwrap();
+    other
+        .execute("insert into users values (40, 'other')")
+        .unwrap();
+    stale
+        .execute("insert into users values (50, 'stale')")
+        .unwrap();
+    let conn = sqlite::open(path).unwrap();
+    let rows = conn.query_rows("select user_id, name from users").unwrap();
+    let rows = rows.into_iter().map(|r| r.cells).collect::<Vec<_>>();
+    assert_eq!(
+        rows[rows.len() - 2..],
+        [row(40, text("other")), row(50, text("stale"))]
+    );
+    assert_eq!(conn.integrity_check().unwrap(), Vec::<String>::new());
+    assert_eq!(db.sqlite_integrity_check(), vec!["ok"]);
+
+    // databases that can't be written to say so instead of changing anything
+    let options = OpenOptions {
+        read_only: true,
+        ..Default::default()
+    };
+    let mut conn = sqlite::open_with_options("tests/fixtures/rowid.db", options).unwrap();
+    let err = conn
+        .execute("insert into tags values ('a', 'b')")
+        .unwrap_err();
+    assert!(matches!(err, Error::Unsupported(_)));
+    let mut conn = sqlite::open_bytes(std::fs::read("tests/fixtures/rowid.db").unwrap()).unwrap();
+    let err = conn
+        .execute("insert into tags values ('a', 'b')")
+        .unwrap_err();
+    assert!(matches!(err, Error::Unsupported(_)));
+}
+
+#[test]
+fn balance_test() {
+    // enough rows to split the table and its index a few levels deep on 1k pages
+    let db = TempDb::copy("tests/fixtures/ranges.db");
+    let path = db.path();
+    let mut conn = sqlite::open(path).unwrap();
+    let before = conn.query_rows("select id from readings").unwrap().len();
+    for i in 0..40 {
+        let rows = (0..50i64)
+            .map(|j| {
+                let value = (i * 50 + j) * 7919 % 1013 - 500;
+                format!("({}, '{}')", value, "label ".repeat(j as usize % 30))
+            })
+            .collect::<Vec<_>>()
+            .join(", ");
+        let sql = format!("insert into readings (value, label) values {}", rows);
+        assert_eq!(conn.execute(sql).unwrap(), 50);
+    }
+    let long = "v".repeat(3000);
+    conn.execute(format!("insert into readings values (-7, 0, '{}')", long))
+        .unwrap();
+    assert_eq!(conn.integrity_check().unwrap(), Vec::<String>::new());
+
+    let conn = sqlite::open(path).unwrap();
+    assert_eq!(conn.integrity_check().unwrap(), Vec::<String>::new());
+    let rows = conn.query_rows("select id, value from readings").unwrap();
+    assert_eq!(rows.len(), before + 2001);
+    assert!(rows
+        .windows(2)
+        .all(|w| w[0].cells[0].sqlite_cmp(&w[1].cells[0]).is_lt()));
+    // the index finds the same rows as reading the whole table
+    let expected = rows
+        .iter()
+        .filter(|row| row.cells[1] == CellValue::Int(13))
+        .count();
+    let found = conn
+        .query_rows("select id from readings where value = 13")
+        .unwrap();
+    assert!(expected > 1);
+    assert_eq!(found.len(), expected);
+    let schema = conn.get_db().get_table_schema("readings").unwrap();
+    let SqliteSchema::Table(table) = schema.as_ref() else {
+        panic!("readings is a table");
+    };
+    let root = conn
+        .get_db()
+        .read_table_page(table.root_page, None)
+        .unwrap();
+    assert!(matches!(root, TablePage::Interior(_)));
+    assert_eq!(db.sqlite_integrity_check(), vec!["ok"]);
+
+    // pages come off the freelist before the file grows
+    let db = TempDb::copy("tests/fixtures/freelist.db");
+    let path = db.path();
+    let mut conn = sqlite::open(path).unwrap();
+    let (database_size, freelist_count) = (
+        conn.get_header().database_size,
+        conn.get_header().freelist_count,
+    );
+    for i in 0..100 {
+        let sql = format!("insert into keep values ('{}')", "k".repeat(i * 7));
+        conn.execute(sql).unwrap();
+    }
+    let conn = sqlite::open(path).unwrap();
+    assert_eq!(conn.get_header().database_size, database_size);
+    assert!(conn.get_header().freelist_count < freelist_count);
+    assert_eq!(
+        conn.get_db().get_freelist().unwrap().len(),
+        conn.get_header().freelist_count as usize
+    );
+    assert_eq!(conn.integrity_check().unwrap(), Vec::<String>::new());
+    assert_eq!(db.sqlite_integrity_check(), vec!["ok"]);
+}
+
+#[test]
+fn delete_test() {
+    let db = TempDb::copy("tests/fixtures/ranges.db");
+    let path = db.path();
+    let mut conn = sqlite::open(path).unwrap();
+    for i in 0..20 {
+        let rows = (0..50i64)
+            .map(|j| format!("({}, '{}')", (i * 50 + j) % 97, "label ".repeat(j as usize)))
+            .collect::<Vec<_>>()
+            .join(", ");
+        let sql = format!("insert into readings (value, label) values {}", rows);
+        conn.execute(sql).unwrap();
+    }
+    let rows = conn.query_rows("select id, value from readings").unwrap();
+    let freelist_count = conn.get_header().freelist_count;
+
+    // the index picks the rows of the first, the second reads the whole table
+    let in_range = |value: &CellValue| matches!(value, CellValue::Int(10..=40));
+    let expected = rows.iter().filter(|row| in_range(&row.cells[1])).count();
+    let deleted = conn
+        .execute("delete from readings where value between 10 and 40")
+        .unwrap();
+    assert_eq!(deleted, expected);
+    assert_eq!(
+        conn.execute("delete from readings where id > 200 and id < 900")
+            .unwrap(),
+        rows.iter()
+            .filter(|row| !in_range(&row.cells[1]))
+            .filter(|row| matches!(row.cells[0], CellValue::Int(201..=899)))
+            .count()
+    );
+    assert!(conn.query_rows("delete from readings").is_err());
+    // values that are the same key delete their rows once
+    let found = conn
+        .query_rows("select id from readings where value = 45")
+        .unwrap();
+    assert!(!found.is_empty());
+    assert_eq!(
+        conn.execute("delete from readings where value in (45, '45', 45.0)")
+            .unwrap(),
+        found.len()
+    );
+
+    let conn = sqlite::open(path).unwrap();
+    assert_eq!(conn.integrity_check().unwrap(), Vec::<String>::new());
+    assert!(conn.get_header().freelist_count > freelist_count);
+    let left = conn.query_rows("select id, value from readings").unwrap();
+    assert!(left.iter().all(|row| !in_range(&row.cells[1])));
+    let found = conn
+        .query_rows("select id from readings where value = 50")
+        .unwrap();
+    let expected = left
+        .iter()
+        .filter(|row| row.cells[1] == CellValue::Int(50))
+        .count();
+    assert!(expected > 0);
+    assert_eq!(found.len(), expected);
+    assert_eq!(db.sqlite_integrity_check(), vec!["ok"]);
+
+    // pages left empty all go back to the freelist
+    let mut conn = conn;
+    conn.execute("delete from readings").unwrap();
+    assert_eq!(conn.integrity_check().unwrap(), Vec::<String>::new());
+    assert!(conn
+        .query_rows("select id from readings")
+        .unwrap()
+        .is_empty());
+    assert_eq!(
+        conn.get_db().get_freelist().unwrap().len() + 3,
+        conn.get_header().database_size as usize
+    );
+    assert_eq!(db.sqlite_integrity_check(), vec!["ok"]);
+}
@@ -0,0 +1,232 @@
+// Changes to the database are made to copies of the pages they touch, which are held
+// here until the transaction commits. The database then writes them to the file all
+// together, behind a rollback journal holding the pages they replace.
+
+use std::{
+    borrow::Cow,
+    collections::{btree_map::Entry, BTreeMap},
+};
+
+use super::{
+    database::Database,
+    error::{bail, Result},
+    page::{btree_page::BTreePage, page_header::PageType},
+};
+
+/// sqlite never uses the page holding the byte at offset 2^30, which file locks are
+/// taken on
+const PENDING_BYTE: u64 = 0x4000_0000;
+
+pub struct Transaction {
+    /// The changed pages by page number
+    pages: BTreeMap<u32, Vec<u8>>,
+    /// The database size in pages once the transaction commits
+    pub database_size: u32,
+}
+
+impl Transaction {
+    pub fn new(database_size: u32) -> Transaction {
+        Transaction {
+            pages: BTreeMap::new(),
+            database_size,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pages.is_empty()
+    }
+
+    /// The changed pages in page number order
+    pub fn pages(&self) -> impl Iterator<Item = (u32, &[u8])> {
+        self.pages.iter().map(|(n, page)| (*n, page.as_slice()))
+    }
+
+    pub fn into_pages(self) -> impl Iterator<Item = (u32, Vec<u8>)> {
+        self.pages.into_iter()
+    }
+
+    /// A page as this transaction has left it
+    pub fn page(&self, db: &Database, page_number: u32) -> Result<Cow<'_, [u8]>> {
+        Ok(match self.pages.get(&page_number) {
+            Some(page) => Cow::Borrowed(page),
+            None => Cow::Owned(db.read_page(page_number)?.to_vec()),
+        })
+    }
+
+    /// A page to change, copied from the database the first time it is asked for
+    pub fn page_mut(&mut self, db: &Database, page_number: u32) -> Result<&mut Vec<u8>> {
+        if page_number == 0 || page_number > self.database_size {
+            bail!(
+                Corrupt,
+                "page {} is past the end of the database",
+                page_number
+            );
+        }
+        Ok(match self.pages.entry(page_number) {
+            Entry::Occupied(page) => page.into_mut(),
+            Entry::Vacant(entry) => entry.insert(db.read_page(page_number)?.to_vec()),
+        })
+    }
+
+    pub fn btree_page(&self, db: &Database, page_number: u32) -> Result<BTreePage<Cow<'_, [u8]>>> {
+        BTreePage::new(db, page_number, self.page(db, page_number)?)
+    }
+
+    pub fn btree_page_mut(
+        &mut self,
+        db: &Database,
+        page_number: u32,
+    ) -> Result<BTreePage<&mut Vec<u8>>> {
+        BTreePage::new(db, page_number, self.page_mut(db, page_number)?)
+    }
+
+    /// Starts a b-tree page from scratch, leaving it empty
+    pub fn init_btree_page(
+        &mut self,
+        db: &Database,
+        page_number: u32,
+        page_type: PageType,
+    ) -> Result<BTreePage<&mut Vec<u8>>> {
+        BTreePage::init(db, page_number, self.page_mut(db, page_number)?, page_type)
+    }
+
+    /// Takes a zeroed page off the freelist, or adds one to the end of the database when
+    /// the freelist is empty
+    pub fn allocate_page(&mut self, db: &Database) -> Result<u32> {
+        let (trunk, free_count) = {
+            let header = self.page(db, 1)?;
+            (u32_at(&header, 32), u32_at(&header, 36))
+        };
+        match trunk {
+            0 => {
+                self.database_size += 1;
+                let page_size = db.header.page_size as u64;
+                if (self.database_size as u64 - 1) * page_size == PENDING_BYTE {
+                    self.database_size += 1;
+                }
+                let page = vec![0; db.header.page_size as usize];
+                self.pages.insert(self.database_size, page);
+                Ok(self.database_size)
+            }
+            trunk => {
+                let max_leaves = db.header.usable_size() as usize / 4 - 2;
+                let page = self.page_mut(db, trunk)?;
+                let (next, leaves) = (u32_at(page, 0), u32_at(page, 4) as usize);
+                let page_number = match leaves {
+                    // the last leaf of the first trunk is the cheapest page to take
+                    1.. if leaves <= max_leaves => {
+                        page[4..8].copy_from_slice(&(leaves as u32 - 1).to_be_bytes());
+                        u32_at(page, 4 + 4 * leaves)
+                    }
+                    // an empty trunk is taken itself, the next one heads the list then
+                    0 => {
+                        self.page_mut(db, 1)?[32..36].copy_from_slice(&next.to_be_bytes());
+                        trunk
+                    }
+                    _ => bail!(
+                        Corrupt,
+                        "freelist trunk page {} claims {} leaves",
+                        trunk,
+                        leaves
+                    ),
+                };
+                let free_count = free_count.saturating_sub(1);
+                self.page_mut(db, 1)?[36..40].copy_from_slice(&free_count.to_be_bytes());
+                self.page_mut(db, page_number)?.fill(0);
+                Ok(page_number)
+            }
+        }
+    }
+
+    /// Puts a page that is no longer used on the freelist
+    pub fn free_page(&mut self, db: &Database, page_number: u32) -> Result<()> {
+        let (trunk, free_count) = {
+            let header = self.page(db, 1)?;
+            (u32_at(&header, 32), u32_at(&header, 36))
+        };
+        let free_count = (free_count + 1).to_be_bytes();
+        // like sqlite the last few slots of a trunk page are left unused, some old
+        // versions of it took them for corruption
+        let max_leaves = db.header.usable_size() as usize / 4 - 8;
+        if trunk != 0 {
+            let page = self.page_mut(db, trunk)?;
+            let leaves = u32_at(page, 4) as usize;
+            if leaves < max_leaves {
+                page[8 + 4 * leaves..12 + 4 * leaves].copy_from_slice(&page_number.to_be_bytes());
+                page[4..8].copy_from_slice(&(leaves as u32 + 1).to_be_bytes());
+                self.page_mut(db, 1)?[36..40].copy_from_slice(&free_count);
+                return Ok(());
+            }
+        }
+        // the page becomes the first trunk, ahead of the full one
+        let page = self.page_mut(db, page_number)?;
+        page.fill(0);
+        page[..4].copy_from_slice(&trunk.to_be_bytes());
+        let header = self.page_mut(db, 1)?;
+        header[32..36].copy_from_slice(&page_number.to_be_bytes());
+        header[36..40].copy_from_slice(&free_count);
+        Ok(())
+    }
+
+    /// Stores the part of a payload that doesn't fit on its b-tree page in a chain of
+    /// overflow pages, returning the first of them
+    pub fn write_overflow(&mut self, db: &Database, payload: &[u8]) -> Result<u32> {
+        // every overflow page starts with the number of the next one, 0 on the last
+        let chunks = payload
+            .chunks(db.header.usable_size() as usize - 4)
+            .collect::<Vec<_>>();
+        let page_numbers = chunks
+            .iter()
+            .map(|_| self.allocate_page(db))
+            .collect::<Result<Vec<_>>>()?;
+        for (i, chunk) in chunks.iter().enumerate() {
+            let next = page_numbers.get(i + 1).copied().unwrap_or(0);
+            let page = self.page_mut(db, page_numbers[i])?;
+            page[..4].copy_from_slice(&next.to_be_bytes());
+            page[4..4 + chunk.len()].copy_from_slice(chunk);
+        }
+        match page_numbers.first() {
+            Some(first) => Ok(*first),
+            None => bail!(Corrupt, "an empty payload has no overflow pages"),
+        }
+    }
+
+    /// Puts the overflow pages holding the given number of payload bytes on the freelist
+    pub fn free_overflow(&mut self, db: &Database, first_page: u32, size: usize) -> Result<()> {
+        let mut next = first_page;
+        for _ in 0..size.div_ceil(db.header.usable_size() as usize - 4) {
+            if next == 0 || next > self.database_size {
+                bail!(Corrupt, "overflow page {} is out of bounds", next);
+            }
+            let page_number = next;
+            next = u32_at(&self.page(db, page_number)?, 0);
+            self.free_page(db, page_number)?;
+        }
+        Ok(())
+    }
+
+    /// Reads the given number of payload bytes from a chain of overflow pages
+    pub fn read_overflow(&self, db: &Database, first_page: u32, size: usize) -> Result<Vec<u8>> {
+        let mut payload = Vec::with_capacity(size);
+        let mut next = first_page;
+        while payload.len() < size {
+            if next == 0 || next > self.database_size {
+                bail!(Corrupt, "overflow page {} is out of bounds", next);
+            }
+            let page = self.page(db, next)?;
+            let chunk = (size - payload.len()).min(db.header.usable_size() as usize - 4);
+            payload.extend_from_slice(&page[4..4 + chunk]);
+            next = u32_at(&page, 0);
+        }
+        Ok(payload)
+    }
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes([
+        data[offset],
+        data[offset + 1],
+        data[offset + 2],
+        data[offset + 3],
+    ])
+}
@@ -0,0 +1,250 @@
+// https://www.sqlite.org/fileformat2.html#the_write_ahead_log
+//
+// A WAL file is a 32-byte header followed by frames, each a 24-byte frame header and a
+// page image. A frame whose header holds a non zero database size commits a transaction
+// along with every frame since the previous commit.
+//
+// WAL header
+// Offset  Size  Description
+// 0       4     Magic number. 0x377f0682 or 0x377f0683
+// 4       4     File format version. Currently 3007000.
+// 8       4     Database page size. Example: 1024
+// 12      4     Checkpoint sequence number
+// 16      4     Salt-1: random integer incremented with each checkpoint
+// 20      4     Salt-2: a different random number for each checkpoint
+// 24      4     Checksum-1: First part of a checksum on the first 24 bytes of header
+// 28      4     Checksum-2: Second part of the checksum on the first 24 bytes of header
+//
+// Frame header
+// Offset  Size  Description
+// 0       4     Page number
+// 4       4     For commit records, the size of the database file in pages after the commit.
+//               For all other records, zero.
+// 8       4     Salt-1 copied from the WAL header
+// 12      4     Salt-2 copied from the WAL header
+// 16      4     Checksum-1: Cumulative checksum up through and including this page
+// 20      4     Checksum-2: Second half of the cumulative checksum.
+
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{self, BufReader, Read},
+};
+
+use super::{
+    error::Result,
+    storage::{FileStorage, Storage},
+};
+
+const WAL_MAGIC_LE: u32 = 0x377f0682;
+const WAL_MAGIC_BE: u32 = 0x377f0683;
+const WAL_VERSION: u32 = 3007000;
+pub const WAL_HEADER_SIZE: u64 = 32;
+pub const FRAME_HEADER_SIZE: u64 = 24;
+
+#[derive(Debug, Clone)]
+pub struct WalHeader {
+    pub magic: u32,
+    pub file_format: u32,
+    pub page_size: u32,
+    pub checkpoint_sequence: u32,
+    pub salt: (u32, u32),
+    pub checksum: (u32, u32),
+}
+
+impl WalHeader {
+    /// Parses the header, returning None when it isn't a valid WAL header in which case
+    /// sqlite treats the log as empty
+    pub fn parse(buffer: &[u8; 32]) -> Option<WalHeader> {
+        let u32_at = |offset: usize| {
+            u32::from_be_bytes([
+                buffer[offset],
+                buffer[offset + 1],
+                buffer[offset + 2],
+                buffer[offset + 3],
+            ])
+        };
+        let header = WalHeader {
+            magic: u32_at(0),
+            file_format: u32_at(4),
+            page_size: u32_at(8),
+            checkpoint_sequence: u32_at(12),
+            salt: (u32_at(16), u32_at(20)),
+            checksum: (u32_at(24), u32_at(28)),
+        };
+
+        if !matches!(header.magic, WAL_MAGIC_LE | WAL_MAGIC_BE)
+            || header.file_format != WAL_VERSION
+            || !(512..=65536).contains(&header.page_size)
+            || !header.page_size.is_power_of_two()
+        {
+            return None;
+        }
+        if checksum(header.big_endian_checksums(), (0, 0), &buffer[..24]) != header.checksum {
+            return None;
+        }
+        Some(header)
+    }
+
+    /// The low bit of the magic number picks the byte order of the checksum words
+    pub fn big_endian_checksums(&self) -> bool {
+        self.magic == WAL_MAGIC_BE
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct FrameHeader {
+    pub page_number: u32,
+    /// The database size in pages after the commit, zero for frames that don't commit
+    pub database_size: u32,
+    pub salt: (u32, u32),
+    pub checksum: (u32, u32),
+}
+
+impl FrameHeader {
+    pub fn parse(buffer: &[u8; 24]) -> FrameHeader {
+        let u32_at = |offset: usize| {
+            u32::from_be_bytes([
+                buffer[offset],
+                buffer[offset + 1],
+                buffer[offset + 2],
+                buffer[offset + 3],
+            ])
+        };
+        FrameHeader {
+            page_number: u32_at(0),
+            database_size: u32_at(4),
+            salt: (u32_at(8), u32_at(12)),
+            checksum: (u32_at(16), u32_at(20)),
+        }
+    }
+
+    pub fn is_commit(&self) -> bool {
+        self.database_size != 0
+    }
+}
+
+// https://www.sqlite.org/fileformat2.html#checksum_algorithm
+//
+// Runs over the data as pairs of 32-bit words, continuing from a previous checksum so
+// that every frame's checksum covers all the frames before it
+pub fn checksum(big_endian: bool, initial: (u32, u32), data: &[u8]) -> (u32, u32) {
+    let (mut s0, mut s1) = initial;
+    for chunk in data.chunks_exact(8) {
+        let word = |b: &[u8]| {
+            let bytes = [b[0], b[1], b[2], b[3]];
+            match big_endian {
+                true => u32::from_be_bytes(bytes),
+                false => u32::from_le_bytes(bytes),
+            }
+        };
+        s0 = s0.wrapping_add(word(&chunk[..4])).wrapping_add(s1);
+        s1 = s1.wrapping_add(word(&chunk[4..])).wrapping_add(s0);
+    }
+    (s0, s1)
+}
+
+pub struct Wal {
+    pub header: WalHeader,
+    file: FileStorage,
+    /// Offset in the WAL file of the page image of the latest committed frame of each page
+    frames: HashMap<u32, u64>,
+    /// Number of valid frames up to and including the last commit
+    pub frame_count: u32,
+    /// The database size in pages after the last commit
+    pub database_size: u32,
+}
+
+impl Wal {
+    /// Opens the WAL file next to a database. Returns None when there is no WAL file or
+    /// it holds no committed transactions, the database file is then up to date.
+    pub fn open(path: &str, page_size: u32) -> Result<Option<Wal>> {
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let mut reader = BufReader::new(&file);
+
+        let mut buffer = [0; WAL_HEADER_SIZE as usize];
+        if !read_fully(&mut reader, &mut buffer)? {
+            return Ok(None);
+        }
+        let Some(header) = WalHeader::parse(&buffer) else {
+            return Ok(None);
+        };
+        if header.page_size != page_size {
+            return Ok(None);
+        }
+
+        let big_endian = header.big_endian_checksums();
+        let mut running_checksum = header.checksum;
+        let mut pending: Vec<(u32, u64)> = Vec::new();
+        let mut frames = HashMap::new();
+        let mut frame_count = 0;
+        let mut database_size = 0;
+        let mut page = vec![0; page_size as usize];
+        let mut offset = WAL_HEADER_SIZE;
+
+        // the log ends at the first frame that is cut short, belongs to an older
+        // checkpoint or fails its checksum
+        loop {
+            let mut buffer = [0; FRAME_HEADER_SIZE as usize];
+            if !read_fully(&mut reader, &mut buffer)? || !read_fully(&mut reader, &mut page)? {
+                break;
+            }
+            let frame = FrameHeader::parse(&buffer);
+            if frame.page_number == 0 || frame.salt != header.salt {
+                break;
+            }
+            let frame_checksum = checksum(
+                big_endian,
+                checksum(big_endian, running_checksum, &buffer[..8]),
+                &page,
+            );
+            if frame_checksum != frame.checksum {
+                break;
+            }
+            running_checksum = frame_checksum;
+
+            pending.push((frame.page_number, offset + FRAME_HEADER_SIZE));
+            offset += FRAME_HEADER_SIZE + page_size as u64;
+
+            // frames only become visible once a commit frame ends their transaction
+            if frame.is_commit() {
+                frame_count += pending.len() as u32;
+                frames.extend(pending.drain(..));
+                database_size = frame.database_size;
+            }
+        }
+
+        if frames.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(Wal {
+            header,
+            file: FileStorage::new(file),
+            frames,
+            frame_count,
+            database_size,
+        }))
+    }
+
+    /// Offset in the WAL file of the latest committed version of a page
+    pub fn frame_offset(&self, page_number: u32) -> Option<u64> {
+        self.frames.get(&page_number).copied()
+    }
+
+    pub fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<()> {
+        self.file.read_at(offset, buf)
+    }
+}
+
+/// Fills the buffer, returning false if the reader ends first
+fn read_fully(reader: &mut impl Read, buf: &mut [u8]) -> Result<bool> {
+    match reader.read_exact(buf) {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}